@@ -1,36 +1,59 @@
-use clap::{App, Arg};
-use easyfs::{BlockDev, EzFileSys};
-use std::fs::{read_dir, File, OpenOptions};
-use std::io::{Read, Seek, SeekFrom, Write};
+use clap::{App, Arg, ArgMatches, SubCommand};
+use easyfs::vfs::{split_path, VirtInode, S_IFDIR};
+use easyfs::layout::{DiskInode, JOURNAL_LOG_BLOCKS};
+use easyfs::{cache_man::set_cache_capacity, fsck::check, BlockDev, EzFileSys, EzFsError, EzResult};
+use std::fs::{self, read_dir, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::mem::size_of;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::process;
 use std::sync::Arc;
 use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+mod mount;
 
 const BLOCK_SZ: usize = 512;
 
 struct BlockFile(Mutex<File>);
 
 impl BlockDev for BlockFile {
-    fn read_block(&self, block_id: usize, buf: &mut [u8]) {
+    fn read_block(&self, block_id: usize, buf: &mut [u8]) -> EzResult<()> {
+        self.read_blocks(block_id, buf)
+    }
+
+    fn write_block(&self, block_id: usize, buf: &[u8]) -> EzResult<()> {
+        self.write_blocks(block_id, buf)
+    }
+
+    fn read_blocks(&self, start_block: usize, buf: &mut [u8]) -> EzResult<()> {
         let mut file = self.0.lock().unwrap();
-        file.seek(SeekFrom::Start((block_id * BLOCK_SZ) as u64))
-            .expect("Error when seeking!");
-        assert_eq!(file.read(buf).unwrap(), BLOCK_SZ, "Not a complete block!");
+        file.seek(SeekFrom::Start((start_block * BLOCK_SZ) as u64))
+            .and_then(|_| file.read_exact(buf))
+            .map_err(|_| EzFsError::Io)
     }
 
-    fn write_block(&self, block_id: usize, buf: &[u8]) {
+    fn write_blocks(&self, start_block: usize, buf: &[u8]) -> EzResult<()> {
         let mut file = self.0.lock().unwrap();
-        file.seek(SeekFrom::Start((block_id * BLOCK_SZ) as u64))
-            .expect("Error when seeking!");
-        assert_eq!(file.write(buf).unwrap(), BLOCK_SZ, "Not a complete block!");
+        file.seek(SeekFrom::Start((start_block * BLOCK_SZ) as u64))
+            .and_then(|_| file.write_all(buf))
+            .map_err(|_| EzFsError::Io)
     }
 }
 
-fn main() {
-    //efs_test();
-    easy_fs_pack().expect("Error when packing easy-fs!");
+fn ez_err(e: EzFsError) -> io::Error {
+    io::Error::from_raw_os_error(e.errno())
+}
+
+fn host_time_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as u64)
 }
 
-fn easy_fs_pack() -> std::io::Result<()> {
+fn main() {
+    //efs_test();
     let matches = App::new("EzFileSys packer")
         .arg(
             Arg::with_name("source")
@@ -46,40 +69,347 @@ fn easy_fs_pack() -> std::io::Result<()> {
                 .takes_value(true)
                 .help("Executable target dir(with backslash)"),
         )
+        .arg(
+            Arg::with_name("data")
+                .short("d")
+                .long("data")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .help("Data file or directory to pack with its full name"),
+        )
+        .arg(
+            Arg::with_name("size")
+                .long("size")
+                .default_value("16M")
+                .help("Image size, with an optional K/M/G suffix"),
+        )
+        .arg(
+            Arg::with_name("inodes")
+                .long("inodes")
+                .default_value("4096")
+                .help("Number of inodes"),
+        )
+        .arg(
+            Arg::with_name("block-size")
+                .long("block-size")
+                .default_value("512")
+                .help("Block size in bytes"),
+        )
+        .subcommand(
+            SubCommand::with_name("check")
+                .about("Check an image for inconsistencies")
+                .arg(Arg::with_name("image").required(true).help("Image file"))
+                .arg(
+                    Arg::with_name("repair")
+                        .short("r")
+                        .long("repair")
+                        .help("Repair what can be repaired"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("mount")
+                .about("Mount an image through FUSE")
+                .arg(Arg::with_name("image").required(true).help("Image file"))
+                .arg(Arg::with_name("mountpoint").required(true).help("Mount point"))
+                .arg(
+                    Arg::with_name("cache")
+                        .long("cache")
+                        .default_value("1024")
+                        .help("Number of cached blocks"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("add")
+                .about("Add or replace files in an existing image")
+                .arg(Arg::with_name("image").required(true).help("Image file"))
+                .arg(
+                    Arg::with_name("files")
+                        .required(true)
+                        .multiple(true)
+                        .help("Host files or directories"),
+                )
+                .arg(
+                    Arg::with_name("dest")
+                        .long("dest")
+                        .default_value("/")
+                        .help("Directory in the image"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("ls")
+                .about("List files in an image")
+                .arg(Arg::with_name("image").required(true).help("Image file"))
+                .arg(Arg::with_name("path").default_value("/").help("Directory or file in the image"))
+                .arg(
+                    Arg::with_name("recursive")
+                        .short("r")
+                        .long("recursive")
+                        .help("List sub-directories too"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("extract")
+                .about("Copy a file or directory tree out of an image")
+                .arg(Arg::with_name("image").required(true).help("Image file"))
+                .arg(Arg::with_name("path").required(true).help("File or directory in the image"))
+                .arg(Arg::with_name("dest").required(true).help("Host destination")),
+        )
         .get_matches();
-    let src_path = matches.value_of("source").unwrap();
+    match matches.subcommand() {
+        ("check", Some(m)) => {
+            let clean = easy_fs_check(m).expect("Error when checking easy-fs!");
+            process::exit(if clean { 0 } else { 1 });
+        }
+        ("mount", Some(m)) => easy_fs_mount(m).expect("Error when mounting easy-fs!"),
+        ("add", Some(m)) => easy_fs_add(m).expect("Error when adding to easy-fs!"),
+        ("ls", Some(m)) => easy_fs_ls(m).expect("Error when listing easy-fs!"),
+        ("extract", Some(m)) => easy_fs_extract(m).expect("Error when extracting easy-fs!"),
+        _ => easy_fs_pack(&matches).expect("Error when packing easy-fs!"),
+    }
+}
+
+fn open_image(path: &str) -> std::io::Result<Arc<BlockFile>> {
+    let f = OpenOptions::new().read(true).write(true).open(path)?;
+    Ok(Arc::new(BlockFile(Mutex::new(f))))
+}
+
+// returns whether the image was found clean
+fn easy_fs_check(matches: &ArgMatches) -> std::io::Result<bool> {
+    let repair = matches.is_present("repair");
+    let efs = EzFileSys::from_device(open_image(matches.value_of("image").unwrap())?).map_err(ez_err)?;
+    let issues = check(&efs, repair).map_err(ez_err)?;
+    for (issue, repaired) in issues.iter() {
+        let note = match (repair, repaired) {
+            (false, _) => "",
+            (true, true) => " (repaired)",
+            (true, false) => " (not repaired)",
+        };
+        println!("{}{}", issue, note);
+    }
+    if issues.is_empty() {
+        println!("clean");
+    }
+    Ok(issues.is_empty())
+}
+
+fn easy_fs_mount(matches: &ArgMatches) -> std::io::Result<()> {
+    let cache = matches.value_of("cache").unwrap();
+    set_cache_capacity(cache.parse().map_err(|_| invalid_input(format!("bad cache size {}", cache)))?).map_err(ez_err)?;
+    let efs = EzFileSys::from_device(open_image(matches.value_of("image").unwrap())?).map_err(ez_err)?;
+    efs.lock().set_clock(host_time_ms);
+    mount::mount(efs, matches.value_of("mountpoint").unwrap())
+}
+
+fn lookup_image_path(root_inode: &Arc<VirtInode>, path: &str) -> std::io::Result<Arc<VirtInode>> {
+    root_inode.lookup_path(path).map_err(|e| match e {
+        EzFsError::NotFound => io::Error::new(io::ErrorKind::NotFound, format!("{} not found in image", path)),
+        e => ez_err(e),
+    })
+}
+
+fn read_all(inode: &VirtInode) -> EzResult<Vec<u8>> {
+    let mut data = vec![0u8; inode.stat()?.size as usize];
+    let len = inode.read_at(0, &mut data)?;
+    data.truncate(len);
+    Ok(data)
+}
+
+// (path, size, is_dir) of everything under `inode`, which is named `path`
+fn list_tree(inode: &VirtInode, path: &str, recursive: bool) -> EzResult<Vec<(String, u64, bool)>> {
+    if !inode.is_dir()? {
+        return Ok(vec![(path.to_string(), inode.stat()?.size, false)]);
+    }
+    let mut v = Vec::new();
+    let mut pos = 0;
+    while let Some((item, next)) = inode.read_dir(pos)? {
+        pos = next;
+        if item.name == "." || item.name == ".." {
+            continue;
+        }
+        let child = inode.find(&item.name)?;
+        let child_path = format!("{}/{}", path.trim_end_matches('/'), item.name);
+        let is_dir = item.mode == S_IFDIR;
+        v.push((child_path.clone(), child.stat()?.size, is_dir));
+        if recursive && is_dir {
+            v.extend(list_tree(&child, &child_path, true)?);
+        }
+    }
+    Ok(v)
+}
+
+fn easy_fs_ls(matches: &ArgMatches) -> std::io::Result<()> {
+    let efs = EzFileSys::from_device(open_image(matches.value_of("image").unwrap())?).map_err(ez_err)?;
+    let root_inode = Arc::new(EzFileSys::root_vinode(&efs));
+    let path = matches.value_of("path").unwrap();
+    let inode = lookup_image_path(&root_inode, path)?;
+    for (name, size, is_dir) in list_tree(&inode, path, matches.is_present("recursive")).map_err(ez_err)? {
+        if is_dir {
+            println!("{:>10}  {}/", "-", name);
+        } else {
+            println!("{:>10}  {}", size, name);
+        }
+    }
+    Ok(())
+}
+
+// copy `inode` to `dest`, recursing into directories
+fn extract_tree(inode: &VirtInode, dest: &Path) -> std::io::Result<()> {
+    if !inode.is_dir().map_err(ez_err)? {
+        return fs::write(dest, read_all(inode).map_err(ez_err)?);
+    }
+    fs::create_dir_all(dest)?;
+    for name in inode.ls().map_err(ez_err)? {
+        if name == "." || name == ".." {
+            continue;
+        }
+        let child = inode.find(&name).map_err(ez_err)?;
+        extract_tree(&child, &dest.join(&name))?;
+    }
+    Ok(())
+}
+
+fn easy_fs_extract(matches: &ArgMatches) -> std::io::Result<()> {
+    let efs = EzFileSys::from_device(open_image(matches.value_of("image").unwrap())?).map_err(ez_err)?;
+    let root_inode = Arc::new(EzFileSys::root_vinode(&efs));
+    let inode = lookup_image_path(&root_inode, matches.value_of("path").unwrap())?;
+    let mut dest = PathBuf::from(matches.value_of("dest").unwrap());
+    // a file extracted into an existing directory keeps its name
+    if !inode.is_dir().map_err(ez_err)? && dest.is_dir() {
+        let (_, name) = split_path(matches.value_of("path").unwrap());
+        dest.push(name);
+    }
+    extract_tree(&inode, &dest)
+}
+
+// "16M", "512K", or a plain byte count
+fn parse_size(size: &str) -> Option<u64> {
+    let (num, unit) = match size.char_indices().last()? {
+        (i, 'K' | 'k') => (&size[..i], 1 << 10),
+        (i, 'M' | 'm') => (&size[..i], 1 << 20),
+        (i, 'G' | 'g') => (&size[..i], 1 << 30),
+        _ => (size, 1),
+    };
+    num.parse::<u64>().ok().map(|n| n * unit)
+}
+
+fn invalid_input(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, msg)
+}
+
+// (total blocks, inode bitmap blocks) for the requested image
+fn image_geometry(matches: &ArgMatches) -> std::io::Result<(u32, u32)> {
+    let block_size = matches.value_of("block-size").unwrap();
+    // the on-disk layout of easyfs is built around its BLOCK_SIZE
+    if block_size.parse::<usize>() != Ok(BLOCK_SZ) {
+        return Err(invalid_input(format!("block size must be {}", BLOCK_SZ)));
+    }
+    let size = matches.value_of("size").unwrap();
+    let total_blocks = parse_size(size)
+        .map(|bytes| bytes / BLOCK_SZ as u64)
+        .filter(|blocks| *blocks <= u32::MAX as u64)
+        .ok_or_else(|| invalid_input(format!("bad image size {}", size)))? as u32;
+    let inodes = matches.value_of("inodes").unwrap();
+    let inodes = inodes.parse::<u32>()
+        .map_err(|_| invalid_input(format!("bad inode count {}", inodes)))?;
+    let inode_bitmap_blocks = ((inodes as usize + BLOCK_SZ * 8 - 1) / (BLOCK_SZ * 8)).max(1) as u32;
+    // superblock, journal, inode area and at least one data bitmap and data block
+    let inode_area_blocks = inode_bitmap_blocks as usize * BLOCK_SZ * 8 * size_of::<DiskInode>() / BLOCK_SZ;
+    let min_blocks = 1 + 1 + JOURNAL_LOG_BLOCKS + inode_bitmap_blocks as usize + inode_area_blocks + 2;
+    if (total_blocks as usize) < min_blocks {
+        return Err(invalid_input(format!(
+            "{} inodes need an image of at least {} bytes", inodes, min_blocks * BLOCK_SZ
+        )));
+    }
+    Ok((total_blocks, inode_bitmap_blocks))
+}
+
+// copy a host file, or a directory tree, into `dir`, replacing files of the same name
+fn put_host_path(dir: &Arc<VirtInode>, host: &Path) -> std::io::Result<()> {
+    let name = host.file_name()
+        .and_then(|name| name.to_str())
+        .ok_or_else(|| invalid_input(format!("bad file name {}", host.display())))?;
+    let cannot_create = |e: EzFsError| invalid_input(format!("cannot create {} in image: {}", name, e));
+    if host.is_dir() {
+        let sub = match dir.find(name) {
+            Ok(sub) if sub.is_dir().map_err(ez_err)? => sub,
+            Ok(_) => return Err(cannot_create(EzFsError::Exists)),
+            Err(EzFsError::NotFound) => dir.mkdir(name).map_err(cannot_create)?,
+            Err(e) => return Err(ez_err(e)),
+        };
+        for entry in read_dir(host)? {
+            put_host_path(&sub, &entry?.path())?;
+        }
+        return Ok(());
+    }
+    let data = fs::read(host)?;
+    let inode = match dir.find(name) {
+        Ok(inode) if !inode.is_dir().map_err(ez_err)? => {
+            inode.clear().map_err(ez_err)?;
+            inode
+        }
+        Ok(_) => return Err(cannot_create(EzFsError::IsDir)),
+        Err(EzFsError::NotFound) => dir.create(name).map_err(cannot_create)?,
+        Err(e) => return Err(ez_err(e)),
+    };
+    if inode.write_at(0, &data).map_err(ez_err)? < data.len() {
+        return Err(ez_err(EzFsError::NoSpace));
+    }
+    inode.set_mode(fs::metadata(host)?.permissions().mode() & 0o777).map_err(ez_err)
+}
+
+fn easy_fs_add(matches: &ArgMatches) -> std::io::Result<()> {
+    let efs = EzFileSys::from_device(open_image(matches.value_of("image").unwrap())?).map_err(ez_err)?;
+    efs.lock().set_clock(host_time_ms);
+    let root_inode = Arc::new(EzFileSys::root_vinode(&efs));
+    let dest = lookup_image_path(&root_inode, matches.value_of("dest").unwrap())?;
+    for path in matches.values_of("files").unwrap() {
+        put_host_path(&dest, Path::new(path))?;
+    }
+    Ok(())
+}
+
+fn easy_fs_pack(matches: &ArgMatches) -> std::io::Result<()> {
     let target_path = matches.value_of("target").unwrap();
-    println!("src_path = {}\ntarget_path = {}", src_path, target_path);
+    let (total_blocks, inode_bitmap_blocks) = image_geometry(matches)?;
+    println!("target_path = {}", target_path);
     let block_file = Arc::new(BlockFile(Mutex::new({
         let f = OpenOptions::new()
             .read(true)
             .write(true)
             .create(true)
+            .truncate(true)
             .open(format!("{}{}", target_path, "fs.img"))?;
-        f.set_len(16 * 2048 * 512).unwrap();
+        f.set_len(total_blocks as u64 * BLOCK_SZ as u64).unwrap();
         f
     })));
-    // 16MiB, at most 4095 files
-    let efs = EzFileSys::new(block_file, 16 * 2048, 1);
+    let efs = EzFileSys::new(block_file, total_blocks, inode_bitmap_blocks).map_err(ez_err)?;
+    efs.lock().set_clock(host_time_ms);
     let root_inode = Arc::new(EzFileSys::root_vinode(&efs));
-    let apps: Vec<_> = read_dir(src_path)
-        .unwrap()
-        .into_iter()
-        .map(|dir_entry| {
-            let mut name_with_ext = dir_entry.unwrap().file_name().into_string().unwrap();
-            name_with_ext.drain(name_with_ext.find('.').unwrap()..name_with_ext.len());
-            name_with_ext
-        })
-        .collect();
-    for app in apps {
-        // load app data from host file system
-        let mut host_file = File::open(format!("{}{}", target_path, app)).unwrap();
-        let mut all_data: Vec<u8> = Vec::new();
-        host_file.read_to_end(&mut all_data).unwrap();
-        // create a file in easy-fs
-        let inode = root_inode.create(app.as_str()).unwrap();
-        // write data to easy-fs
-        inode.write_at(0, all_data.as_slice());
+    if let Some(src_path) = matches.value_of("source") {
+        println!("src_path = {}", src_path);
+        // apps are named after their sources, without the extension
+        let apps: Vec<_> = read_dir(src_path)
+            .unwrap()
+            .map(|dir_entry| {
+                let path = dir_entry.unwrap().path();
+                path.file_stem().unwrap().to_str().unwrap().to_string()
+            })
+            .collect();
+        for app in apps {
+            // load app data from host file system
+            let mut host_file = File::open(format!("{}{}", target_path, app)).unwrap();
+            let mut all_data: Vec<u8> = Vec::new();
+            host_file.read_to_end(&mut all_data).unwrap();
+            // create a file in easy-fs
+            let inode = root_inode.create(app.as_str()).map_err(ez_err)?;
+            // write data to easy-fs
+            inode.write_at(0, all_data.as_slice()).map_err(ez_err)?;
+            inode.set_mode(0o755).map_err(ez_err)?;
+        }
+    }
+    for path in matches.values_of("data").into_iter().flatten() {
+        put_host_path(&root_inode, Path::new(path))?;
     }
     // list apps
     // for app in root_inode.ls() {
@@ -88,48 +418,66 @@ fn easy_fs_pack() -> std::io::Result<()> {
     Ok(())
 }
 
-#[test]
-fn efs_test() -> std::io::Result<()> {
-    let block_file = Arc::new(BlockFile(Mutex::new({
+#[cfg(test)]
+use easyfs::{cache_man::{get_block_cache, BlockCacheMan}, fsck::FsckIssue};
+#[cfg(test)]
+use easyfs::vfs::{PATH_MAX, S_IFLNK, S_IFMT, S_IFREG};
+#[cfg(test)]
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+// tests share one image file and must not overlap
+#[cfg(test)]
+static TEST_IMG_LOCK: Mutex<()> = Mutex::new(());
+
+#[cfg(test)]
+fn test_block_file() -> std::io::Result<Arc<BlockFile>> {
+    Ok(Arc::new(BlockFile(Mutex::new({
         let f = OpenOptions::new()
             .read(true)
             .write(true)
             .create(true)
+            .truncate(false)
             .open("target/fs.img")?;
         f.set_len(8192 * 512).unwrap();
         f
-    })));
-    EzFileSys::new(block_file.clone(), 4096, 1);
-    let efs = EzFileSys::from_device(block_file.clone());
+    }))))
+}
+
+#[test]
+fn efs_test() -> std::io::Result<()> {
+    let _guard = TEST_IMG_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let block_file = test_block_file()?;
+    EzFileSys::new(block_file.clone(), 4096, 1).unwrap();
+    let efs = EzFileSys::from_device(block_file.clone()).unwrap();
     let root_inode = EzFileSys::root_vinode(&efs);
-    root_inode.create("filea");
-    root_inode.create("fileb");
-    for name in root_inode.ls() {
+    root_inode.create("filea").unwrap();
+    root_inode.create("fileb").unwrap();
+    for name in root_inode.ls().unwrap() {
         println!("{}", name);
     }
     let filea = root_inode.find("filea").unwrap();
     let greet_str = "Hello, world!";
-    filea.write_at(0, greet_str.as_bytes());
+    filea.write_at(0, greet_str.as_bytes()).unwrap();
     //let mut buffer = [0u8; 512];
     let mut buffer = [0u8; 233];
-    let len = filea.read_at(0, &mut buffer);
+    let len = filea.read_at(0, &mut buffer).unwrap();
     assert_eq!(greet_str, core::str::from_utf8(&buffer[..len]).unwrap(),);
 
     let mut random_str_test = |len: usize| {
-        filea.clear();
-        assert_eq!(filea.read_at(0, &mut buffer), 0,);
+        filea.clear().unwrap();
+        assert_eq!(filea.read_at(0, &mut buffer), Ok(0),);
         let mut str = String::new();
         use rand;
         // random digit
         for _ in 0..len {
-            str.push(char::from('0' as u8 + rand::random::<u8>() % 10));
+            str.push(char::from(b'0' + rand::random::<u8>() % 10));
         }
-        filea.write_at(0, str.as_bytes());
+        filea.write_at(0, str.as_bytes()).unwrap();
         let mut read_buffer = [0u8; 127];
         let mut offset = 0usize;
         let mut read_str = String::new();
         loop {
-            let len = filea.read_at(offset, &mut read_buffer);
+            let len = filea.read_at(offset, &mut read_buffer).unwrap();
             if len == 0 {
                 break;
             }
@@ -150,3 +498,1115 @@ fn efs_test() -> std::io::Result<()> {
 
     Ok(())
 }
+
+#[test]
+fn efs_dir_test() -> std::io::Result<()> {
+    let _guard = TEST_IMG_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let block_file = test_block_file()?;
+    EzFileSys::new(block_file.clone(), 4096, 1).unwrap();
+    let efs = EzFileSys::from_device(block_file.clone()).unwrap();
+    let root_inode = Arc::new(EzFileSys::root_vinode(&efs));
+    assert_eq!(root_inode.ls().unwrap(), [".", ".."]);
+
+    let usr = root_inode.mkdir("usr").unwrap();
+    let bin = usr.mkdir("bin").unwrap();
+    assert_eq!(usr.mkdir("bin").err(), Some(EzFsError::Exists));
+    bin.create("hello").unwrap().write_at(0, b"hi").unwrap();
+    assert_eq!(bin.mkdir("hello").err(), Some(EzFsError::Exists));
+
+    let hello = root_inode.lookup_path("/usr/bin/hello").unwrap();
+    let mut buffer = [0u8; 8];
+    let len = hello.read_at(0, &mut buffer).unwrap();
+    assert_eq!(&buffer[..len], b"hi");
+    let same = root_inode.lookup_path("usr/./bin/../bin//hello").unwrap();
+    assert_eq!(same.inode_id(), hello.inode_id());
+    assert_eq!(root_inode.lookup_path("/usr/..").unwrap().inode_id(), 0);
+    assert_eq!(root_inode.lookup_path("/..").unwrap().inode_id(), 0);
+    assert_eq!(root_inode.lookup_path("/usr/bin/hello/x").err(), Some(EzFsError::NotDir));
+    assert_eq!(hello.create("x").err(), Some(EzFsError::NotDir));
+
+    assert_eq!(usr.rmdir("bin"), Err(EzFsError::NotEmpty));
+    assert!(usr.rmdir("..").is_err());
+    usr.mkdir("tmp").unwrap();
+    assert_eq!(usr.rmdir("tmp"), Ok(()));
+    assert_eq!(root_inode.lookup_path("/usr/tmp").err(), Some(EzFsError::NotFound));
+    assert_eq!(usr.ls().unwrap(), [".", "..", "bin"]);
+    let lib = usr.mkdir("lib").unwrap();
+    assert_eq!(usr.ls().unwrap(), [".", "..", "bin", "lib"]);
+    assert_eq!(lib.lookup_path("..").unwrap().inode_id(), usr.inode_id());
+
+    // positions are byte offsets, "." and ".." come first
+    let (_, next) = usr.read_dir(0).unwrap().unwrap();
+    let (_, next) = usr.read_dir(next).unwrap().unwrap();
+    let (item, next) = usr.read_dir(next).unwrap().unwrap();
+    assert_eq!((item.name.as_str(), item.mode, item.inode_id), ("bin", S_IFDIR, bin.inode_id()));
+    let (item, next) = usr.read_dir(next).unwrap().unwrap();
+    assert_eq!(item.name, "lib");
+    assert!(usr.read_dir(next).unwrap().is_none());
+    let (_, next) = bin.read_dir(0).unwrap().unwrap();
+    let (_, next) = bin.read_dir(next).unwrap().unwrap();
+    let (item, _) = bin.read_dir(next).unwrap().unwrap();
+    assert_eq!((item.name.as_str(), item.mode), ("hello", S_IFREG));
+    assert!(hello.read_dir(0).is_err());
+    Ok(())
+}
+
+#[test]
+fn efs_long_name_test() -> std::io::Result<()> {
+    let _guard = TEST_IMG_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let block_file = test_block_file()?;
+    EzFileSys::new(block_file.clone(), 8192, 1).unwrap();
+    let efs = EzFileSys::from_device(block_file.clone()).unwrap();
+    let root_inode = Arc::new(EzFileSys::root_vinode(&efs));
+
+    let longest = "n".repeat(255);
+    let file = root_inode.create(&longest).unwrap();
+    assert_eq!(root_inode.find(&longest).unwrap().inode_id(), file.inode_id());
+    assert_eq!(root_inode.create(&"n".repeat(256)).err(), Some(EzFsError::NameTooLong));
+    assert_eq!(root_inode.create("a/b").err(), Some(EzFsError::InvalidName));
+    assert_eq!(root_inode.create("a\0b").err(), Some(EzFsError::InvalidName));
+    assert_eq!(root_inode.ls().unwrap(), [".", "..", longest.as_str()]);
+
+    // enough entries for the index to grow a second level
+    let dir = root_inode.mkdir("generated").unwrap();
+    let name = |i: usize| format!("generated-test-file-{:05}-with-a-rather-long-descriptive-name.txt", i);
+    let mut ids = Vec::new();
+    for i in 0..3000 {
+        ids.push(dir.create(&name(i)).unwrap().inode_id());
+    }
+    for i in 0..8 {
+        dir.create(&format!("{}{}", i, "x".repeat(254))).unwrap();
+    }
+    assert_eq!(dir.ls().unwrap().len(), 3000 + 8 + 2);
+    // more leaves than the 60 the index root holds
+    assert!(dir.stat().unwrap().size as usize > 61 * BLOCK_SZ);
+    for (i, id) in ids.iter().enumerate() {
+        assert_eq!(dir.find(&name(i)).unwrap().inode_id(), *id);
+    }
+    assert_eq!(dir.create(&name(7)).err(), Some(EzFsError::Exists));
+    assert!(check(&efs, false).unwrap().is_empty());
+
+    // a lookup reads the inode, the index root, an index block and a leaf
+    let count_file = Arc::new(CountFile {
+        file: block_file.clone(),
+        reads: AtomicU64::new(0),
+        writes: AtomicU64::new(0)
+    });
+    let count_efs = EzFileSys::from_device(count_file.clone()).unwrap();
+    let count_dir = EzFileSys::root_vinode(&count_efs).find("generated").unwrap();
+    let reads = count_file.reads.load(Ordering::SeqCst);
+    assert_eq!(count_dir.find(&name(2024)).unwrap().inode_id(), ids[2024]);
+    assert!(count_file.reads.load(Ordering::SeqCst) - reads <= 4);
+
+    for i in (0..3000).step_by(2) {
+        dir.unlink(&name(i)).unwrap();
+    }
+    assert_eq!(dir.find(&name(10)).err(), Some(EzFsError::NotFound));
+    assert_eq!(dir.find(&name(11)).unwrap().inode_id(), ids[11]);
+    assert_eq!(dir.ls().unwrap().len(), 1500 + 8 + 2);
+    dir.create(&name(10)).unwrap();
+    assert!(check(&efs, false).unwrap().is_empty());
+    Ok(())
+}
+
+#[test]
+fn dir_records_test() {
+    use easyfs::dir::Records;
+    // a record that ends 4 bytes short of the block leaves no room for a head
+    let mut blk = [0u8; BLOCK_SZ];
+    blk[0..4].copy_from_slice(&7u32.to_le_bytes());
+    blk[4..6].copy_from_slice(&((BLOCK_SZ - 4) as u16).to_le_bytes());
+    blk[6] = 1;
+    blk[8] = b'a';
+    let mut records = Records::new(&blk);
+    assert_eq!(records.next().unwrap().unwrap().name, b"a");
+    assert_eq!(records.next().unwrap().err(), Some(EzFsError::Corrupted));
+    assert!(records.next().is_none());
+}
+
+#[test]
+fn efs_link_test() -> std::io::Result<()> {
+    let _guard = TEST_IMG_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let block_file = test_block_file()?;
+    EzFileSys::new(block_file.clone(), 4096, 1).unwrap();
+    let efs = EzFileSys::from_device(block_file.clone()).unwrap();
+    let root_inode = Arc::new(EzFileSys::root_vinode(&efs));
+    let dir = root_inode.mkdir("dir").unwrap();
+
+    let filea = root_inode.create("filea").unwrap();
+    filea.write_at(0, b"linked").unwrap();
+    assert_eq!(dir.link("fileb", &filea), Ok(()));
+    let stat = filea.stat().unwrap();
+    assert_eq!((stat.ino, stat.nlink, stat.size), (filea.inode_id() as u64, 2, 6));
+    assert_eq!(stat.mode & S_IFMT, S_IFREG);
+    assert_eq!(dir.stat().unwrap().mode & S_IFMT, S_IFDIR);
+    assert_eq!(root_inode.stat().unwrap().nlink, 3);
+    assert_eq!(root_inode.link("filea", &filea), Err(EzFsError::Exists));
+    assert_eq!(root_inode.link("dir2", &dir), Err(EzFsError::IsDir));
+    assert_eq!(root_inode.unlink("dir"), Err(EzFsError::IsDir));
+    assert_eq!(root_inode.unlink("filea"), Ok(()));
+    assert_eq!(root_inode.find("filea").err(), Some(EzFsError::NotFound));
+    let fileb = root_inode.lookup_path("dir/fileb").unwrap();
+    assert_eq!(fileb.inode_id(), filea.inode_id());
+    let mut buffer = [0u8; 16];
+    let len = fileb.read_at(0, &mut buffer).unwrap();
+    assert_eq!(&buffer[..len], b"linked");
+    assert_eq!(dir.unlink("fileb"), Ok(()));
+    assert_eq!(dir.unlink("fileb"), Err(EzFsError::NotFound));
+    assert_eq!(root_inode.rmdir("dir"), Ok(()));
+
+    // blocks of unlinked files are reusable
+    let data = vec![0x5au8; 3000 * BLOCK_SZ];
+    for _ in 0..3 {
+        let file = root_inode.create("big").unwrap();
+        assert_eq!(file.write_at(0, &data), Ok(data.len()));
+        assert_eq!(root_inode.unlink("big"), Ok(()));
+    }
+    assert_eq!(root_inode.ls().unwrap(), [".", ".."]);
+    assert!(check(&efs, false).unwrap().is_empty());
+    Ok(())
+}
+
+#[test]
+fn efs_open_unlinked_test() -> std::io::Result<()> {
+    let _guard = TEST_IMG_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let block_file = test_block_file()?;
+    EzFileSys::new(block_file.clone(), 4096, 1).unwrap();
+    let efs = EzFileSys::from_device(block_file.clone()).unwrap();
+    let root_inode = Arc::new(EzFileSys::root_vinode(&efs));
+    let free_inodes = efs.lock().statfs().free_inodes;
+
+    // an open file outlives its last link
+    let old = root_inode.create("old").unwrap();
+    old.write_at(0, &[1u8; 4 * BLOCK_SZ]).unwrap();
+    assert_eq!(root_inode.unlink("old"), Ok(()));
+    let new = root_inode.create("new").unwrap();
+    assert_ne!(new.inode_id(), old.inode_id());
+    new.write_at(0, &[2u8; 4 * BLOCK_SZ]).unwrap();
+    assert_eq!(old.write_at(4 * BLOCK_SZ, b"tail"), Ok(4));
+    let mut buffer = vec![0u8; 4 * BLOCK_SZ + 4];
+    assert_eq!(old.read_at(0, &mut buffer), Ok(buffer.len()));
+    assert!(buffer[..4 * BLOCK_SZ].iter().all(|b| *b == 1));
+    assert!(check(&efs, false).unwrap().is_empty());
+    drop(old);
+    assert_eq!(efs.lock().statfs().free_inodes, free_inodes - 1);
+    assert!(check(&efs, false).unwrap().is_empty());
+
+    // a removed directory takes no new entries
+    let dir = root_inode.mkdir("dir").unwrap();
+    assert_eq!(root_inode.rmdir("dir"), Ok(()));
+    assert_eq!(dir.create("file").err(), Some(EzFsError::NotFound));
+    assert_eq!(root_inode.rename("new", &dir, "new", false), Err(EzFsError::NotFound));
+    drop(dir);
+
+    // one left open across a crash is freed at the next mount
+    let lost = root_inode.create("lost").unwrap();
+    lost.write_at(0, b"lost").unwrap();
+    assert_eq!(root_inode.unlink("lost"), Ok(()));
+    std::mem::forget(lost);
+    let efs = EzFileSys::from_device(block_file).unwrap();
+    assert_eq!(efs.lock().statfs().free_inodes, free_inodes - 1);
+    assert!(check(&efs, false).unwrap().is_empty());
+    Ok(())
+}
+
+#[cfg(test)]
+static TEST_CLOCK: AtomicU64 = AtomicU64::new(0);
+
+#[cfg(test)]
+fn test_clock() -> u64 {
+    TEST_CLOCK.fetch_add(1, Ordering::SeqCst) + 1
+}
+
+#[test]
+fn efs_rename_test() -> std::io::Result<()> {
+    let _guard = TEST_IMG_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let block_file = test_block_file()?;
+    EzFileSys::new(block_file.clone(), 4096, 1).unwrap();
+    let efs = EzFileSys::from_device(block_file.clone()).unwrap();
+    let root_inode = Arc::new(EzFileSys::root_vinode(&efs));
+
+    // write a temporary file, then move it over the real one
+    let config = root_inode.create("config").unwrap();
+    config.write_at(0, &[1u8; 4 * BLOCK_SZ]).unwrap();
+    let tmp = root_inode.create("config.tmp").unwrap();
+    tmp.write_at(0, b"new").unwrap();
+    assert_eq!(root_inode.rename("config.tmp", &root_inode, "config", false), Err(EzFsError::Exists));
+    assert_eq!(root_inode.rename("config.tmp", &root_inode, "config", true), Ok(()));
+    assert_eq!(root_inode.find("config").unwrap().inode_id(), tmp.inode_id());
+    assert_eq!(root_inode.find("config.tmp").err(), Some(EzFsError::NotFound));
+    assert_eq!(root_inode.ls().unwrap(), [".", "..", "config"]);
+    // the replaced file went with its last link
+    assert!(check(&efs, false).unwrap().is_empty());
+    assert_eq!(root_inode.rename("missing", &root_inode, "x", true), Err(EzFsError::NotFound));
+    assert_eq!(root_inode.rename(".", &root_inode, "x", true), Err(EzFsError::InvalidName));
+    assert_eq!(root_inode.rename("config", &root_inode, "a/b", true), Err(EzFsError::InvalidName));
+
+    // moving a directory takes its ".." and a link of the parent along
+    let a = root_inode.mkdir("a").unwrap();
+    let b = root_inode.mkdir("b").unwrap();
+    let sub = a.mkdir("sub").unwrap();
+    sub.create("file").unwrap();
+    assert_eq!(a.rename("sub", &b, "moved", true), Ok(()));
+    assert_eq!(root_inode.lookup_path("/b/moved/..").unwrap().inode_id(), b.inode_id());
+    assert_eq!(root_inode.lookup_path("/b/moved/file").unwrap().inode_id(), sub.find("file").unwrap().inode_id());
+    assert_eq!((a.stat().unwrap().nlink, b.stat().unwrap().nlink), (2, 3));
+    // not into itself or below
+    assert_eq!(root_inode.rename("b", &sub, "loop", true), Err(EzFsError::InvalidName));
+    assert_eq!(b.rename("moved", &sub, "loop", true), Err(EzFsError::InvalidName));
+
+    // replacing checks the kinds and that a directory is empty
+    root_inode.create("plain").unwrap();
+    assert_eq!(root_inode.rename("plain", &root_inode, "a", true), Err(EzFsError::IsDir));
+    assert_eq!(root_inode.rename("a", &root_inode, "plain", true), Err(EzFsError::NotDir));
+    assert_eq!(root_inode.rename("a", &root_inode, "b", true), Err(EzFsError::NotEmpty));
+    assert_eq!(root_inode.rename("b", &root_inode, "a", true), Ok(()));
+    assert_eq!(root_inode.find("a").unwrap().inode_id(), b.inode_id());
+    assert_eq!(root_inode.stat().unwrap().nlink, 3);
+
+    // two links of one file stay as they are
+    root_inode.link("again", &tmp).unwrap();
+    assert_eq!(root_inode.rename("again", &root_inode, "config", true), Ok(()));
+    assert_eq!(root_inode.ls().unwrap(), [".", "..", "config", "a", "plain", "again"]);
+    assert!(check(&efs, false).unwrap().is_empty());
+    Ok(())
+}
+
+#[test]
+fn efs_symlink_test() -> std::io::Result<()> {
+    let _guard = TEST_IMG_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let block_file = test_block_file()?;
+    EzFileSys::new(block_file.clone(), 4096, 1).unwrap();
+    let efs = EzFileSys::from_device(block_file.clone()).unwrap();
+    let root_inode = Arc::new(EzFileSys::root_vinode(&efs));
+
+    let bin = root_inode.mkdir("bin").unwrap();
+    let v1 = bin.create("app-1.0").unwrap();
+    bin.create("app-1.1").unwrap();
+    let app = root_inode.symlink("app", "bin/app-1.0").unwrap();
+    assert_eq!(app.readlink().unwrap(), "bin/app-1.0");
+    assert_eq!(app.is_symlink(), Ok(true));
+    let stat = app.stat().unwrap();
+    assert_eq!((stat.mode, stat.size, stat.nlink), (S_IFLNK | 0o777, 11, 1));
+    let mut pos = 0;
+    let item = loop {
+        let (item, next) = root_inode.read_dir(pos).unwrap().unwrap();
+        if item.name == "app" {
+            break item;
+        }
+        pos = next;
+    };
+    assert_eq!((item.mode, item.inode_id), (S_IFLNK, app.inode_id()));
+    // the link is not followed here, and may dangle
+    assert_eq!(root_inode.lookup_path("/app").unwrap().inode_id(), app.inode_id());
+    assert_ne!(app.inode_id(), v1.inode_id());
+    root_inode.symlink("dangling", "/nowhere").unwrap();
+    assert_eq!(v1.readlink(), Err(EzFsError::InvalidName));
+    assert_eq!(root_inode.symlink("app", "x").err(), Some(EzFsError::Exists));
+    assert_eq!(root_inode.symlink("empty", "").err(), Some(EzFsError::InvalidName));
+
+    // point the stable name at a new version in one step
+    root_inode.symlink("app.new", "bin/app-1.1").unwrap();
+    assert_eq!(root_inode.rename("app.new", &root_inode, "app", true), Ok(()));
+    assert_eq!(root_inode.find("app").unwrap().readlink().unwrap(), "bin/app-1.1");
+
+    // a long target takes several blocks, given back with the link
+    let target = "d/".repeat(PATH_MAX / 2 - 1) + "x";
+    assert_eq!(root_inode.symlink("long", &(target.clone() + "y")).err(), Some(EzFsError::NameTooLong));
+    let long = root_inode.symlink("long", &target).unwrap();
+    assert_eq!(long.readlink().unwrap(), target);
+    assert_eq!(long.stat().unwrap().blocks, 8);
+    assert!(check(&efs, false).unwrap().is_empty());
+    root_inode.unlink("long").unwrap();
+    root_inode.unlink("app").unwrap();
+    assert_eq!(bin.ls().unwrap(), [".", "..", "app-1.0", "app-1.1"]);
+    assert!(check(&efs, false).unwrap().is_empty());
+    Ok(())
+}
+
+#[test]
+fn efs_attr_test() -> std::io::Result<()> {
+    let _guard = TEST_IMG_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    assert_eq!(std::mem::size_of::<easyfs::layout::DiskInode>(), 128);
+    let block_file = test_block_file()?;
+    EzFileSys::new(block_file.clone(), 4096, 1).unwrap();
+    let efs = EzFileSys::from_device(block_file.clone()).unwrap();
+    efs.lock().set_clock(test_clock);
+    let root_inode = Arc::new(EzFileSys::root_vinode(&efs));
+
+    let file = root_inode.create("file").unwrap();
+    let created = file.stat().unwrap();
+    assert_eq!(created.mode, S_IFREG | 0o644);
+    assert!(created.atime > 0 && created.atime == created.mtime && created.mtime == created.ctime);
+    assert!(root_inode.stat().unwrap().mtime >= created.mtime);
+    assert_eq!(root_inode.stat().unwrap().mode, S_IFDIR | 0o755);
+
+    file.write_at(0, b"data").unwrap();
+    let written = file.stat().unwrap();
+    assert!(written.mtime > created.mtime && written.ctime == written.mtime);
+    assert_eq!(written.atime, created.atime);
+
+    file.read_at(0, &mut [0u8; 4]).unwrap();
+    let read = file.stat().unwrap();
+    assert!(read.atime > written.mtime);
+    assert_eq!(read.mtime, written.mtime);
+
+    file.set_mode(0o100600).unwrap();
+    let chmod = file.stat().unwrap();
+    assert_eq!(chmod.mode, S_IFREG | 0o600);
+    assert!(chmod.ctime > chmod.mtime);
+
+    let dir_mtime = root_inode.stat().unwrap().mtime;
+    assert_eq!(root_inode.unlink("file"), Ok(()));
+    assert!(root_inode.stat().unwrap().mtime > dir_mtime);
+
+    // timestamps survive remounting
+    let dir = root_inode.mkdir("dir").unwrap().stat().unwrap();
+    let efs = EzFileSys::from_device(block_file).unwrap();
+    let root_inode = Arc::new(EzFileSys::root_vinode(&efs));
+    let remounted = root_inode.find("dir").unwrap().stat().unwrap();
+    assert_eq!((remounted.mtime, remounted.mode), (dir.mtime, dir.mode));
+    Ok(())
+}
+
+// loses every write from the journal header of the first commit after arming
+#[cfg(test)]
+struct CrashFile {
+    file: Arc<BlockFile>,
+    keep_header: bool,
+    armed: AtomicBool,
+    crashed: AtomicBool
+}
+
+#[cfg(test)]
+impl BlockDev for CrashFile {
+    fn read_block(&self, block_id: usize, buf: &mut [u8]) -> EzResult<()> {
+        self.file.read_block(block_id, buf)
+    }
+
+    fn write_block(&self, block_id: usize, buf: &[u8]) -> EzResult<()> {
+        // block 1 is the journal header
+        if self.armed.load(Ordering::SeqCst) && block_id == 1 && !self.crashed.swap(true, Ordering::SeqCst) {
+            if self.keep_header {
+                self.file.write_block(block_id, buf)?;
+            }
+            return Ok(());
+        }
+        if !self.crashed.load(Ordering::SeqCst) {
+            self.file.write_block(block_id, buf)?;
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn efs_journal_test() -> std::io::Result<()> {
+    let _guard = TEST_IMG_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    for keep_header in [false, true] {
+        EzFileSys::new(test_block_file()?, 4096, 1).unwrap();
+        let crash_file = Arc::new(CrashFile {
+            file: test_block_file()?,
+            keep_header,
+            armed: AtomicBool::new(false),
+            crashed: AtomicBool::new(false)
+        });
+        let efs = EzFileSys::from_device(crash_file.clone()).unwrap();
+        let root_inode = Arc::new(EzFileSys::root_vinode(&efs));
+        root_inode.create("old").unwrap().write_at(0, b"kept").unwrap();
+        crash_file.armed.store(true, Ordering::SeqCst);
+        root_inode.mkdir("dir").unwrap().create("file").unwrap();
+        assert!(crash_file.crashed.load(Ordering::SeqCst));
+
+        // reboot on a fresh device, which shares no cached blocks
+        let efs = EzFileSys::from_device(test_block_file()?).unwrap();
+        let root_inode = Arc::new(EzFileSys::root_vinode(&efs));
+        let mut buffer = [0u8; 8];
+        let len = root_inode.find("old").unwrap().read_at(0, &mut buffer).unwrap();
+        assert_eq!(&buffer[..len], b"kept");
+        // the mkdir is replayed from the journal only once its header made it
+        let dir = root_inode.find("dir");
+        assert_eq!(dir.is_ok(), keep_header);
+        assert_eq!(root_inode.stat().unwrap().nlink, if keep_header { 3 } else { 2 });
+        if let Ok(dir) = dir {
+            assert_eq!(dir.ls().unwrap(), [".", ".."]);
+        }
+        // the bitmaps agree with the directory tree
+        let after = root_inode.create("after").unwrap();
+        assert_eq!(after.inode_id(), if keep_header { 3 } else { 2 });
+        assert_eq!(after.write_at(0, &[1u8; 4096]), Ok(4096));
+    }
+    Ok(())
+}
+
+#[test]
+fn efs_journal_overflow_test() -> std::io::Result<()> {
+    let _guard = TEST_IMG_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    EzFileSys::new(test_block_file()?, 4096, 1).unwrap();
+    let efs = EzFileSys::from_device(test_block_file()?).unwrap();
+    let root_inode = Arc::new(EzFileSys::root_vinode(&efs));
+
+    // a write several times the journal goes through in journal-sized pieces
+    let blocks = 4 * JOURNAL_LOG_BLOCKS + 3;
+    let data: Vec<u8> = (0..blocks * BLOCK_SZ).map(|i| (i / BLOCK_SZ) as u8).collect();
+    let file = root_inode.create("file").unwrap();
+    assert_eq!(file.write_at(100, &data), Ok(data.len()));
+    let efs = EzFileSys::from_device(test_block_file()?).unwrap();
+    let root_inode = Arc::new(EzFileSys::root_vinode(&efs));
+    let mut buffer = vec![0u8; data.len()];
+    assert_eq!(root_inode.find("file").unwrap().read_at(100, &mut buffer), Ok(data.len()));
+    assert!(buffer == data);
+    assert!(check(&efs, false).unwrap().is_empty());
+
+    // a single transaction the journal can't hold is refused and dropped
+    let dev: Arc<dyn BlockDev> = test_block_file()?;
+    let efs = EzFileSys::from_device(dev.clone()).unwrap();
+    let start = efs.lock().data_block_pos(3000) as usize;
+    let journal = efs.lock().journal();
+    let res = journal.lock().transaction(&efs, |_| {
+        for block_id in start..start + JOURNAL_LOG_BLOCKS + 1 {
+            get_block_cache(block_id, dev.clone())?
+                .lock()
+                .modify(0, |block: &mut [u8; BLOCK_SZ]| block.fill(0xff));
+        }
+        Ok(())
+    });
+    assert_eq!(res, Err(EzFsError::TxTooLarge));
+    let mut block = [0u8; BLOCK_SZ];
+    get_block_cache(start, dev.clone()).unwrap()
+        .lock()
+        .read(0, |cached: &[u8; BLOCK_SZ]| block.copy_from_slice(cached));
+    assert_eq!(block, [0u8; BLOCK_SZ]);
+    test_block_file()?.read_block(start, &mut block).unwrap();
+    assert_eq!(block, [0u8; BLOCK_SZ]);
+    Ok(())
+}
+
+#[cfg(test)]
+struct FailFile {
+    file: Arc<BlockFile>,
+    armed: AtomicBool
+}
+
+#[cfg(test)]
+impl BlockDev for FailFile {
+    fn read_block(&self, block_id: usize, buf: &mut [u8]) -> EzResult<()> {
+        self.file.read_block(block_id, buf)
+    }
+
+    fn write_block(&self, block_id: usize, buf: &[u8]) -> EzResult<()> {
+        if self.armed.load(Ordering::SeqCst) {
+            return Err(EzFsError::Io);
+        }
+        self.file.write_block(block_id, buf)
+    }
+}
+
+#[test]
+fn efs_error_test() -> std::io::Result<()> {
+    let _guard = TEST_IMG_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    EzFileSys::new(test_block_file()?, 4096, 1).unwrap();
+    let fail_file = Arc::new(FailFile {
+        file: test_block_file()?,
+        armed: AtomicBool::new(false)
+    });
+    let efs = EzFileSys::from_device(fail_file.clone()).unwrap();
+    let root_inode = Arc::new(EzFileSys::root_vinode(&efs));
+    root_inode.create("old").unwrap();
+
+    // a failed commit leaves nothing behind
+    fail_file.armed.store(true, Ordering::SeqCst);
+    assert_eq!(root_inode.mkdir("dir").err(), Some(EzFsError::Io));
+    fail_file.armed.store(false, Ordering::SeqCst);
+    assert_eq!(root_inode.find("dir").err(), Some(EzFsError::NotFound));
+    assert_eq!(root_inode.ls().unwrap(), [".", "..", "old"]);
+    assert!(check(&efs, false).unwrap().is_empty());
+    assert_eq!(root_inode.mkdir("dir").unwrap().inode_id(), 2);
+
+    let file = root_inode.find("old").unwrap();
+    assert_eq!(file.write_at(u32::MAX as usize, b"x"), Err(EzFsError::TooLarge));
+    assert_eq!(root_inode.create("").err(), Some(EzFsError::InvalidName));
+    assert_eq!(root_inode.create("a/b").err(), Some(EzFsError::InvalidName));
+
+    // writes stop short once the disk fills up
+    let data = vec![7u8; 8192 * BLOCK_SZ];
+    let written = file.write_at(0, &data).unwrap();
+    assert!(written > 0 && written < data.len());
+    assert_eq!(file.write_at(written, &data), Err(EzFsError::NoSpace));
+    assert_eq!(file.stat().unwrap().size as usize, written);
+    assert!(check(&efs, false).unwrap().is_empty());
+    Ok(())
+}
+
+#[test]
+fn efs_fsck_test() -> std::io::Result<()> {
+    let _guard = TEST_IMG_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let block_file = test_block_file()?;
+    EzFileSys::new(block_file.clone(), 4096, 1).unwrap();
+    let efs = EzFileSys::from_device(block_file.clone()).unwrap();
+    let root_inode = Arc::new(EzFileSys::root_vinode(&efs));
+    let dir = root_inode.mkdir("dir").unwrap();
+    let file = dir.create("file").unwrap();
+    file.write_at(0, &vec![1u8; 40 * BLOCK_SZ]).unwrap();
+    root_inode.create("gone").unwrap().write_at(0, b"gone").unwrap();
+    assert!(check(&efs, false).unwrap().is_empty());
+
+    // free the inode behind "gone", its data block leaks
+    let gone = root_inode.find("gone").unwrap().inode_id();
+    efs.lock().dealloc_inode(gone).unwrap();
+    // a wrong link count
+    let (block_id, block_offset) = efs.lock().inode_pos(file.inode_id());
+    get_block_cache(block_id as usize, block_file.clone())
+        .unwrap()
+        .lock()
+        .modify(block_offset, |inode: &mut DiskInode| inode.nlink = 5);
+    // a block in use but free in the bitmap, and one allocated for nothing
+    let leaked = efs.lock().alloc_data().unwrap();
+    let used = efs.lock().data_block_pos(0);
+    efs.lock().data_bitmap.dealloc(&(block_file.clone() as Arc<dyn BlockDev>), 0).unwrap();
+    efs.lock().commit().unwrap();
+
+    let issues: Vec<FsckIssue> = check(&efs, false).unwrap().into_iter().map(|(issue, _)| issue).collect();
+    assert!(issues.contains(&FsckIssue::DanglingEntry { dir: 0, name: "gone".into(), inode_id: gone }));
+    assert!(issues.contains(&FsckIssue::LinkCount { inode_id: file.inode_id(), nlink: 5, found: 1 }));
+    assert!(issues.contains(&FsckIssue::UnmarkedBlock { block_id: used }));
+    assert!(issues.contains(&FsckIssue::LeakedBlock { block_id: leaked }));
+    assert!(issues.len() >= 5);
+
+    let repaired = check(&efs, true).unwrap();
+    assert!(repaired.iter().all(|(_, repaired)| *repaired));
+    assert!(check(&efs, false).unwrap().is_empty());
+    assert_eq!(root_inode.find("gone").err(), Some(EzFsError::NotFound));
+    assert_eq!(file.stat().unwrap().nlink, 1);
+    assert_eq!(file.read_at(0, &mut [0u8; BLOCK_SZ]), Ok(BLOCK_SZ));
+    // the free counts are right again after a repair
+    let counted = efs.lock().statfs();
+    efs.lock().recount().unwrap();
+    assert_eq!(efs.lock().statfs(), counted);
+    Ok(())
+}
+
+#[test]
+fn efs_mount_test() -> std::io::Result<()> {
+    let _guard = TEST_IMG_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let block_file = test_block_file()?;
+    EzFileSys::new(block_file.clone(), 4096, 1).unwrap();
+    let efs = EzFileSys::from_device(block_file).unwrap();
+    let root_inode = Arc::new(EzFileSys::root_vinode(&efs));
+    root_inode.mkdir("dir").unwrap().create("file").unwrap().write_at(0, b"fuse").unwrap();
+    let fuse = mount::EzFuse::new(efs);
+
+    // fuse inode 1 is the root
+    let dir = fuse.lookup_attr(1, "dir").unwrap();
+    assert_eq!((dir.kind, dir.perm, dir.nlink), (fuser::FileType::Directory, 0o755, 2));
+    let file = fuse.lookup_attr(dir.ino, "file").unwrap();
+    assert_eq!((file.kind, file.size), (fuser::FileType::RegularFile, 4));
+    assert_eq!(fuse.lookup_attr(dir.ino, "none"), Err(libc::ENOENT));
+    assert_eq!(fuse.lookup_attr(file.ino, "x"), Err(libc::ENOTDIR));
+
+    let names: Vec<_> = fuse.dir_entries(dir.ino, 0).unwrap()
+        .into_iter()
+        .map(|(ino, _, _, name)| (ino, name))
+        .collect();
+    assert_eq!(names, [(dir.ino, ".".to_string()), (1, "..".to_string()), (file.ino, "file".to_string())]);
+    // resume after the first entry
+    let next = fuse.dir_entries(dir.ino, 0).unwrap()[0].1;
+    assert_eq!(fuse.dir_entries(dir.ino, next).unwrap().len(), 2);
+    Ok(())
+}
+
+#[test]
+fn efs_extract_test() -> std::io::Result<()> {
+    let _guard = TEST_IMG_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let block_file = test_block_file()?;
+    EzFileSys::new(block_file.clone(), 4096, 1).unwrap();
+    let efs = EzFileSys::from_device(block_file).unwrap();
+    let root_inode = Arc::new(EzFileSys::root_vinode(&efs));
+    let log = vec![b'x'; 3 * BLOCK_SZ + 7];
+    let var = root_inode.mkdir("var").unwrap();
+    var.create("log").unwrap().write_at(0, &log).unwrap();
+    var.mkdir("empty").unwrap();
+    root_inode.create("top").unwrap().write_at(0, b"top").unwrap();
+
+    let files: Vec<_> = list_tree(&root_inode, "/", true)
+        .unwrap()
+        .into_iter()
+        .map(|(path, size, is_dir)| (path, if is_dir { None } else { Some(size) }))
+        .collect();
+    assert_eq!(files, [
+        ("/var".to_string(), None),
+        ("/var/log".to_string(), Some(log.len() as u64)),
+        ("/var/empty".to_string(), None),
+        ("/top".to_string(), Some(3))
+    ]);
+    assert_eq!(list_tree(&root_inode, "/", false).unwrap().len(), 2);
+
+    let dest = Path::new("target/extract_test");
+    let _ = fs::remove_dir_all(dest);
+    extract_tree(&root_inode, dest)?;
+    assert_eq!(fs::read(dest.join("var/log"))?, log);
+    assert_eq!(fs::read(dest.join("top"))?, b"top");
+    assert!(dest.join("var/empty").is_dir());
+    fs::remove_dir_all(dest)?;
+    Ok(())
+}
+
+#[test]
+fn efs_add_test() -> std::io::Result<()> {
+    let _guard = TEST_IMG_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    assert_eq!(parse_size("16M"), Some(16 << 20));
+    assert_eq!(parse_size("512k"), Some(512 << 10));
+    assert_eq!(parse_size("1024"), Some(1024));
+    assert_eq!(parse_size("M"), None);
+    assert_eq!(parse_size("1x"), None);
+
+    let block_file = test_block_file()?;
+    EzFileSys::new(block_file.clone(), 4096, 1).unwrap();
+    let efs = EzFileSys::from_device(block_file).unwrap();
+    let root_inode = Arc::new(EzFileSys::root_vinode(&efs));
+    root_inode.create("keep").unwrap().write_at(0, b"keep").unwrap();
+
+    let src = Path::new("target/add_test");
+    let _ = fs::remove_dir_all(src);
+    fs::create_dir_all(src.join("etc"))?;
+    fs::write(src.join("etc/motd.txt"), b"hello")?;
+    fs::write(src.join("data.tar.gz"), vec![7u8; 2 * BLOCK_SZ + 3])?;
+    put_host_path(&root_inode, &src.join("etc"))?;
+    put_host_path(&root_inode, &src.join("data.tar.gz"))?;
+    // replacing shrinks the file rather than leaving stale data behind
+    fs::write(src.join("data.tar.gz"), b"short")?;
+    put_host_path(&root_inode, &src.join("data.tar.gz"))?;
+    fs::remove_dir_all(src)?;
+
+    let etc = root_inode.find("etc").unwrap();
+    assert_eq!(etc.is_dir(), Ok(true));
+    assert_eq!(read_all(&etc.find("motd.txt").unwrap()).unwrap(), b"hello");
+    assert_eq!(read_all(&root_inode.find("data.tar.gz").unwrap()).unwrap(), b"short");
+    assert_eq!(read_all(&root_inode.find("keep").unwrap()).unwrap(), b"keep");
+    assert!(check(&efs, false).unwrap().is_empty());
+    Ok(())
+}
+
+#[test]
+fn block_cache_lru_test() -> std::io::Result<()> {
+    let _guard = TEST_IMG_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let dev: Arc<dyn BlockDev> = test_block_file()?;
+    let other: Arc<dyn BlockDev> = test_block_file()?;
+    let mut man = BlockCacheMan::new(4);
+    for id in 0..4 {
+        man.get_block_cache(id, Arc::clone(&dev)).unwrap();
+    }
+    // same block id on another device is a different entry
+    man.get_block_cache(0, Arc::clone(&other)).unwrap();
+    assert_eq!(man.len(), 4);
+    assert!(man.peek_block_cache(0, &dev).is_none());
+    assert!(man.peek_block_cache(0, &other).is_some());
+    // a hit makes block 1 the most recently used, so 2 goes next
+    man.get_block_cache(1, Arc::clone(&dev)).unwrap();
+    man.get_block_cache(4, Arc::clone(&dev)).unwrap();
+    assert!(man.peek_block_cache(1, &dev).is_some());
+    assert!(man.peek_block_cache(2, &dev).is_none());
+
+    // borrowed blocks are kept, running over capacity
+    let held: Vec<_> = (10..16).map(|id| man.get_block_cache(id, Arc::clone(&dev)).unwrap()).collect();
+    for cache in held.iter() {
+        cache.lock().modify(0, |byte: &mut u8| *byte = 0x5a);
+    }
+    assert_eq!(man.len(), 6);
+    drop(held);
+    // once released, the oldest dirty blocks are written back to make room
+    man.get_block_cache(20, Arc::clone(&dev)).unwrap();
+    assert_eq!(man.len(), 4);
+    assert!(man.peek_block_cache(12, &dev).is_none());
+    assert!(man.peek_block_cache(13, &dev).is_some());
+    let mut block = [0u8; BLOCK_SZ];
+    dev.read_block(10, &mut block).unwrap();
+    assert_eq!(block[0], 0x5a);
+    man.set_capacity(1).unwrap();
+    assert_eq!(man.len(), 1);
+    assert_eq!(man.capacity(), 1);
+
+    // the dirty blocks of a mounted journal wait for its commit
+    EzFileSys::new(Arc::clone(&dev), 4096, 1).unwrap();
+    let efs = EzFileSys::from_device(Arc::clone(&dev)).unwrap();
+    let start = efs.lock().data_block_pos(3000) as usize;
+    for id in start..start + 4 {
+        man.get_block_cache(id, Arc::clone(&dev)).unwrap().lock().modify(0, |byte: &mut u8| *byte = 0xa5);
+    }
+    assert_eq!(man.len(), 4);
+    dev.read_block(start, &mut block).unwrap();
+    assert_eq!(block[0], 0);
+    drop(efs);
+    man.get_block_cache(start + 4, Arc::clone(&dev)).unwrap();
+    assert_eq!(man.len(), 1);
+    Ok(())
+}
+
+#[test]
+fn block_cache_write_back_test() -> std::io::Result<()> {
+    use easyfs::cache_man::{get_block_cache, periodic_write_back, BlockCacheMan, DIRTY_ROUNDS};
+    let _guard = TEST_IMG_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let dev: Arc<dyn BlockDev> = test_block_file()?;
+    let mut block = [0u8; BLOCK_SZ];
+    dev.write_block(100, &block).unwrap();
+    // a dirty block goes to the device once it sat out enough rounds
+    get_block_cache(100, Arc::clone(&dev)).unwrap().lock().modify(0, |byte: &mut u8| *byte = 0x3c);
+    for _ in 1..DIRTY_ROUNDS {
+        periodic_write_back().unwrap();
+    }
+    dev.read_block(100, &mut block).unwrap();
+    assert_eq!(block[0], 0);
+    periodic_write_back().unwrap();
+    dev.read_block(100, &mut block).unwrap();
+    assert_eq!(block[0], 0x3c);
+
+    // borrowed blocks run over capacity only up to a hard limit
+    let mut man = BlockCacheMan::new(4);
+    let limit = 2 * 4 + JOURNAL_LOG_BLOCKS;
+    let held: Vec<_> = (0..limit).map(|id| man.get_block_cache(id, Arc::clone(&dev)).unwrap()).collect();
+    assert!(matches!(man.get_block_cache(limit, Arc::clone(&dev)), Err(EzFsError::Busy)));
+    drop(held);
+    man.get_block_cache(limit, Arc::clone(&dev)).unwrap();
+    assert_eq!(man.len(), 4);
+    Ok(())
+}
+
+#[cfg(test)]
+struct CountFile {
+    file: Arc<BlockFile>,
+    reads: AtomicU64,
+    writes: AtomicU64
+}
+
+#[cfg(test)]
+impl BlockDev for CountFile {
+    fn read_block(&self, block_id: usize, buf: &mut [u8]) -> EzResult<()> {
+        self.read_blocks(block_id, buf)
+    }
+
+    fn write_block(&self, block_id: usize, buf: &[u8]) -> EzResult<()> {
+        self.write_blocks(block_id, buf)
+    }
+
+    fn read_blocks(&self, start_block: usize, buf: &mut [u8]) -> EzResult<()> {
+        self.reads.fetch_add(1, Ordering::SeqCst);
+        self.file.read_blocks(start_block, buf)
+    }
+
+    fn write_blocks(&self, start_block: usize, buf: &[u8]) -> EzResult<()> {
+        self.writes.fetch_add(1, Ordering::SeqCst);
+        self.file.write_blocks(start_block, buf)
+    }
+}
+
+#[test]
+fn block_io_batch_test() -> std::io::Result<()> {
+    let _guard = TEST_IMG_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    EzFileSys::new(test_block_file()?, 4096, 1).unwrap();
+    // every mount gets a device of its own, which shares no cached blocks
+    let mount = || -> std::io::Result<(Arc<CountFile>, Arc<VirtInode>)> {
+        let count_file = Arc::new(CountFile {
+            file: test_block_file()?,
+            reads: AtomicU64::new(0),
+            writes: AtomicU64::new(0)
+        });
+        let efs = EzFileSys::from_device(count_file.clone()).unwrap();
+        Ok((count_file, Arc::new(EzFileSys::root_vinode(&efs))))
+    };
+
+    let (count_file, root_inode) = mount()?;
+    let file = root_inode.create("file").unwrap();
+    let data: Vec<u8> = (0..64 * BLOCK_SZ).map(|i| (i / BLOCK_SZ) as u8).collect();
+    let writes = count_file.writes.load(Ordering::SeqCst);
+    assert_eq!(file.write_at(0, &data[..8 * BLOCK_SZ]), Ok(8 * BLOCK_SZ));
+    // log, header, the home runs and clearing the header, not one per block
+    assert!(count_file.writes.load(Ordering::SeqCst) - writes <= 6);
+    assert_eq!(file.write_at(8 * BLOCK_SZ, &data[8 * BLOCK_SZ..]), Ok(56 * BLOCK_SZ));
+
+    // whole uncached blocks are read in runs
+    let (count_file, root_inode) = mount()?;
+    let file = root_inode.find("file").unwrap();
+    let reads = count_file.reads.load(Ordering::SeqCst);
+    let mut buffer = vec![0u8; data.len()];
+    assert_eq!(file.read_at(0, &mut buffer), Ok(data.len()));
+    assert_eq!(buffer, data);
+    assert!(count_file.reads.load(Ordering::SeqCst) - reads <= 4);
+
+    // a small read pulls in the blocks that follow it
+    let (count_file, root_inode) = mount()?;
+    let file = root_inode.find("file").unwrap();
+    let mut buffer = [0u8; 16];
+    assert_eq!(file.read_at(0, &mut buffer), Ok(16));
+    let reads = count_file.reads.load(Ordering::SeqCst);
+    for blk in 1..4 {
+        assert_eq!(file.read_at(blk * BLOCK_SZ + 100, &mut buffer), Ok(16));
+        assert_eq!(buffer, [blk as u8; 16]);
+    }
+    assert_eq!(count_file.reads.load(Ordering::SeqCst), reads);
+    Ok(())
+}
+
+#[test]
+fn efs_large_file_test() -> std::io::Result<()> {
+    let _guard = TEST_IMG_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    // room for a file reaching into the triple indirect blocks
+    let block_file = test_block_file()?;
+    block_file.0.lock().unwrap().set_len(40960 * BLOCK_SZ as u64)?;
+    EzFileSys::new(block_file.clone(), 40960, 1).unwrap();
+    let efs = EzFileSys::from_device(block_file.clone()).unwrap();
+    let root_inode = Arc::new(EzFileSys::root_vinode(&efs));
+
+    // files growing side by side each keep a run of their own
+    let filea = root_inode.create("filea").unwrap();
+    let fileb = root_inode.create("fileb").unwrap();
+    for i in 0..16 {
+        assert_eq!(filea.write_at(i * 4096, &[1u8; 4096]), Ok(4096));
+        assert_eq!(fileb.write_at(i * 4096, &[2u8; 4096]), Ok(4096));
+    }
+    let count_file = Arc::new(CountFile {
+        file: block_file.clone(),
+        reads: AtomicU64::new(0),
+        writes: AtomicU64::new(0)
+    });
+    let count_efs = EzFileSys::from_device(count_file.clone()).unwrap();
+    let count_root = Arc::new(EzFileSys::root_vinode(&count_efs));
+    for (name, byte) in [("filea", 1u8), ("fileb", 2u8)] {
+        let file = count_root.find(name).unwrap();
+        let reads = count_file.reads.load(Ordering::SeqCst);
+        let mut buffer = vec![0u8; 16 * 4096];
+        assert_eq!(file.read_at(0, &mut buffer), Ok(buffer.len()));
+        assert!(buffer.iter().all(|b| *b == byte));
+        // the index block and one run of data
+        assert!(count_file.reads.load(Ordering::SeqCst) - reads <= 3);
+    }
+
+    // past the 18 direct, 128 single and 128 * 128 double indirect blocks
+    let blocks = 18 + 128 + 128 * 128 + 300;
+    let data: Vec<u8> = (0..blocks * BLOCK_SZ).map(|i| ((i / BLOCK_SZ) ^ (i / (256 * BLOCK_SZ))) as u8).collect();
+    let big = root_inode.create("big").unwrap();
+    assert_eq!(big.write_at(0, &data), Ok(data.len()));
+    let stat = big.stat().unwrap();
+    assert_eq!(stat.size as usize, data.len());
+    // index blocks: 1 single, 1 + 128 double, 1 + 1 + 3 triple
+    assert_eq!(stat.blocks as usize, blocks + 135);
+    let mut buffer = vec![0u8; data.len()];
+    assert_eq!(big.read_at(0, &mut buffer), Ok(data.len()));
+    assert!(buffer == data);
+    assert!(check(&efs, false).unwrap().is_empty());
+
+    // freeing it takes several transactions, each fitting in the journal
+    assert_eq!(root_inode.unlink("big"), Ok(()));
+    assert!(check(&efs, false).unwrap().is_empty());
+    let again = root_inode.create("big").unwrap();
+    assert_eq!(again.write_at(0, &data), Ok(data.len()));
+    again.clear().unwrap();
+    assert_eq!(again.stat().unwrap().blocks, 0);
+    assert!(check(&efs, false).unwrap().is_empty());
+    Ok(())
+}
+
+#[test]
+fn efs_sparse_test() -> std::io::Result<()> {
+    let _guard = TEST_IMG_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let block_file = test_block_file()?;
+    EzFileSys::new(block_file.clone(), 8192, 1).unwrap();
+    let efs = EzFileSys::from_device(block_file.clone()).unwrap();
+    let root_inode = Arc::new(EzFileSys::root_vinode(&efs));
+
+    // a write far past the end leaves a hole instead of filling it in
+    let file = root_inode.create("sparse").unwrap();
+    let offset = 20000 * BLOCK_SZ + 100;
+    assert_eq!(file.write_at(offset, b"tail"), Ok(4));
+    let stat = file.stat().unwrap();
+    assert_eq!(stat.size as usize, offset + 4);
+    // the data block and the three index blocks of the triple indirect tree
+    assert_eq!(stat.blocks, 4);
+    let mut buffer = vec![1u8; 2 * BLOCK_SZ];
+    assert_eq!(file.read_at(19999 * BLOCK_SZ, &mut buffer), Ok(BLOCK_SZ + 104));
+    assert!(buffer[..BLOCK_SZ + 100].iter().all(|b| *b == 0));
+    assert_eq!(&buffer[BLOCK_SZ + 100..BLOCK_SZ + 104], b"tail");
+    assert_eq!(file.write_at(5 * BLOCK_SZ, &[7u8; BLOCK_SZ]), Ok(BLOCK_SZ));
+    assert_eq!(file.stat().unwrap().blocks, 5);
+    assert!(check(&efs, false).unwrap().is_empty());
+
+    // cutting into the hole drops the blocks past the new end
+    assert_eq!(file.truncate(6 * BLOCK_SZ + 10), Ok(()));
+    let stat = file.stat().unwrap();
+    assert_eq!((stat.size as usize, stat.blocks), (6 * BLOCK_SZ + 10, 1));
+    assert!(check(&efs, false).unwrap().is_empty());
+
+    // growing again exposes zeros, also in the rest of a cut block
+    assert_eq!(file.write_at(0, &[3u8; 3 * BLOCK_SZ]), Ok(3 * BLOCK_SZ));
+    assert_eq!(file.truncate(2 * BLOCK_SZ + 10), Ok(()));
+    assert_eq!(file.truncate(10 * BLOCK_SZ), Ok(()));
+    let stat = file.stat().unwrap();
+    assert_eq!((stat.size as usize, stat.blocks), (10 * BLOCK_SZ, 3));
+    let mut buffer = vec![1u8; 10 * BLOCK_SZ];
+    assert_eq!(file.read_at(0, &mut buffer), Ok(buffer.len()));
+    assert!(buffer[..2 * BLOCK_SZ + 10].iter().all(|b| *b == 3));
+    assert!(buffer[2 * BLOCK_SZ + 10..].iter().all(|b| *b == 0));
+    assert!(check(&efs, false).unwrap().is_empty());
+
+    assert_eq!(root_inode.truncate(0), Err(EzFsError::IsDir));
+    assert_eq!(file.truncate(usize::MAX / 2), Err(EzFsError::TooLarge));
+    assert_eq!(file.truncate(0), Ok(()));
+    assert_eq!(file.stat().unwrap().blocks, 0);
+
+    // a block count off from the tree is put right by fsck
+    assert_eq!(file.write_at(40 * BLOCK_SZ, &[5u8; 10]), Ok(10));
+    let (block_id, block_offset) = efs.lock().inode_pos(file.inode_id());
+    get_block_cache(block_id as usize, block_file.clone())
+        .unwrap()
+        .lock()
+        .modify(block_offset, |inode: &mut DiskInode| inode.blocks = 9);
+    efs.lock().commit().unwrap();
+    let issues = check(&efs, true).unwrap();
+    assert_eq!(issues, vec![(FsckIssue::BlockCount { inode_id: 1, blocks: 9, found: 2 }, true)]);
+    assert!(check(&efs, false).unwrap().is_empty());
+    Ok(())
+}
+
+// holds up reads of one block until it is opened
+#[cfg(test)]
+struct GateFile {
+    file: Arc<BlockFile>,
+    gated: AtomicU64,
+    waiting: AtomicBool,
+    open: AtomicBool
+}
+
+#[cfg(test)]
+impl BlockDev for GateFile {
+    fn read_block(&self, block_id: usize, buf: &mut [u8]) -> EzResult<()> {
+        self.read_blocks(block_id, buf)
+    }
+
+    fn write_block(&self, block_id: usize, buf: &[u8]) -> EzResult<()> {
+        self.write_blocks(block_id, buf)
+    }
+
+    fn read_blocks(&self, start_block: usize, buf: &mut [u8]) -> EzResult<()> {
+        let gated = self.gated.load(Ordering::SeqCst) as usize;
+        if (start_block..start_block + buf.len() / BLOCK_SZ).contains(&gated) {
+            self.waiting.store(true, Ordering::SeqCst);
+            while !self.open.load(Ordering::SeqCst) {
+                std::thread::yield_now();
+            }
+        }
+        self.file.read_blocks(start_block, buf)
+    }
+
+    fn write_blocks(&self, start_block: usize, buf: &[u8]) -> EzResult<()> {
+        self.file.write_blocks(start_block, buf)
+    }
+}
+
+#[test]
+fn efs_concurrent_test() -> std::io::Result<()> {
+    let _guard = TEST_IMG_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let block_file = test_block_file()?;
+    let efs = EzFileSys::new(block_file.clone(), 4096, 1).unwrap();
+    let root_inode = EzFileSys::root_vinode(&efs);
+    root_inode.create("x").unwrap().write_at(0, &[1u8; 4 * BLOCK_SZ]).unwrap();
+    root_inode.create("y").unwrap().write_at(0, &[2u8; 4 * BLOCK_SZ]).unwrap();
+
+    // a device of its own starts with nothing cached
+    let gate = Arc::new(GateFile {
+        file: test_block_file()?,
+        gated: AtomicU64::new(u64::MAX),
+        waiting: AtomicBool::new(false),
+        open: AtomicBool::new(false)
+    });
+    let dev: Arc<dyn BlockDev> = gate.clone();
+    let efs = EzFileSys::from_device(dev.clone()).unwrap();
+    let root_inode = Arc::new(EzFileSys::root_vinode(&efs));
+    let x = root_inode.find("x").unwrap();
+    let y = root_inode.find("y").unwrap();
+    let (block_id, block_offset) = efs.lock().inode_pos(x.inode_id());
+    let first = get_block_cache(block_id as usize, dev.clone()).unwrap()
+        .lock()
+        .read(block_offset, |inode: &DiskInode| inode.get_block_id(0, &dev))
+        .unwrap();
+    gate.gated.store(first as u64, Ordering::SeqCst);
+    let reader = {
+        let x = x.clone();
+        std::thread::spawn(move || {
+            let mut buf = vec![0u8; 4 * BLOCK_SZ];
+            (x.read_at(0, &mut buf), buf)
+        })
+    };
+    while !gate.waiting.load(Ordering::SeqCst) {
+        std::thread::yield_now();
+    }
+
+    // while the reader of "x" waits for the disk, the rest carries on
+    let (tx, rx) = std::sync::mpsc::channel();
+    let other = {
+        let (root_inode, x) = (root_inode.clone(), x.clone());
+        std::thread::spawn(move || {
+            let mut buf = vec![0u8; 4 * BLOCK_SZ];
+            let read = y.read_at(0, &mut buf).map(|len| (len, buf.iter().all(|&b| b == 2)));
+            let written = y.write_at(BLOCK_SZ, &[3u8; BLOCK_SZ]);
+            let made = root_inode.create("z").map(|_| ());
+            tx.send((read, written, made, x.stat().map(|stat| stat.size))).unwrap();
+        })
+    };
+    let res = rx.recv_timeout(std::time::Duration::from_secs(10));
+    gate.open.store(true, Ordering::SeqCst);
+    assert_eq!(res, Ok((Ok((4 * BLOCK_SZ, true)), Ok(BLOCK_SZ), Ok(()), Ok(4 * BLOCK_SZ as u64))));
+    other.join().unwrap();
+    let (read, buf) = reader.join().unwrap();
+    assert_eq!(read, Ok(4 * BLOCK_SZ));
+    assert!(buf.iter().all(|&b| b == 1));
+    assert!(check(&efs, false).unwrap().is_empty());
+    Ok(())
+}
+
+#[test]
+fn efs_statfs_test() -> std::io::Result<()> {
+    let _guard = TEST_IMG_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let block_file = test_block_file()?;
+    EzFileSys::new(block_file.clone(), 4096, 1).unwrap();
+    let efs = EzFileSys::from_device(block_file).unwrap();
+    let root_inode = Arc::new(EzFileSys::root_vinode(&efs));
+    let empty = root_inode.statfs();
+    assert_eq!(empty.block_size, BLOCK_SZ as u64);
+    assert_eq!(empty.inodes, BLOCK_SZ as u64 * 8);
+    // the root and its first directory block
+    assert_eq!(empty.free_inodes, empty.inodes - 1);
+    assert_eq!(empty.free_blocks, empty.blocks - 1);
+
+    root_inode.create("file").unwrap().write_at(0, &[1u8; 4 * BLOCK_SZ]).unwrap();
+    let st = root_inode.statfs();
+    assert_eq!((st.free_inodes, st.free_blocks), (empty.free_inodes - 1, empty.free_blocks - 4));
+
+    // fill the disk; a failed mkdir leaves the counts as they were
+    let big = root_inode.create("big").unwrap();
+    big.write_at(0, &vec![7u8; 8192 * BLOCK_SZ]).unwrap();
+    let mut i = 0;
+    let full = loop {
+        let before = root_inode.statfs();
+        match root_inode.mkdir(&format!("d{}", i)) {
+            Ok(_) => i += 1,
+            Err(e) => {
+                assert_eq!(e, EzFsError::NoSpace);
+                break before;
+            }
+        }
+    };
+    assert_eq!(root_inode.statfs(), full);
+    assert!(full.free_blocks < 2);
+    efs.lock().recount().unwrap();
+    assert_eq!(root_inode.statfs(), full);
+
+    let big_blocks = big.stat().unwrap().blocks;
+    root_inode.unlink("big").unwrap();
+    // the file goes when it is closed
+    drop(big);
+    let st = root_inode.statfs();
+    assert_eq!(st.free_blocks, full.free_blocks + big_blocks);
+    assert_eq!(st.free_inodes, full.free_inodes + 1);
+    assert!(check(&efs, false).unwrap().is_empty());
+    efs.lock().recount().unwrap();
+    assert_eq!(root_inode.statfs(), st);
+    Ok(())
+}
@@ -0,0 +1,315 @@
+use easyfs::vfs::{Stat, VirtInode, S_IFDIR, S_IFLNK, S_IFMT};
+use easyfs::sync::Mutex;
+use easyfs::{EzFileSys, EzFsError, EzResult};
+use fuser::{
+    FileAttr, FileType, Filesystem, MountOption, ReplyAttr, ReplyCreate, ReplyData, ReplyDirectory,
+    ReplyEmpty, ReplyEntry, ReplyStatfs, ReplyWrite, Request, TimeOrNow,
+};
+use libc::{c_int, EINVAL, RENAME_NOREPLACE};
+use std::ffi::OsStr;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::BLOCK_SZ;
+
+const TTL: Duration = Duration::from_secs(1);
+
+// fuse numbers inodes from 1, easyfs from 0
+fn to_ino(inode_id: u32) -> u64 {
+    inode_id as u64 + 1
+}
+
+fn to_time(ms: u64) -> SystemTime {
+    UNIX_EPOCH + Duration::from_millis(ms)
+}
+
+fn errno(e: EzFsError) -> c_int {
+    e.errno()
+}
+
+// the kind of a file by its S_IF* bits
+fn kind(mode: u32) -> FileType {
+    match mode & S_IFMT {
+        S_IFDIR => FileType::Directory,
+        S_IFLNK => FileType::Symlink,
+        _ => FileType::RegularFile,
+    }
+}
+
+pub struct EzFuse {
+    efs: Arc<Mutex<EzFileSys>>,
+    uid: u32,
+    gid: u32,
+}
+
+impl EzFuse {
+    pub fn new(efs: Arc<Mutex<EzFileSys>>) -> Self {
+        Self {
+            efs,
+            uid: unsafe { libc::getuid() },
+            gid: unsafe { libc::getgid() },
+        }
+    }
+
+    fn inode(&self, ino: u64) -> Arc<VirtInode> {
+        Arc::new(EzFileSys::get_vinode(&self.efs, (ino - 1) as u32))
+    }
+
+    fn attr(&self, stat: &Stat) -> FileAttr {
+        FileAttr {
+            ino: stat.ino + 1,
+            size: stat.size,
+            blocks: stat.blocks,
+            atime: to_time(stat.atime),
+            mtime: to_time(stat.mtime),
+            ctime: to_time(stat.ctime),
+            crtime: to_time(stat.ctime),
+            kind: kind(stat.mode),
+            perm: (stat.mode & 0o7777) as u16,
+            nlink: stat.nlink,
+            uid: self.uid,
+            gid: self.gid,
+            rdev: 0,
+            blksize: BLOCK_SZ as u32,
+            flags: 0,
+        }
+    }
+
+    pub fn lookup_attr(&self, parent: u64, name: &str) -> Result<FileAttr, c_int> {
+        let inode = self.inode(parent).find(name).map_err(errno)?;
+        Ok(self.attr(&inode.stat().map_err(errno)?))
+    }
+
+    // (ino, offset of the next entry, kind, name) from `offset` on
+    pub fn dir_entries(&self, ino: u64, offset: i64) -> Result<Vec<(u64, i64, FileType, String)>, c_int> {
+        let dir = self.inode(ino);
+        let mut v = Vec::new();
+        let mut pos = offset as usize;
+        while let Some((item, next)) = dir.read_dir(pos).map_err(errno)? {
+            v.push((to_ino(item.inode_id), next as i64, kind(item.mode), item.name));
+            pos = next;
+        }
+        Ok(v)
+    }
+
+    fn make(&self, parent: u64, name: &str, mode: u32, is_dir: bool) -> EzResult<FileAttr> {
+        let dir = self.inode(parent);
+        let inode = if is_dir { dir.mkdir(name) } else { dir.create(name) }?;
+        inode.set_mode(mode)?;
+        Ok(self.attr(&inode.stat()?))
+    }
+
+    fn set_attr(&self, ino: u64, mode: Option<u32>, size: Option<u64>) -> Result<FileAttr, c_int> {
+        let inode = self.inode(ino);
+        if let Some(mode) = mode {
+            inode.set_mode(mode).map_err(errno)?;
+        }
+        if let Some(size) = size {
+            inode.truncate(size as usize).map_err(errno)?;
+        }
+        Ok(self.attr(&inode.stat().map_err(errno)?))
+    }
+}
+
+impl Filesystem for EzFuse {
+    fn lookup(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        match self.lookup_attr(parent, &name.to_string_lossy()) {
+            Ok(attr) => reply.entry(&TTL, &attr, 0),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn getattr(&mut self, _req: &Request<'_>, ino: u64, reply: ReplyAttr) {
+        match self.inode(ino).stat() {
+            Ok(stat) => reply.attr(&TTL, &self.attr(&stat)),
+            Err(e) => reply.error(errno(e)),
+        }
+    }
+
+    fn setattr(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        mode: Option<u32>,
+        _uid: Option<u32>,
+        _gid: Option<u32>,
+        size: Option<u64>,
+        _atime: Option<TimeOrNow>,
+        _mtime: Option<TimeOrNow>,
+        _ctime: Option<SystemTime>,
+        _fh: Option<u64>,
+        _crtime: Option<SystemTime>,
+        _chgtime: Option<SystemTime>,
+        _bkuptime: Option<SystemTime>,
+        _flags: Option<u32>,
+        reply: ReplyAttr,
+    ) {
+        match self.set_attr(ino, mode, size) {
+            Ok(attr) => reply.attr(&TTL, &attr),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn mkdir(
+        &mut self,
+        _req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        umask: u32,
+        reply: ReplyEntry,
+    ) {
+        match self.make(parent, &name.to_string_lossy(), mode & !umask, true) {
+            Ok(attr) => reply.entry(&TTL, &attr, 0),
+            Err(e) => reply.error(errno(e)),
+        }
+    }
+
+    fn unlink(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        match self.inode(parent).unlink(&name.to_string_lossy()) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(errno(e)),
+        }
+    }
+
+    fn rmdir(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        match self.inode(parent).rmdir(&name.to_string_lossy()) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(errno(e)),
+        }
+    }
+
+    fn symlink(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, link: &Path, reply: ReplyEntry) {
+        let inode = self.inode(parent).symlink(&name.to_string_lossy(), &link.to_string_lossy());
+        match inode.and_then(|inode| inode.stat()) {
+            Ok(stat) => reply.entry(&TTL, &self.attr(&stat), 0),
+            Err(e) => reply.error(errno(e)),
+        }
+    }
+
+    fn readlink(&mut self, _req: &Request<'_>, ino: u64, reply: ReplyData) {
+        match self.inode(ino).readlink() {
+            Ok(target) => reply.data(target.as_bytes()),
+            Err(e) => reply.error(errno(e)),
+        }
+    }
+
+    fn rename(
+        &mut self,
+        _req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        newparent: u64,
+        newname: &OsStr,
+        flags: u32,
+        reply: ReplyEmpty,
+    ) {
+        // RENAME_EXCHANGE is not supported
+        if flags & !RENAME_NOREPLACE != 0 {
+            return reply.error(EINVAL);
+        }
+        let new_dir = self.inode(newparent);
+        let replace = flags & RENAME_NOREPLACE == 0;
+        match self.inode(parent).rename(&name.to_string_lossy(), &new_dir, &newname.to_string_lossy(), replace) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(errno(e)),
+        }
+    }
+
+    fn read(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        size: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: ReplyData,
+    ) {
+        let mut buf = vec![0u8; size as usize];
+        match self.inode(ino).read_at(offset as usize, &mut buf) {
+            Ok(len) => reply.data(&buf[..len]),
+            Err(e) => reply.error(errno(e)),
+        }
+    }
+
+    fn write(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        data: &[u8],
+        _write_flags: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: ReplyWrite,
+    ) {
+        match self.inode(ino).write_at(offset as usize, data) {
+            Ok(len) => reply.written(len as u32),
+            Err(e) => reply.error(errno(e)),
+        }
+    }
+
+    fn create(
+        &mut self,
+        _req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        umask: u32,
+        _flags: i32,
+        reply: ReplyCreate,
+    ) {
+        match self.make(parent, &name.to_string_lossy(), mode & !umask, false) {
+            Ok(attr) => reply.created(&TTL, &attr, 0, 0, 0),
+            Err(e) => reply.error(errno(e)),
+        }
+    }
+
+    fn readdir(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        mut reply: ReplyDirectory,
+    ) {
+        match self.dir_entries(ino, offset) {
+            Ok(entries) => {
+                for (ino, next, kind, name) in entries {
+                    if reply.add(ino, next, kind, name) {
+                        break;
+                    }
+                }
+                reply.ok();
+            }
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn statfs(&mut self, _req: &Request<'_>, _ino: u64, reply: ReplyStatfs) {
+        let st = self.efs.lock().statfs();
+        reply.statfs(
+            st.blocks,
+            st.free_blocks,
+            st.free_blocks,
+            st.inodes,
+            st.free_inodes,
+            st.block_size as u32,
+            st.name_max as u32,
+            st.block_size as u32,
+        );
+    }
+}
+
+// serve the image at `mountpoint` until it is unmounted
+pub fn mount(efs: Arc<Mutex<EzFileSys>>, mountpoint: &str) -> std::io::Result<()> {
+    let options = [
+        MountOption::FSName("easyfs".to_string()),
+        MountOption::DefaultPermissions,
+    ];
+    fuser::mount2(EzFuse::new(efs), mountpoint, &options)
+}
@@ -1,52 +1,123 @@
+use core::cmp::min;
+
 use alloc::sync::Arc;
 
-use crate::{BLOCK_SIZE, BlockDev, cache_man::get_block_cache};
+use crate::{BLOCK_SIZE, BlockDev, EzFsError, EzResult, cache_man::get_block_cache};
 
 pub const BLOCK_BITS: usize = BLOCK_SIZE * 8;
 pub struct Bitmap {
     start_block_id: usize,
-    blocks: usize
+    // positions in use, the last block may have spare bits
+    bits: usize
 }
 impl Bitmap {
-    pub fn new(start_block_id: usize, blocks: usize) -> Self {
+    pub fn new(start_block_id: usize, blocks: usize, bits: usize) -> Self {
         Self {
-            start_block_id, blocks
+            start_block_id,
+            bits: min(bits, blocks * BLOCK_BITS)
+        }
+    }
+    pub fn alloc(&self, block_dev: &Arc<dyn BlockDev>) -> EzResult<Option<usize>> {
+        self.alloc_near(block_dev, 0)
+    }
+    // the first free position at or after `goal`, wrapping around to the start
+    pub fn alloc_near(&self, block_dev: &Arc<dyn BlockDev>, goal: usize) -> EzResult<Option<usize>> {
+        let goal = if goal < self.bits { goal } else { 0 };
+        match self.alloc_in(block_dev, goal, self.bits)? {
+            Some(pos) => Ok(Some(pos)),
+            None => self.alloc_in(block_dev, 0, goal)
         }
     }
-    pub fn alloc(&self, block_dev: &Arc<dyn BlockDev>) -> Option<usize> {
-        (0..self.blocks).find_map(|id|
-            get_block_cache(id + self.start_block_id, Arc::clone(block_dev))
+    fn alloc_in(&self, block_dev: &Arc<dyn BlockDev>, from: usize, to: usize) -> EzResult<Option<usize>> {
+        let mut pos = from;
+        while pos < to {
+            let block_pos = pos / BLOCK_BITS;
+            let block_end = min(to, (block_pos + 1) * BLOCK_BITS);
+            let bitmap_block = get_block_cache(block_pos + self.start_block_id, Arc::clone(block_dev))?;
+            let mut bitmap_block = bitmap_block.lock();
+            // look before touching, so full blocks are not made dirty
+            let found = bitmap_block.read(0, |bitmap_block: &BitmapBlock| {
+                let mut pos = pos;
+                while pos < block_end {
+                    let (bits_pos, inner_pos) = ((pos % BLOCK_BITS) >> 6, pos & 63);
+                    let free = !bitmap_block[bits_pos] & (u64::MAX << inner_pos);
+                    if free != 0 {
+                        let found = pos - inner_pos + free.trailing_zeros() as usize;
+                        return if found < block_end { Some(found) } else { None };
+                    }
+                    pos += 64 - inner_pos;
+                }
+                None
+            });
+            if let Some(found) = found {
+                let (_, bits_pos, inner_pos) = Self::pos_decomp(found);
+                bitmap_block.modify(0, |bitmap_block: &mut BitmapBlock| {
+                    bitmap_block[bits_pos] |= 1u64 << inner_pos;
+                });
+                return Ok(Some(found));
+            }
+            pos = block_end;
+        }
+        Ok(None)
+    }
+    // positions not in use, counted from the blocks
+    pub fn count_free(&self, block_dev: &Arc<dyn BlockDev>) -> EzResult<usize> {
+        let mut used = 0;
+        for block_pos in 0..(self.bits + BLOCK_BITS - 1) / BLOCK_BITS {
+            let bits = min(BLOCK_BITS, self.bits - block_pos * BLOCK_BITS);
+            used += get_block_cache(block_pos + self.start_block_id, Arc::clone(block_dev))?
                 .lock()
-                .modify(
-                    0, 
-                    |bitmap_block: &mut BitmapBlock| bitmap_block
-                        .iter_mut()
-                        .enumerate()
-                        .find(|(_, bits)| **bits != u64::MAX)
-                        .map(|(bits_pos, bits)| {
-                            let inner_pos = bits.trailing_ones();
-                            *bits |= 1u64 << inner_pos;
-                            id * BLOCK_BITS + bits_pos * 64 + inner_pos as usize
-                        })
-                )
-        )
+                .read(0, |bitmap_block: &BitmapBlock| {
+                    bitmap_block.iter().enumerate().map(|(i, word)| match bits.saturating_sub(i * 64) {
+                        0 => 0,
+                        n if n < 64 => (word & ((1u64 << n) - 1)).count_ones() as usize,
+                        _ => word.count_ones() as usize
+                    }).sum::<usize>()
+                });
+        }
+        Ok(self.bits - used)
+    }
+    pub fn bits(&self) -> usize {
+        self.bits
     }
     fn pos_decomp(pos: usize) -> (usize, usize, usize) {
         let (block_pos, inner_pos) = (pos / BLOCK_BITS, pos % BLOCK_BITS);
         (block_pos, inner_pos >> 6, inner_pos & 63)
     }
-    pub fn dealloc(&self, block_dev: &Arc<dyn BlockDev>, pos: usize) {
+    pub fn is_set(&self, block_dev: &Arc<dyn BlockDev>, pos: usize) -> EzResult<bool> {
+        let (block_pos, bits_pos, inner_pos) = Self::pos_decomp(pos);
+        Ok(get_block_cache(block_pos + self.start_block_id, Arc::clone(block_dev))?
+            .lock()
+            .read(0, |bitmap_block: &BitmapBlock| {
+                bitmap_block[bits_pos] & (1u64 << inner_pos) > 0
+            }))
+    }
+    // mark a given position as allocated
+    pub fn set(&self, block_dev: &Arc<dyn BlockDev>, pos: usize) -> EzResult<()> {
+        let (block_pos, bits_pos, inner_pos) = Self::pos_decomp(pos);
+        get_block_cache(block_pos + self.start_block_id, Arc::clone(block_dev))?
+            .lock()
+            .modify(0, |bitmap_block: &mut BitmapBlock| {
+                bitmap_block[bits_pos] |= 1u64 << inner_pos;
+            });
+        Ok(())
+    }
+    pub fn dealloc(&self, block_dev: &Arc<dyn BlockDev>, pos: usize) -> EzResult<()> {
         let (block_pos, bits_pos, inner_pos) = Self::pos_decomp(pos);
-        get_block_cache(block_pos + self.start_block_id, Arc::clone(block_dev))
+        get_block_cache(block_pos + self.start_block_id, Arc::clone(block_dev))?
             .lock()
             .modify(
                 0,
                 |bitmap_block: &mut BitmapBlock| {
-                    assert!((bitmap_block[bits_pos] & (1u64 << inner_pos)) > 0, "Block dealloc before alloc");
+                    // freeing a free position means the image is broken
+                    if bitmap_block[bits_pos] & (1u64 << inner_pos) == 0 {
+                        return Err(EzFsError::Corrupted);
+                    }
                     bitmap_block[bits_pos] -= 1u64 << inner_pos;
+                    Ok(())
                 }
-            );
+            )
     }
 }
 
-type BitmapBlock = [u64; 64];
\ No newline at end of file
+type BitmapBlock = [u64; 64];
@@ -2,25 +2,50 @@ use core::mem::size_of;
 
 use alloc::sync::Arc;
 
-use crate::{BLOCK_SIZE, BlockDev};
+use crate::{cache_man::{note_home_write, write_back_round}, BLOCK_SIZE, BlockDev, EzResult};
 
 pub struct BlockCache {
     cache: [u8; BLOCK_SIZE],
     block_id: usize,
     block_dev: Arc<dyn BlockDev>,
-    modified: bool
+    modified: bool,
+    // the write-back round it was modified in, while it is
+    dirtied: usize,
+    // bumped by every change, so a copy written back can tell if it is current
+    stamp: usize
 }
 impl BlockCache {
-    pub fn new(block_id: usize, block_dev: Arc<dyn BlockDev>) -> Self {
+    pub fn new(block_id: usize, block_dev: Arc<dyn BlockDev>) -> EzResult<Self> {
         let mut cache = [0u8; BLOCK_SIZE];
-        block_dev.read_block(block_id, &mut cache);
+        block_dev.read_block(block_id, &mut cache)?;
+        Ok(Self {
+            cache,
+            block_id,
+            block_dev,
+            modified: false,
+            dirtied: 0,
+            stamp: 0
+        })
+    }
+    // a block whose contents were already read from the device
+    pub fn with_data(block_id: usize, block_dev: Arc<dyn BlockDev>, data: &[u8]) -> Self {
+        let mut cache = [0u8; BLOCK_SIZE];
+        cache.copy_from_slice(data);
         Self {
             cache,
             block_id,
             block_dev,
-            modified: false
+            modified: false,
+            dirtied: 0,
+            stamp: 0
         }
     }
+    pub fn block_id(&self) -> usize {
+        self.block_id
+    }
+    pub fn block_dev(&self) -> Arc<dyn BlockDev> {
+        Arc::clone(&self.block_dev)
+    }
     fn addr_of_offset(&self, offset: usize) -> usize {
         &self.cache[offset] as *const _ as usize
     }
@@ -40,7 +65,11 @@ impl BlockCache {
         let ty_size = size_of::<T>();
         assert!(offset + ty_size <= BLOCK_SIZE);
         let addr = self.addr_of_offset(offset);
+        if !self.modified {
+            self.dirtied = write_back_round();
+        }
         self.modified = true;
+        self.stamp += 1;
         unsafe {
             &mut *(addr as *mut T)
         }
@@ -51,16 +80,40 @@ impl BlockCache {
     pub fn modify<T, V>(&mut self, offset: usize, f: impl FnOnce(&mut T) -> V) -> V {
         f(self.get_mut(offset))
     }
-    pub fn sync(&mut self) {
+    pub fn is_modified(&self) -> bool {
+        self.modified
+    }
+    pub fn dirtied(&self) -> usize {
+        self.dirtied
+    }
+    // a block that failed to write stays modified
+    pub fn sync(&mut self) -> EzResult<()> {
         if self.modified {
+            self.block_dev.write_block(self.block_id, &self.cache)?;
             self.modified = false;
-            self.block_dev.write_block(self.block_id, &self.cache);
+            note_home_write();
         }
+        Ok(())
+    }
+    // the contents and their stamp, to be written back without holding the lock
+    pub fn snapshot(&self) -> ([u8; BLOCK_SIZE], usize) {
+        (self.cache, self.stamp)
+    }
+    // a snapshot was written to the device, clean unless changed since
+    pub fn mark_synced(&mut self, stamp: usize) {
+        if self.stamp == stamp {
+            self.modified = false;
+        }
+    }
+    // forget the changes, the cache must not be used afterwards
+    pub fn discard(&mut self) {
+        self.modified = false;
     }
 }
 
 impl Drop for BlockCache {
     fn drop(&mut self) {
-        self.sync()
+        // nowhere to report a failure from here
+        let _ = self.sync();
     }
 }
\ No newline at end of file
@@ -1,6 +1,22 @@
 use core::any::Any;
 
+use crate::{BLOCK_SIZE, EzResult};
+
 pub trait BlockDev: Send + Sync + Any {
-    fn read_block(&self, block_id: usize, buf: &mut [u8]);
-    fn write_block(&self, block_id: usize, buf: &[u8]);
-}
\ No newline at end of file
+    fn read_block(&self, block_id: usize, buf: &mut [u8]) -> EzResult<()>;
+    fn write_block(&self, block_id: usize, buf: &[u8]) -> EzResult<()>;
+    // consecutive blocks from `start_block`, `buf` holds a whole number of them;
+    // devices that can move several blocks per request should override these
+    fn read_blocks(&self, start_block: usize, buf: &mut [u8]) -> EzResult<()> {
+        for (i, blk) in buf.chunks_mut(BLOCK_SIZE).enumerate() {
+            self.read_block(start_block + i, blk)?;
+        }
+        Ok(())
+    }
+    fn write_blocks(&self, start_block: usize, buf: &[u8]) -> EzResult<()> {
+        for (i, blk) in buf.chunks(BLOCK_SIZE).enumerate() {
+            self.write_block(start_block + i, blk)?;
+        }
+        Ok(())
+    }
+}
@@ -1,57 +1,485 @@
-use alloc::{collections::VecDeque, sync::Arc};
+use core::{ops::Range, sync::atomic::{AtomicUsize, Ordering}};
+
+use alloc::{sync::Arc, vec, vec::Vec};
+use hashbrown::HashMap;
 use lazy_static::lazy_static;
-use spin::Mutex;
 
-use crate::{block_cache::BlockCache, BlockDev};
+use crate::{block_cache::BlockCache, layout::JOURNAL_LOG_BLOCKS, sync::Mutex, BlockDev, EzFsError, EzResult, BLOCK_SIZE};
+
+pub const DEFAULT_CACHE_NUM: usize = 64;
+const NIL: usize = usize::MAX;
+
+pub(crate) fn dev_id(block_dev: &Arc<dyn BlockDev>) -> usize {
+    Arc::as_ptr(block_dev) as *const () as usize
+}
+
+// cached blocks written back to the device so far; a block read while this
+// moved may be older than a copy the cache has dropped in the meantime
+static HOME_WRITES: AtomicUsize = AtomicUsize::new(0);
+
+pub(crate) fn note_home_write() {
+    HOME_WRITES.fetch_add(1, Ordering::AcqRel);
+}
+
+// rounds of `periodic_write_back` run so far
+static ROUND: AtomicUsize = AtomicUsize::new(0);
+// rounds a dirty block outside a journal waits before it is written back
+pub const DIRTY_ROUNDS: usize = 2;
+
+pub(crate) fn write_back_round() -> usize {
+    ROUND.load(Ordering::Acquire)
+}
 
-const MAX_CACHE_NUM: usize = 16;
+lazy_static! {
+    // devices with a mounted journal, counting the mounts; their dirty blocks
+    // belong to the running transaction and go home only with its commit
+    static ref JOURNALED: Mutex<HashMap<usize, usize>> = Mutex::new(HashMap::new());
+}
+
+pub(crate) fn journal_on(block_dev: &Arc<dyn BlockDev>) {
+    *JOURNALED.lock().entry(dev_id(block_dev)).or_insert(0) += 1;
+}
 
+pub(crate) fn journal_off(block_dev: &Arc<dyn BlockDev>) {
+    let mut journaled = JOURNALED.lock();
+    let dev = dev_id(block_dev);
+    if let Some(count) = journaled.get_mut(&dev) {
+        *count -= 1;
+        if *count == 0 {
+            journaled.remove(&dev);
+        }
+    }
+}
+
+struct CacheSlot {
+    key: (usize, usize),
+    cache: Arc<Mutex<BlockCache>>,
+    // neighbours in the lru list
+    prev: usize,
+    next: usize,
+}
+
+// lru list threaded through a slab, indexed by (device, block id)
 pub struct BlockCacheMan {
-    queue: VecDeque<(usize, Arc<Mutex<BlockCache>>)>
+    capacity: usize,
+    index: HashMap<(usize, usize), usize>,
+    slots: Vec<Option<CacheSlot>>,
+    free: Vec<usize>,
+    // most recently used
+    head: usize,
+    // least recently used
+    tail: usize,
+}
+impl Default for BlockCacheMan {
+    fn default() -> Self { Self::new(DEFAULT_CACHE_NUM) }
 }
 impl BlockCacheMan {
-    pub fn new() -> Self {
-        Self { queue: VecDeque::new() }
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity: capacity.max(1),
+            index: HashMap::new(),
+            slots: Vec::new(),
+            free: Vec::new(),
+            head: NIL,
+            tail: NIL,
+        }
+    }
+    fn slot(&self, idx: usize) -> &CacheSlot {
+        self.slots[idx].as_ref().unwrap()
+    }
+    fn slot_mut(&mut self, idx: usize) -> &mut CacheSlot {
+        self.slots[idx].as_mut().unwrap()
+    }
+    fn unlink(&mut self, idx: usize) {
+        let (prev, next) = {
+            let slot = self.slot(idx);
+            (slot.prev, slot.next)
+        };
+        if prev == NIL { self.head = next; } else { self.slot_mut(prev).next = next; }
+        if next == NIL { self.tail = prev; } else { self.slot_mut(next).prev = prev; }
+    }
+    fn push_front(&mut self, idx: usize) {
+        let head = self.head;
+        let slot = self.slot_mut(idx);
+        slot.prev = NIL;
+        slot.next = head;
+        if head == NIL { self.tail = idx; } else { self.slot_mut(head).prev = idx; }
+        self.head = idx;
+    }
+    fn evictable(&self, idx: usize) -> bool {
+        let cache = &self.slot(idx).cache;
+        // dirty blocks stay until they are synced or committed
+        Arc::strong_count(cache) == 1 && !cache.lock().is_modified()
     }
-    pub fn get_block_cache(&mut self, block_id: usize, block_dev: Arc<dyn BlockDev>) -> Arc<Mutex<BlockCache>> {
-        if let Some((_, bc)) = self.queue
-            .iter()
-            .find(|(id, _)| *id == block_id)
-        {
-            return Arc::clone(bc);
+    // drop clean, unreferenced blocks from the cold end until at most `limit`
+    // are left; borrowed blocks and those of a running transaction let the
+    // cache run over capacity and are reclaimed once released or committed
+    fn shrink_to(&mut self, limit: usize) {
+        let mut idx = self.tail;
+        while self.index.len() > limit && idx != NIL {
+            let prev = self.slot(idx).prev;
+            if self.evictable(idx) {
+                self.remove(idx);
+            }
+            idx = prev;
+        }
+    }
+    // the oldest unreferenced dirty blocks outside a journal, as many as the
+    // cache is still over capacity; they are written back to make room
+    fn write_back_victims(&self) -> Vec<Arc<Mutex<BlockCache>>> {
+        let mut over = self.index.len().saturating_sub(self.capacity);
+        let mut victims = Vec::new();
+        if over == 0 {
+            return victims;
+        }
+        let journaled = JOURNALED.lock();
+        let mut idx = self.tail;
+        while over > 0 && idx != NIL {
+            let slot = self.slot(idx);
+            if Arc::strong_count(&slot.cache) == 1 && !journaled.contains_key(&slot.key.0) {
+                if slot.cache.lock().is_modified() {
+                    victims.push(Arc::clone(&slot.cache));
+                }
+                over -= 1;
+            }
+            idx = slot.prev;
+        }
+        victims
+    }
+    // dirty blocks outside a journal that sat out DIRTY_ROUNDS rounds by `round`
+    fn aged_dirty(&self, round: usize) -> Vec<Arc<Mutex<BlockCache>>> {
+        let journaled = JOURNALED.lock();
+        self.caches()
+            .filter(|slot| !journaled.contains_key(&slot.key.0))
+            .filter(|slot| {
+                let cache = slot.cache.lock();
+                cache.is_modified() && round - cache.dirtied() >= DIRTY_ROUNDS
+            })
+            .map(|slot| Arc::clone(&slot.cache))
+            .collect()
+    }
+    // the cache runs over capacity by the blocks of a running transaction and
+    // those borrowed, but no further than this
+    fn hard_limit(&self) -> usize {
+        2 * self.capacity + JOURNAL_LOG_BLOCKS
+    }
+    // no room for another block, even with what can be dropped gone
+    fn is_full(&mut self) -> bool {
+        self.shrink_to(self.capacity);
+        self.index.len() >= self.hard_limit()
+    }
+    // bring the cache back to capacity, writing back dirty blocks if need be
+    fn write_back(&mut self) -> EzResult<()> {
+        for cache in self.write_back_victims() {
+            sync_cache(&cache)?;
+        }
+        self.shrink_to(self.capacity);
+        Ok(())
+    }
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+    pub fn set_capacity(&mut self, capacity: usize) -> EzResult<()> {
+        self.capacity = capacity.max(1);
+        self.write_back()
+    }
+    pub fn len(&self) -> usize {
+        self.index.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.index.is_empty()
+    }
+    pub fn peek_block_cache(&self, block_id: usize, block_dev: &Arc<dyn BlockDev>) -> Option<Arc<Mutex<BlockCache>>> {
+        self.index
+            .get(&(dev_id(block_dev), block_id))
+            .map(|idx| Arc::clone(&self.slot(*idx).cache))
+    }
+    // a cached block, now the most recently used
+    fn touch(&mut self, block_id: usize, block_dev: &Arc<dyn BlockDev>) -> Option<Arc<Mutex<BlockCache>>> {
+        let idx = *self.index.get(&(dev_id(block_dev), block_id))?;
+        if self.head != idx {
+            self.unlink(idx);
+            self.push_front(idx);
+        }
+        Some(Arc::clone(&self.slot(idx).cache))
+    }
+    // cache a block read from the device, unless a copy got there first
+    fn insert_loaded(&mut self, cache: BlockCache, block_dev: &Arc<dyn BlockDev>) -> Arc<Mutex<BlockCache>> {
+        if let Some(cached) = self.touch(cache.block_id(), block_dev) {
+            return cached;
+        }
+        self.shrink_to(self.capacity - 1);
+        let key = (dev_id(block_dev), cache.block_id());
+        let cache = Arc::new(Mutex::new(cache));
+        self.insert(key, Arc::clone(&cache));
+        cache
+    }
+    pub fn get_block_cache(&mut self, block_id: usize, block_dev: Arc<dyn BlockDev>) -> EzResult<Arc<Mutex<BlockCache>>> {
+        if let Some(cache) = self.touch(block_id, &block_dev) {
+            return Ok(cache);
+        }
+        if self.is_full() {
+            self.write_back()?;
+            if self.is_full() {
+                return Err(EzFsError::Busy);
+            }
+        }
+        let cache = BlockCache::new(block_id, Arc::clone(&block_dev))?;
+        let cache = self.insert_loaded(cache, &block_dev);
+        self.write_back()?;
+        Ok(cache)
+    }
+    // runs of the blocks in `start_block..start_block + count` not cached yet,
+    // never more than the cache holds
+    fn missing_runs(&self, start_block: usize, count: usize, block_dev: &Arc<dyn BlockDev>) -> Vec<Range<usize>> {
+        let dev = dev_id(block_dev);
+        let end = start_block + count.min(self.capacity);
+        let mut runs = Vec::new();
+        let mut block_id = start_block;
+        while block_id < end {
+            if self.index.contains_key(&(dev, block_id)) {
+                block_id += 1;
+                continue;
+            }
+            let run_start = block_id;
+            while block_id < end && !self.index.contains_key(&(dev, block_id)) {
+                block_id += 1;
+            }
+            runs.push(run_start..block_id);
         }
-        if self.queue.len() >= MAX_CACHE_NUM {
-            if let Some((idx, _)) = self.queue
-                .iter()
-                .enumerate()
-                .find(|(_, (_, bc))| Arc::strong_count(bc) == 1)
-            {
-                self.queue.drain(idx..=idx);
-            } else {
-                panic!("Run out of BlockCache! ");
+        runs
+    }
+    // load the blocks of `start_block..start_block + count` that are not cached
+    // yet, each run of missing blocks with a single device request
+    pub fn read_ahead(&mut self, start_block: usize, count: usize, block_dev: &Arc<dyn BlockDev>) -> EzResult<()> {
+        for run in self.missing_runs(start_block, count, block_dev) {
+            if self.is_full() {
+                break;
+            }
+            for cache in read_run(run, block_dev)? {
+                self.insert_loaded(cache, block_dev);
             }
         }
-        let bc = Arc::new(Mutex::new(
-            BlockCache::new(block_id, Arc::clone(&block_dev))
-        ));
-        self.queue.push_back((block_id, Arc::clone(&bc)));
-        bc
+        self.write_back()
+    }
+    fn insert(&mut self, key: (usize, usize), cache: Arc<Mutex<BlockCache>>) {
+        let slot = CacheSlot { key, cache, prev: NIL, next: NIL };
+        let idx = match self.free.pop() {
+            Some(idx) => {
+                self.slots[idx] = Some(slot);
+                idx
+            }
+            None => {
+                self.slots.push(Some(slot));
+                self.slots.len() - 1
+            }
+        };
+        self.index.insert(key, idx);
+        self.push_front(idx);
+    }
+    fn remove(&mut self, idx: usize) {
+        self.unlink(idx);
+        let slot = self.slots[idx].take().unwrap();
+        self.index.remove(&slot.key);
+        self.free.push(idx);
     }
+    fn caches(&self) -> impl Iterator<Item = &CacheSlot> {
+        self.slots.iter().flatten()
+    }
+}
+
+fn read_run(run: Range<usize>, block_dev: &Arc<dyn BlockDev>) -> EzResult<Vec<BlockCache>> {
+    let mut buf = vec![0u8; run.len() * BLOCK_SIZE];
+    block_dev.read_blocks(run.start, &mut buf)?;
+    Ok(run.zip(buf.chunks(BLOCK_SIZE))
+        .map(|(block_id, data)| BlockCache::with_data(block_id, Arc::clone(block_dev), data))
+        .collect())
 }
 
+// the device is never accessed with BLOCK_CACHE_MAN held, so one thread
+// waiting for a block does not hold up the hits of everyone else
 lazy_static! {
     pub static ref BLOCK_CACHE_MAN: Mutex<BlockCacheMan> = Mutex::new(
-        BlockCacheMan::new()
+        BlockCacheMan::default()
     );
 }
 
-pub fn get_block_cache(block_id: usize, block_dev: Arc<dyn BlockDev>) -> Arc<Mutex<BlockCache>> {
-    BLOCK_CACHE_MAN.lock().get_block_cache(block_id, block_dev)
+pub fn get_block_cache(block_id: usize, block_dev: Arc<dyn BlockDev>) -> EzResult<Arc<Mutex<BlockCache>>> {
+    loop {
+        let writes = HOME_WRITES.load(Ordering::Acquire);
+        if let Some(cache) = BLOCK_CACHE_MAN.lock().touch(block_id, &block_dev) {
+            return Ok(cache);
+        }
+        // make room by writing back what can go, else the caller backs off
+        if BLOCK_CACHE_MAN.lock().is_full() {
+            write_back_block_caches()?;
+            if BLOCK_CACHE_MAN.lock().is_full() {
+                return Err(EzFsError::Busy);
+            }
+        }
+        let cache = BlockCache::new(block_id, Arc::clone(&block_dev))?;
+        let mut cache_man = BLOCK_CACHE_MAN.lock();
+        // read again if the block may have been written and dropped meanwhile
+        if HOME_WRITES.load(Ordering::Acquire) == writes || cache_man.touch(block_id, &block_dev).is_some() {
+            let cache = cache_man.insert_loaded(cache, &block_dev);
+            drop(cache_man);
+            write_back_block_caches()?;
+            return Ok(cache);
+        }
+    }
+}
+
+lazy_static! {
+    // blocks outside a journal are written back by one thread at a time, so
+    // an older copy of a block never lands on the device after a newer one
+    static ref WRITE_BACK: Mutex<()> = Mutex::new(());
+}
+
+// write a modified block back, holding its lock only to copy it
+fn sync_cache(cache: &Mutex<BlockCache>) -> EzResult<()> {
+    let (data, stamp, block_id, block_dev) = {
+        let cache = cache.lock();
+        if !cache.is_modified() {
+            return Ok(());
+        }
+        let (data, stamp) = cache.snapshot();
+        (data, stamp, cache.block_id(), cache.block_dev())
+    };
+    block_dev.write_block(block_id, &data)?;
+    cache.lock().mark_synced(stamp);
+    note_home_write();
+    Ok(())
 }
 
-pub fn sync_block_cache() {
-    let cache_man = BLOCK_CACHE_MAN.lock();
-    for (_, cache) in cache_man.queue.iter() {
-        cache.lock().sync();
+// write back the oldest dirty blocks outside a journal while the cache is
+// over capacity; they stay cached while written, so no one reads a stale copy
+fn write_back_block_caches() -> EzResult<()> {
+    let victims = BLOCK_CACHE_MAN.lock().write_back_victims();
+    if victims.is_empty() {
+        return Ok(());
     }
-}
\ No newline at end of file
+    let _write_back = WRITE_BACK.lock();
+    for cache in victims {
+        sync_cache(&cache)?;
+    }
+    let mut cache_man = BLOCK_CACHE_MAN.lock();
+    let capacity = cache_man.capacity;
+    cache_man.shrink_to(capacity);
+    Ok(())
+}
+
+// a round of write-back, for a timer or a sync to run now and then: blocks
+// outside a journal dirty for DIRTY_ROUNDS rounds go to the device, those of
+// a journal go with their commit
+pub fn periodic_write_back() -> EzResult<()> {
+    let round = ROUND.fetch_add(1, Ordering::AcqRel) + 1;
+    let aged = BLOCK_CACHE_MAN.lock().aged_dirty(round);
+    if !aged.is_empty() {
+        let _write_back = WRITE_BACK.lock();
+        for cache in aged {
+            sync_cache(&cache)?;
+        }
+    }
+    write_back_block_caches()
+}
+
+// the cached copy of a block, without loading it on a miss
+pub fn peek_block_cache(block_id: usize, block_dev: &Arc<dyn BlockDev>) -> Option<Arc<Mutex<BlockCache>>> {
+    BLOCK_CACHE_MAN.lock().peek_block_cache(block_id, block_dev)
+}
+
+pub fn read_ahead_block_caches(start_block: usize, count: usize, block_dev: &Arc<dyn BlockDev>) -> EzResult<()> {
+    let writes = HOME_WRITES.load(Ordering::Acquire);
+    let runs = BLOCK_CACHE_MAN.lock().missing_runs(start_block, count, block_dev);
+    for run in runs {
+        let caches = read_run(run, block_dev)?;
+        let mut cache_man = BLOCK_CACHE_MAN.lock();
+        // only a hint: what may be stale is left to be read on demand,
+        // and nothing is read ahead into a full cache
+        if HOME_WRITES.load(Ordering::Acquire) != writes || cache_man.is_full() {
+            break;
+        }
+        for cache in caches {
+            cache_man.insert_loaded(cache, block_dev);
+        }
+    }
+    write_back_block_caches()
+}
+
+pub fn set_cache_capacity(capacity: usize) -> EzResult<()> {
+    let capacity = capacity.max(1);
+    let mut cache_man = BLOCK_CACHE_MAN.lock();
+    cache_man.capacity = capacity;
+    cache_man.shrink_to(capacity);
+    drop(cache_man);
+    write_back_block_caches()
+}
+
+// modified blocks of a device, ordered by block id
+pub fn dirty_block_caches(block_dev: &Arc<dyn BlockDev>) -> Vec<(usize, Arc<Mutex<BlockCache>>)> {
+    let dev = dev_id(block_dev);
+    let mut v: Vec<(usize, Arc<Mutex<BlockCache>>)> = BLOCK_CACHE_MAN.lock()
+        .caches()
+        .filter(|slot| slot.key.0 == dev)
+        .map(|slot| (slot.key.1, Arc::clone(&slot.cache)))
+        .collect();
+    v.retain(|(_, bc)| bc.lock().is_modified());
+    v.sort_by_key(|(id, _)| *id);
+    v
+}
+
+// write cached blocks, sorted by id, back to the device, merging runs of
+// consecutive ids into one request
+pub fn write_block_caches(blocks: &[(usize, Arc<Mutex<BlockCache>>)], block_dev: &Arc<dyn BlockDev>) -> EzResult<()> {
+    let mut start = 0;
+    while start < blocks.len() {
+        let mut end = start + 1;
+        while end < blocks.len() && blocks[end].0 == blocks[end - 1].0 + 1 {
+            end += 1;
+        }
+        let run = &blocks[start..end];
+        let mut buf = vec![0u8; run.len() * BLOCK_SIZE];
+        let mut stamps = Vec::with_capacity(run.len());
+        for ((_, bc), dst) in run.iter().zip(buf.chunks_mut(BLOCK_SIZE)) {
+            let (data, stamp) = bc.lock().snapshot();
+            dst.copy_from_slice(&data);
+            stamps.push(stamp);
+        }
+        block_dev.write_blocks(run[0].0, &buf)?;
+        for ((_, bc), stamp) in run.iter().zip(stamps) {
+            bc.lock().mark_synced(stamp);
+        }
+        note_home_write();
+        start = end;
+    }
+    Ok(())
+}
+
+// drop the modified blocks of a device, so they are read again from it
+pub fn discard_dirty_caches(block_dev: &Arc<dyn BlockDev>) {
+    let dirty = dirty_block_caches(block_dev);
+    for (_, bc) in dirty.iter() {
+        bc.lock().discard();
+    }
+    let dev = dev_id(block_dev);
+    let mut cache_man = BLOCK_CACHE_MAN.lock();
+    for (block_id, bc) in dirty {
+        if let Some(&idx) = cache_man.index.get(&(dev, block_id)) {
+            if Arc::ptr_eq(&cache_man.slot(idx).cache, &bc) {
+                cache_man.remove(idx);
+            }
+        }
+    }
+}
+
+pub fn sync_block_cache() -> EzResult<()> {
+    let caches: Vec<Arc<Mutex<BlockCache>>> = BLOCK_CACHE_MAN.lock()
+        .caches()
+        .map(|slot| Arc::clone(&slot.cache))
+        .collect();
+    let _write_back = WRITE_BACK.lock();
+    for cache in caches {
+        sync_cache(&cache)?;
+    }
+    Ok(())
+}
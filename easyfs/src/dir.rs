@@ -0,0 +1,452 @@
+use alloc::{sync::Arc, vec::Vec};
+use core::iter::once;
+
+use crate::{cache_man::get_block_cache, layout::DiskInode, BlockDev, EzFsError, EzResult, BLOCK_SIZE};
+
+// longest name of an entry, in bytes
+pub const NAME_MAX: usize = 255;
+
+// a directory is a run of blocks, each tiled by records of
+//   inode: u32, rec_len: u16, name_len: u8, reserved: u8, name
+// padded to 4 bytes; a record without a name is free space
+const HEAD_SIZE: usize = 8;
+// "." and ".." open the first block. Once a directory outgrows it, ".." runs
+// to the end of the block and the space after it holds the root of a hash
+// index, whose entries lead to leaf blocks or to a level of index blocks
+// below. Index blocks start with a free record covering them, so a walk over
+// the records sees only the entries.
+const DOTDOT_OFFSET: usize = 12;
+const ROOT_INDEX: usize = 24;
+const NODE_INDEX: usize = HEAD_SIZE;
+// count: u16, levels: u8, reserved: u8, then (hash, block) pairs sorted by
+// hash, the block holding the names hashing from there to the next pair
+const INDEX_HEAD: usize = 4;
+const ROOT_LIMIT: usize = (BLOCK_SIZE - ROOT_INDEX - INDEX_HEAD) / 8;
+const NODE_LIMIT: usize = (BLOCK_SIZE - NODE_INDEX - INDEX_HEAD) / 8;
+// leaf splits tried for one insertion before giving up
+const SPLIT_TRIES: usize = 3;
+
+type DataBlock = [u8; BLOCK_SIZE];
+
+fn get_u16(blk: &DataBlock, offset: usize) -> u16 {
+    u16::from_le_bytes([blk[offset], blk[offset + 1]])
+}
+fn get_u32(blk: &DataBlock, offset: usize) -> u32 {
+    u32::from_le_bytes(blk[offset..offset + 4].try_into().unwrap())
+}
+fn put_u16(blk: &mut DataBlock, offset: usize, v: u16) {
+    blk[offset..offset + 2].copy_from_slice(&v.to_le_bytes());
+}
+fn put_u32(blk: &mut DataBlock, offset: usize, v: u32) {
+    blk[offset..offset + 4].copy_from_slice(&v.to_le_bytes());
+}
+
+// bytes a record for a name of `name_len` takes at least
+fn rec_size(name_len: usize) -> usize {
+    (HEAD_SIZE + name_len + 3) & !3
+}
+
+fn put_record(blk: &mut DataBlock, offset: usize, inode: u32, rec_len: usize, name: &[u8]) {
+    put_u32(blk, offset, inode);
+    put_u16(blk, offset + 4, rec_len as u16);
+    blk[offset + 6] = name.len() as u8;
+    blk[offset + 7] = 0;
+    blk[offset + HEAD_SIZE..offset + HEAD_SIZE + name.len()].copy_from_slice(name);
+}
+
+// FNV-1a
+pub fn name_hash(name: &[u8]) -> u32 {
+    name.iter().fold(0x811c9dc5u32, |h, b| (h ^ *b as u32).wrapping_mul(0x01000193))
+}
+
+pub struct Record<'a> {
+    // within the block
+    pub offset: usize,
+    pub inode: u32,
+    pub rec_len: usize,
+    pub name: &'a [u8]
+}
+
+impl Record<'_> {
+    pub fn is_free(&self) -> bool {
+        self.name.is_empty()
+    }
+}
+
+// the records of a directory block in order; a broken one ends the walk
+pub struct Records<'a> {
+    blk: &'a DataBlock,
+    offset: usize
+}
+
+impl<'a> Records<'a> {
+    pub fn new(blk: &'a DataBlock) -> Self {
+        Self { blk, offset: 0 }
+    }
+}
+
+impl<'a> Iterator for Records<'a> {
+    type Item = EzResult<Record<'a>>;
+    fn next(&mut self) -> Option<Self::Item> {
+        let offset = self.offset;
+        if offset >= BLOCK_SIZE {
+            return None;
+        }
+        // the head has to be there before it is read
+        if offset % 4 != 0 || offset + HEAD_SIZE > BLOCK_SIZE {
+            self.offset = BLOCK_SIZE;
+            return Some(Err(EzFsError::Corrupted));
+        }
+        let rec_len = get_u16(self.blk, offset + 4) as usize;
+        let name_len = self.blk[offset + 6] as usize;
+        if rec_len % 4 != 0 || rec_len < rec_size(name_len) || offset + rec_len > BLOCK_SIZE {
+            self.offset = BLOCK_SIZE;
+            return Some(Err(EzFsError::Corrupted));
+        }
+        self.offset += rec_len;
+        Some(Ok(Record {
+            offset,
+            inode: get_u32(self.blk, offset),
+            rec_len,
+            name: &self.blk[offset + HEAD_SIZE..offset + HEAD_SIZE + name_len]
+        }))
+    }
+}
+
+// an entry found by `next_entry`, at byte `offset` of the directory
+pub struct DirRecord {
+    pub offset: usize,
+    // where the record after it starts
+    pub next: usize,
+    pub inode: u32,
+    pub name: Vec<u8>
+}
+
+fn read_block<V>(dir: &DiskInode, inner_id: usize, block_dev: &Arc<dyn BlockDev>, f: impl FnOnce(&DataBlock) -> V) -> EzResult<V> {
+    match dir.get_block_id(inner_id as u32, block_dev)? {
+        0 => Err(EzFsError::Corrupted),
+        block_id => Ok(get_block_cache(block_id as usize, Arc::clone(block_dev))?.lock().read(0, f))
+    }
+}
+fn modify_block<V>(dir: &DiskInode, inner_id: usize, block_dev: &Arc<dyn BlockDev>, f: impl FnOnce(&mut DataBlock) -> V) -> EzResult<V> {
+    match dir.get_block_id(inner_id as u32, block_dev)? {
+        0 => Err(EzFsError::Corrupted),
+        block_id => Ok(get_block_cache(block_id as usize, Arc::clone(block_dev))?.lock().modify(0, f))
+    }
+}
+
+// lay the entries out from the start of a block, the last one taking the rest
+fn fill_leaf(blk: &mut DataBlock, entries: &[(u32, &[u8])]) {
+    if entries.is_empty() {
+        put_record(blk, 0, 0, BLOCK_SIZE, b"");
+        return;
+    }
+    let mut offset = 0;
+    for (i, (inode, name)) in entries.iter().enumerate() {
+        let rec_len = if i + 1 == entries.len() { BLOCK_SIZE - offset } else { rec_size(name.len()) };
+        put_record(blk, offset, *inode, rec_len, name);
+        offset += rec_len;
+    }
+}
+
+// an empty directory of one block, `block_id`
+pub fn init(dir: &mut DiskInode, inode_id: u32, parent_id: u32, block_id: u32, block_dev: &Arc<dyn BlockDev>) -> EzResult<()> {
+    assert!(dir.is_dir() && dir.size == 0);
+    dir.map_block(0, &mut || Ok(block_id), block_dev)?;
+    dir.size = BLOCK_SIZE as u32;
+    modify_block(dir, 0, block_dev, |blk: &mut DataBlock| {
+        fill_leaf(blk, &[(inode_id, b"."), (parent_id, b"..")]);
+    })
+}
+
+fn is_indexed(dir: &DiskInode) -> bool {
+    dir.size as usize > BLOCK_SIZE
+}
+
+// the levels below the root and the (hash, block) pairs of an index block
+fn read_index(dir: &DiskInode, inner_id: usize, block_dev: &Arc<dyn BlockDev>) -> EzResult<(u8, Vec<(u32, u32)>)> {
+    let (base, limit) = if inner_id == 0 { (ROOT_INDEX, ROOT_LIMIT) } else { (NODE_INDEX, NODE_LIMIT) };
+    read_block(dir, inner_id, block_dev, |blk: &DataBlock| {
+        let count = get_u16(blk, base) as usize;
+        let levels = blk[base + 2];
+        if count == 0 || count > limit || levels > 1 {
+            return Err(EzFsError::Corrupted);
+        }
+        let entries = (0..count)
+            .map(|i| base + INDEX_HEAD + i * 8)
+            .map(|pos| (get_u32(blk, pos), get_u32(blk, pos + 4)))
+            .collect();
+        Ok((levels, entries))
+    })?
+}
+fn write_index(dir: &DiskInode, inner_id: usize, levels: u8, entries: &[(u32, u32)], block_dev: &Arc<dyn BlockDev>) -> EzResult<()> {
+    let base = if inner_id == 0 { ROOT_INDEX } else { NODE_INDEX };
+    modify_block(dir, inner_id, block_dev, |blk: &mut DataBlock| {
+        if inner_id != 0 {
+            put_record(blk, 0, 0, BLOCK_SIZE, b"");
+        }
+        put_u16(blk, base, entries.len() as u16);
+        blk[base + 2] = levels;
+        blk[base + 3] = 0;
+        for (i, (hash, block)) in entries.iter().enumerate() {
+            put_u32(blk, base + INDEX_HEAD + i * 8, *hash);
+            put_u32(blk, base + INDEX_HEAD + i * 8 + 4, *block);
+        }
+    })
+}
+
+// the index blocks from the root down, with the pair taken in each, and the
+// leaf that holds the names hashing to `hash`
+fn index_path(dir: &DiskInode, hash: u32, block_dev: &Arc<dyn BlockDev>) -> EzResult<(Vec<(usize, usize)>, usize)> {
+    let levels = read_index(dir, 0, block_dev)?.0;
+    let mut path = Vec::new();
+    let mut node = 0;
+    for _ in 0..=levels {
+        let (_, entries) = read_index(dir, node, block_dev)?;
+        let pos = entries.partition_point(|(start, _)| *start <= hash).max(1) - 1;
+        path.push((node, pos));
+        node = entries[pos].1 as usize;
+        if node == 0 || node >= dir.data_blocks() as usize {
+            return Err(EzFsError::Corrupted);
+        }
+    }
+    Ok((path, node))
+}
+
+// byte offset and inode of the entry `name`
+pub fn lookup(dir: &DiskInode, name: &str, block_dev: &Arc<dyn BlockDev>) -> EzResult<Option<(usize, u32)>> {
+    let name = name.as_bytes();
+    let leaf = if is_indexed(dir) && name != b"." && name != b".." {
+        index_path(dir, name_hash(name), block_dev)?.1
+    } else {
+        0
+    };
+    read_block(dir, leaf, block_dev, |blk: &DataBlock| {
+        for rec in Records::new(blk) {
+            let rec = rec?;
+            if !rec.is_free() && rec.name == name {
+                return Ok(Some((leaf * BLOCK_SIZE + rec.offset, rec.inode)));
+            }
+        }
+        Ok(None)
+    })?
+}
+
+// the first entry starting at or after byte `pos`
+pub fn next_entry(dir: &DiskInode, pos: usize, block_dev: &Arc<dyn BlockDev>) -> EzResult<Option<DirRecord>> {
+    let mut inner_id = pos / BLOCK_SIZE;
+    while inner_id < dir.data_blocks() as usize {
+        let found = read_block(dir, inner_id, block_dev, |blk: &DataBlock| {
+            for rec in Records::new(blk) {
+                let rec = rec?;
+                if !rec.is_free() && inner_id * BLOCK_SIZE + rec.offset >= pos {
+                    return Ok(Some(DirRecord {
+                        offset: inner_id * BLOCK_SIZE + rec.offset,
+                        next: inner_id * BLOCK_SIZE + rec.offset + rec.rec_len,
+                        inode: rec.inode,
+                        name: rec.name.to_vec()
+                    }));
+                }
+            }
+            Ok(None)
+        })??;
+        if found.is_some() {
+            return Ok(found);
+        }
+        inner_id += 1;
+    }
+    Ok(None)
+}
+
+// put the entry into free space of a block, false if there is not enough
+fn try_insert(blk: &mut DataBlock, name: &[u8], inode_id: u32) -> EzResult<bool> {
+    let need = rec_size(name.len());
+    let mut found = None;
+    for rec in Records::new(blk) {
+        let rec = rec?;
+        let used = if rec.is_free() { 0 } else { rec_size(rec.name.len()) };
+        if rec.rec_len - used >= need {
+            found = Some((rec.offset, used, rec.rec_len));
+            break;
+        }
+    }
+    let Some((offset, used, rec_len)) = found else {
+        return Ok(false);
+    };
+    if used > 0 {
+        put_u16(blk, offset + 4, used as u16);
+    }
+    put_record(blk, offset + used, inode_id, rec_len - used, name);
+    Ok(true)
+}
+
+// add a block at the end of the directory
+fn append_block(dir: &mut DiskInode, alloc: &mut dyn FnMut() -> EzResult<u32>, block_dev: &Arc<dyn BlockDev>) -> EzResult<usize> {
+    let inner_id = dir.data_blocks() as usize;
+    if (inner_id + 1) * BLOCK_SIZE > DiskInode::max_size() as usize {
+        return Err(EzFsError::NoSpace);
+    }
+    dir.map_block(inner_id as u32, alloc, block_dev)?;
+    dir.size += BLOCK_SIZE as u32;
+    modify_block(dir, inner_id, block_dev, |blk: &mut DataBlock| fill_leaf(blk, &[]))?;
+    Ok(inner_id)
+}
+
+// move the entries of a full first block to a leaf under a new index
+fn make_index(dir: &mut DiskInode, alloc: &mut dyn FnMut() -> EzResult<u32>, block_dev: &Arc<dyn BlockDev>) -> EzResult<()> {
+    let leaf = append_block(dir, alloc, block_dev)?;
+    let entries = read_block(dir, 0, block_dev, |blk: &DataBlock| {
+        Records::new(blk)
+            .skip(2)
+            .filter(|rec| !matches!(rec, Ok(rec) if rec.is_free()))
+            .map(|rec| rec.map(|rec| (rec.inode, rec.name.to_vec())))
+            .collect::<EzResult<Vec<_>>>()
+    })??;
+    modify_block(dir, leaf, block_dev, |blk: &mut DataBlock| {
+        fill_leaf(blk, &entries.iter().map(|(inode, name)| (*inode, name.as_slice())).collect::<Vec<_>>());
+    })?;
+    modify_block(dir, 0, block_dev, |blk: &mut DataBlock| {
+        put_u16(blk, DOTDOT_OFFSET + 4, (BLOCK_SIZE - DOTDOT_OFFSET) as u16);
+    })?;
+    write_index(dir, 0, 0, &[(0, leaf as u32)], block_dev)
+}
+
+// add (hash, block) after the pair `path` took in the lowest index block
+fn index_insert(
+    dir: &mut DiskInode,
+    path: &[(usize, usize)],
+    hash: u32,
+    block: usize,
+    alloc: &mut dyn FnMut() -> EzResult<u32>,
+    block_dev: &Arc<dyn BlockDev>
+) -> EzResult<()> {
+    let (node, pos) = *path.last().unwrap();
+    let (levels, mut entries) = read_index(dir, node, block_dev)?;
+    let limit = if node == 0 { ROOT_LIMIT } else { NODE_LIMIT };
+    if entries.len() < limit {
+        entries.insert(pos + 1, (hash, block as u32));
+        return write_index(dir, node, levels, &entries, block_dev);
+    }
+    if node == 0 {
+        // a full root hands its pairs down to a new level
+        let child = append_block(dir, alloc, block_dev)?;
+        write_index(dir, child, 0, &entries, block_dev)?;
+        write_index(dir, 0, 1, &[(0, child as u32)], block_dev)?;
+        return index_insert(dir, &[(0, 0), (child, pos)], hash, block, alloc, block_dev);
+    }
+    // a full block below the root splits in two
+    let (root_levels, mut root_entries) = read_index(dir, 0, block_dev)?;
+    if root_entries.len() >= ROOT_LIMIT {
+        return Err(EzFsError::NoSpace);
+    }
+    entries.insert(pos + 1, (hash, block as u32));
+    let upper = entries.split_off(entries.len() / 2);
+    let sibling = append_block(dir, alloc, block_dev)?;
+    write_index(dir, node, 0, &entries, block_dev)?;
+    write_index(dir, sibling, 0, &upper, block_dev)?;
+    root_entries.insert(path[0].1 + 1, (upper[0].0, sibling as u32));
+    write_index(dir, 0, root_levels, &root_entries, block_dev)
+}
+
+// split a leaf too full for a name hashing to `hash` at the hash that
+// balances the two halves best, counting the new name in; equal hashes stay
+// together, so every name is in the leaf its hash leads to
+fn split_leaf(
+    dir: &mut DiskInode,
+    path: &[(usize, usize)],
+    leaf: usize,
+    hash: u32,
+    name_len: usize,
+    alloc: &mut dyn FnMut() -> EzResult<u32>,
+    block_dev: &Arc<dyn BlockDev>
+) -> EzResult<()> {
+    let (node, pos) = *path.last().unwrap();
+    let start = read_index(dir, node, block_dev)?.1[pos].0;
+    let mut entries = read_block(dir, leaf, block_dev, |blk: &DataBlock| {
+        Records::new(blk)
+            .filter(|rec| !matches!(rec, Ok(rec) if rec.is_free()))
+            .map(|rec| rec.map(|rec| (name_hash(rec.name), rec.inode, rec.name.to_vec())))
+            .collect::<EzResult<Vec<_>>>()
+    })??;
+    entries.sort_unstable_by_key(|(hash, _, _)| *hash);
+    let sizes: Vec<(u32, usize)> = entries.iter()
+        .map(|(hash, _, name)| (*hash, rec_size(name.len())))
+        .chain(once((hash, rec_size(name_len))))
+        .collect();
+    let total: usize = sizes.iter().map(|(_, size)| size).sum();
+    let split = sizes.iter()
+        .map(|(hash, _)| *hash)
+        .filter(|split| *split > start)
+        .min_by_key(|split| {
+            let lower: usize = sizes.iter().filter(|(hash, _)| hash < split).map(|(_, size)| size).sum();
+            lower.max(total - lower)
+        })
+        .ok_or(EzFsError::NoSpace)?;
+    let new_leaf = append_block(dir, alloc, block_dev)?;
+    let at = entries.partition_point(|(hash, _, _)| *hash < split);
+    for (inner_id, half) in [(leaf, &entries[..at]), (new_leaf, &entries[at..])] {
+        let half: Vec<(u32, &[u8])> = half.iter().map(|(_, inode, name)| (*inode, name.as_slice())).collect();
+        modify_block(dir, inner_id, block_dev, |blk: &mut DataBlock| fill_leaf(blk, &half))?;
+    }
+    index_insert(dir, path, split, new_leaf, alloc, block_dev)
+}
+
+// add the entry `name`, which must not exist yet; the blocks the directory
+// grows by come from `alloc`
+pub fn insert(
+    dir: &mut DiskInode,
+    name: &str,
+    inode_id: u32,
+    alloc: &mut dyn FnMut() -> EzResult<u32>,
+    block_dev: &Arc<dyn BlockDev>
+) -> EzResult<()> {
+    let name = name.as_bytes();
+    if !is_indexed(dir) {
+        if modify_block(dir, 0, block_dev, |blk: &mut DataBlock| try_insert(blk, name, inode_id))?? {
+            return Ok(());
+        }
+        make_index(dir, alloc, block_dev)?;
+    }
+    let hash = name_hash(name);
+    for _ in 0..=SPLIT_TRIES {
+        let (path, leaf) = index_path(dir, hash, block_dev)?;
+        if modify_block(dir, leaf, block_dev, |blk: &mut DataBlock| try_insert(blk, name, inode_id))?? {
+            return Ok(());
+        }
+        split_leaf(dir, &path, leaf, hash, name.len(), alloc, block_dev)?;
+    }
+    Err(EzFsError::NoSpace)
+}
+
+// drop the entry at byte `offset`, its space going to the record before it
+pub fn remove(dir: &DiskInode, offset: usize, block_dev: &Arc<dyn BlockDev>) -> EzResult<()> {
+    let offset_in_blk = offset % BLOCK_SIZE;
+    modify_block(dir, offset / BLOCK_SIZE, block_dev, |blk: &mut DataBlock| {
+        let mut prev = None;
+        let mut found = None;
+        for rec in Records::new(blk) {
+            let rec = rec?;
+            if rec.offset == offset_in_blk {
+                found = Some(rec.rec_len);
+                break;
+            }
+            prev = Some(rec.offset);
+        }
+        let rec_len = found.ok_or(EzFsError::Corrupted)?;
+        match prev {
+            Some(prev) => {
+                let rec_len = get_u16(blk, prev + 4) as usize + rec_len;
+                put_u16(blk, prev + 4, rec_len as u16);
+            }
+            None => blk[offset_in_blk + 6] = 0
+        }
+        Ok(())
+    })?
+}
+
+// point the entry at byte `offset` to another inode
+pub fn set_inode(dir: &DiskInode, offset: usize, inode_id: u32, block_dev: &Arc<dyn BlockDev>) -> EzResult<()> {
+    modify_block(dir, offset / BLOCK_SIZE, block_dev, |blk: &mut DataBlock| {
+        put_u32(blk, offset % BLOCK_SIZE, inode_id)
+    })
+}
@@ -1,130 +1,410 @@
 use core::mem::size_of;
 
-use alloc::sync::Arc;
-use spin::Mutex;
-
-use crate::{BlockDev, bitmap::{Bitmap, BLOCK_BITS}, BLOCK_SIZE, layout::{DiskInode, SuperBlock, DiskInodeType}, cache_man::{get_block_cache, sync_block_cache}, vfs::VirtInode};
+use alloc::{sync::Arc, vec, vec::Vec};
+use crate::{sync::Mutex, BlockDev, EzFsError, EzResult, bitmap::{Bitmap, BLOCK_BITS}, BLOCK_SIZE, layout::{DiskInode, SuperBlock, DiskInodeType, JournalHeader, JOURNAL_LOG_BLOCKS}, dir::{self, NAME_MAX}, cache_man::{dirty_block_caches, discard_dirty_caches, get_block_cache, journal_off, journal_on, sync_block_cache, write_block_caches}, vfs::VirtInode};
 
 pub struct EzFileSys {
     pub block_dev: Arc<dyn BlockDev>,
     pub inode_bitmap: Bitmap,
     pub data_bitmap: Bitmap,
+    journal_start: u32,
     inode_start: u32,
-    data_start: u32
+    data_start: u32,
+    data_blocks: u32,
+    // kept in step with the bitmaps, and put back with them on abort
+    free_inodes: u32,
+    free_blocks: u32,
+    committed_free: (u32, u32),
+    // held by the running transaction
+    journal: Arc<Mutex<Journal>>,
+    clock: fn() -> u64
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default, Debug, PartialEq)]
+pub struct StatFs {
+    pub block_size: u64,
+    // blocks of the data area
+    pub blocks: u64,
+    pub free_blocks: u64,
+    pub inodes: u64,
+    pub free_inodes: u64,
+    pub name_max: u64
 }
 
+fn no_clock() -> u64 { 0 }
+
+// blocks zeroed per request when formatting
+const MKFS_CLEAR_BLOCKS: usize = 64;
+
 type DataBlock = [u8; BLOCK_SIZE];
+impl Drop for EzFileSys {
+    fn drop(&mut self) {
+        journal_off(&self.block_dev);
+    }
+}
 impl EzFileSys {
     pub fn new(
         block_dev: Arc<dyn BlockDev>,
         total_blocks: u32,
         inode_bitmap_blocks: u32
-    ) -> Arc<Mutex<Self>> {
-        let inode_bitmap = Bitmap::new(1, inode_bitmap_blocks as usize);
+    ) -> EzResult<Arc<Mutex<Self>>> {
+        let journal_blocks = 1 + JOURNAL_LOG_BLOCKS as u32;
+        let meta_start = 1 + journal_blocks;
         let inode_num = inode_bitmap_blocks * BLOCK_BITS as u32;
+        let inode_bitmap = Bitmap::new(meta_start as usize, inode_bitmap_blocks as usize, inode_num as usize);
         let inode_blocks = ((inode_num as usize * size_of::<DiskInode>() + BLOCK_SIZE - 1) / BLOCK_SIZE) as u32;
         let inode_total = inode_bitmap_blocks + inode_blocks;
-        let data_total = total_blocks - inode_total - 1;
+        let data_total = total_blocks - inode_total - meta_start;
         let data_bitmap_blocks = (data_total + 4096) / 4097;
         let data_blocks = data_total - data_bitmap_blocks;
-        let data_bitmap = Bitmap::new(inode_total as usize + 1, data_bitmap_blocks as usize);
+        let data_bitmap = Bitmap::new((meta_start + inode_total) as usize, data_bitmap_blocks as usize, data_blocks as usize);
         let mut efs = Self {
             block_dev: Arc::clone(&block_dev),
             inode_bitmap,
             data_bitmap,
-            inode_start: 1 + inode_bitmap_blocks,
-            data_start: 1 + inode_total + data_bitmap_blocks
+            journal_start: 1,
+            inode_start: meta_start + inode_bitmap_blocks,
+            data_start: meta_start + inode_total + data_bitmap_blocks,
+            data_blocks,
+            free_inodes: 0,
+            free_blocks: 0,
+            committed_free: (0, 0),
+            journal: Journal::new(&block_dev, 1),
+            clock: no_clock
         };
+        journal_on(&block_dev);
         // init superblock
-        get_block_cache(0, Arc::clone(&block_dev))
+        get_block_cache(0, Arc::clone(&block_dev))?
             .lock()
             .modify(0, |super_blk: &mut SuperBlock| {
                 super_blk.init(
                     total_blocks,
+                    journal_blocks,
                     inode_bitmap_blocks,
                     inode_blocks,
                     data_bitmap_blocks,
                     data_blocks
                 );
             });
-        // clear blocks
-        (1..total_blocks as usize).for_each(|i| {
-            get_block_cache(i, Arc::clone(&block_dev))
-                .lock()
-                .modify(0, |blk: &mut DataBlock| {
-                    blk.iter_mut().for_each(|v| { *v = 0; });
-                })
-        });
-        efs.init_root();
-        sync_block_cache();
-        Arc::new(Mutex::new(efs))
+        // clear blocks, bypassing the cache
+        let zero = vec![0u8; MKFS_CLEAR_BLOCKS * BLOCK_SIZE];
+        let mut block_id = 1;
+        while block_id < total_blocks as usize {
+            let count = MKFS_CLEAR_BLOCKS.min(total_blocks as usize - block_id);
+            block_dev.write_blocks(block_id, &zero[..count * BLOCK_SIZE])?;
+            block_id += count;
+        }
+        efs.recount()?;
+        efs.init_root()?;
+        efs.committed_free = (efs.free_inodes, efs.free_blocks);
+        sync_block_cache()?;
+        Ok(Arc::new(Mutex::new(efs)))
     }
     
-    fn init_root(&mut self) {
-        assert_eq!(self.alloc_inode(), 0);
+    fn init_root(&mut self) -> EzResult<()> {
+        assert_eq!(self.alloc_inode()?, 0);
         let (root_blkid, root_offset) = self.inode_pos(0);
-        get_block_cache(root_blkid as usize, Arc::clone(&self.block_dev))
+        let dirent_blk = self.alloc_data()?;
+        get_block_cache(root_blkid as usize, Arc::clone(&self.block_dev))?
             .lock()
             .modify(root_offset, |inode: &mut DiskInode| {
-                inode.init(DiskInodeType::Dir);
+                inode.init(DiskInodeType::Dir, (self.clock)());
+                // ".." of root refers to itself
+                dir::init(inode, 0, 0, dirent_blk, &self.block_dev)
             })
     }
 
-    pub fn from_device(block_dev: Arc<dyn BlockDev>) -> Arc<Mutex<Self>> {
-        get_block_cache(0, Arc::clone(&block_dev))
+    pub fn from_device(block_dev: Arc<dyn BlockDev>) -> EzResult<Arc<Mutex<Self>>> {
+        let mut efs = get_block_cache(0, Arc::clone(&block_dev))?
             .lock()
             .read(0, |super_blk: &SuperBlock| {
-                assert!(super_blk.is_valid(), "Not a valid EFS device");
+                if !super_blk.is_valid() {
+                    return Err(EzFsError::Corrupted);
+                }
+                let meta_start = super_blk.journal_start + super_blk.journal_blocks;
                 let inode_total = super_blk.inode_bitmap_blocks + super_blk.inode_area_blocks;
-                let efs = Self {
-                    block_dev,
-                    inode_bitmap: Bitmap::new(1, super_blk.inode_bitmap_blocks as usize),
-                    data_bitmap: Bitmap::new(1 + inode_total as usize, super_blk.data_bitmap_blocks as usize),
-                    inode_start: 1 + super_blk.inode_bitmap_blocks,
-                    data_start: 1 + inode_total + super_blk.data_bitmap_blocks
-                };
-                Arc::new(Mutex::new(efs))
-            })
+                Ok(Self {
+                    block_dev: Arc::clone(&block_dev),
+                    inode_bitmap: Bitmap::new(
+                        meta_start as usize,
+                        super_blk.inode_bitmap_blocks as usize,
+                        super_blk.inode_bitmap_blocks as usize * BLOCK_BITS
+                    ),
+                    data_bitmap: Bitmap::new(
+                        (meta_start + inode_total) as usize,
+                        super_blk.data_bitmap_blocks as usize,
+                        super_blk.data_area_blocks as usize
+                    ),
+                    journal_start: super_blk.journal_start,
+                    inode_start: meta_start + super_blk.inode_bitmap_blocks,
+                    data_start: meta_start + inode_total + super_blk.data_bitmap_blocks,
+                    data_blocks: super_blk.data_area_blocks,
+                    free_inodes: 0,
+                    free_blocks: 0,
+                    committed_free: (0, 0),
+                    journal: Journal::new(&block_dev, super_blk.journal_start),
+                    clock: no_clock
+                })
+            })?;
+        journal_on(&block_dev);
+        efs.replay()?;
+        efs.recount()?;
+        let orphans = efs.orphans()?;
+        let efs = Arc::new(Mutex::new(efs));
+        // left by a crash while they were still open
+        for inode_id in orphans {
+            Self::get_vinode(&efs, inode_id).free_unlinked()?;
+        }
+        Ok(efs)
+    }
+
+    // allocated inodes without a link
+    fn orphans(&self) -> EzResult<Vec<u32>> {
+        let mut orphans = Vec::new();
+        for inode_id in 0..self.inode_bitmap.bits() {
+            if !self.inode_bitmap.is_set(&self.block_dev, inode_id)? {
+                continue;
+            }
+            let (block_id, block_offset) = self.inode_pos(inode_id as u32);
+            let nlink = get_block_cache(block_id as usize, Arc::clone(&self.block_dev))?
+                .lock()
+                .read(block_offset, |inode: &DiskInode| inode.nlink);
+            if nlink == 0 {
+                orphans.push(inode_id as u32);
+            }
+        }
+        Ok(orphans)
+    }
+
+    // write every modified block of the device atomically through the journal,
+    // for a caller that holds the journal and `fs` both, like fsck
+    pub fn commit(&mut self) -> EzResult<()> {
+        commit_dirty(&self.block_dev, self.journal_start)?;
+        self.committed_free = (self.free_inodes, self.free_blocks);
+        Ok(())
+    }
+
+    // redo a transaction that was committed but not fully written home
+    fn replay(&self) -> EzResult<()> {
+        let header = get_block_cache(self.journal_start as usize, Arc::clone(&self.block_dev))?;
+        let (count, blocks) = header.lock()
+            .read(0, |header: &JournalHeader| (header.count as usize, header.blocks));
+        if count == 0 {
+            return Ok(());
+        }
+        let mut log = vec![0u8; count * BLOCK_SIZE];
+        self.block_dev.read_blocks(self.journal_start as usize + 1, &mut log)?;
+        let mut homes = Vec::with_capacity(count);
+        for (block_id, src) in blocks.iter().zip(log.chunks(BLOCK_SIZE)) {
+            let home = get_block_cache(*block_id as usize, Arc::clone(&self.block_dev))?;
+            home.lock().modify(0, |dst: &mut DataBlock| dst.copy_from_slice(src));
+            homes.push((*block_id as usize, home));
+        }
+        write_block_caches(&homes, &self.block_dev)?;
+        write_journal_header(&self.block_dev, self.journal_start, |header| header.count = 0)
+    }
+
+    // the lock transactions are run under
+    pub fn journal(&self) -> Arc<Mutex<Journal>> {
+        Arc::clone(&self.journal)
+    }
+
+    // source of inode timestamps, in milliseconds since the unix epoch
+    pub fn set_clock(&mut self, clock: fn() -> u64) {
+        self.clock = clock;
+    }
+    pub fn now(&self) -> u64 {
+        (self.clock)()
+    }
+
+    // count the free positions of both bitmaps again
+    pub fn recount(&mut self) -> EzResult<()> {
+        self.free_inodes = self.inode_bitmap.count_free(&self.block_dev)? as u32;
+        self.free_blocks = self.data_bitmap.count_free(&self.block_dev)? as u32;
+        self.committed_free = (self.free_inodes, self.free_blocks);
+        Ok(())
+    }
+    pub fn statfs(&self) -> StatFs {
+        StatFs {
+            block_size: BLOCK_SIZE as u64,
+            blocks: self.data_blocks as u64,
+            free_blocks: self.free_blocks as u64,
+            inodes: self.inode_bitmap.bits() as u64,
+            free_inodes: self.free_inodes as u64,
+            name_max: NAME_MAX as u64
+        }
     }
 
     pub fn inode_pos(&self, inode_id: u32) -> (u32, usize) {
-        let inode_size = size_of::<DiskInode>();
-        let inode_pblk = (BLOCK_SIZE / inode_size) as u32;
-        (
-            self.inode_start + inode_id / inode_pblk,
-            (inode_id % inode_pblk) as usize * inode_size
-        )
+        inode_pos(self.inode_start, inode_id)
     }
     pub fn data_block_pos(&self, data_id: u32) -> u32 {
         self.data_start + data_id
     }
-    pub fn alloc_inode(&mut self) -> u32 {
-        self.inode_bitmap.alloc(&self.block_dev)
-            .expect("Cannot allocate block for inode") as u32
+    pub fn alloc_inode(&mut self) -> EzResult<u32> {
+        let inode_id = self.inode_bitmap.alloc(&self.block_dev)?
+            .ok_or(EzFsError::NoSpace)?;
+        self.free_inodes -= 1;
+        Ok(inode_id as u32)
+    }
+    pub fn dealloc_inode(&mut self, inode_id: u32) -> EzResult<()> {
+        self.inode_bitmap.dealloc(&self.block_dev, inode_id as usize)?;
+        self.free_inodes += 1;
+        Ok(())
     }
-    pub fn alloc_data(&mut self) -> u32 {
-        self.data_start +
-            self.data_bitmap
-                .alloc(&self.block_dev)
-                .expect("Cannot allocate block for data") as u32
+    pub fn alloc_data(&mut self) -> EzResult<u32> {
+        self.alloc_data_near(self.data_start)
     }
-    pub fn dealloc_data(&mut self, block_id: u32) {
-        get_block_cache(block_id as usize, Arc::clone(&self.block_dev))
+    // a zeroed data block, the first free one at or after block `goal`
+    pub fn alloc_data_near(&mut self, goal: u32) -> EzResult<u32> {
+        let pos = self.data_bitmap
+            .alloc_near(&self.block_dev, goal.saturating_sub(self.data_start) as usize)?
+            .ok_or(EzFsError::NoSpace)?;
+        self.free_blocks -= 1;
+        let block_id = self.data_start + pos as u32;
+        get_block_cache(block_id as usize, Arc::clone(&self.block_dev))?
             .lock()
             .modify(0, |blk: &mut DataBlock| {
                 blk.iter_mut().for_each(|v| *v = 0)
             });
-        self.data_bitmap.dealloc(&self.block_dev, (block_id - self.data_start) as usize);
+        Ok(block_id)
+    }
+    // where the first block of inode `inode_id` is looked for: the data area
+    // is cut into groups of one bitmap block each, handed to inodes in turn,
+    // so that files written side by side do not interleave
+    pub fn data_goal(&self, inode_id: u32) -> u32 {
+        let groups = (self.data_blocks as usize + BLOCK_BITS - 1) / BLOCK_BITS;
+        self.data_start + (inode_id as usize % groups.max(1) * BLOCK_BITS) as u32
+    }
+    pub fn dealloc_data(&mut self, block_id: u32) -> EzResult<()> {
+        if block_id < self.data_start {
+            return Err(EzFsError::Corrupted);
+        }
+        self.data_bitmap.dealloc(&self.block_dev, (block_id - self.data_start) as usize)?;
+        self.free_blocks += 1;
+        Ok(())
     }
 
     pub fn root_vinode(efs: &Arc<Mutex<Self>>) -> VirtInode {
-        let block_dev = Arc::clone(&efs.lock().block_dev);
-        let (block_id, block_offset) = efs.lock().inode_pos(0);
+        Self::get_vinode(efs, 0)
+    }
+
+    pub fn get_vinode(efs: &Arc<Mutex<Self>>, inode_id: u32) -> VirtInode {
+        let fs = efs.lock();
         VirtInode::new(
-            block_id,
-            block_offset,
+            inode_id,
+            fs.inode_start,
             Arc::clone(efs),
-            block_dev
+            Arc::clone(&fs.journal),
+            Arc::clone(&fs.block_dev)
         )
     }
 }
+
+// write every modified block of the device atomically through the journal;
+// a transaction the journal can't hold is refused, for the caller to abort
+fn commit_dirty(block_dev: &Arc<dyn BlockDev>, journal_start: u32) -> EzResult<()> {
+    let dirty = dirty_block_caches(block_dev);
+    if dirty.is_empty() {
+        return Ok(());
+    }
+    if dirty.len() > JOURNAL_LOG_BLOCKS {
+        return Err(EzFsError::TxTooLarge);
+    }
+    // the log goes around the cache in one request, only replay reads it
+    let mut log = vec![0u8; dirty.len() * BLOCK_SIZE];
+    for ((_, bc), dst) in dirty.iter().zip(log.chunks_mut(BLOCK_SIZE)) {
+        bc.lock().read(0, |src: &DataBlock| dst.copy_from_slice(src));
+    }
+    block_dev.write_blocks(journal_start as usize + 1, &log)?;
+    write_journal_header(block_dev, journal_start, |header| {
+        header.count = dirty.len() as u32;
+        for (i, (block_id, _)) in dirty.iter().enumerate() {
+            header.blocks[i] = *block_id as u32;
+        }
+    })?;
+    write_block_caches(&dirty, block_dev)?;
+    write_journal_header(block_dev, journal_start, |header| header.count = 0)
+}
+
+// the header goes to the device after its lock is let go
+fn write_journal_header(block_dev: &Arc<dyn BlockDev>, journal_start: u32, f: impl FnOnce(&mut JournalHeader)) -> EzResult<()> {
+    let header = get_block_cache(journal_start as usize, Arc::clone(block_dev))?;
+    header.lock().modify(0, f);
+    write_block_caches(&[(journal_start as usize, header)], block_dev)
+}
+
+// Transactions run one at a time under the lock of the journal, which is
+// taken after the locks of the inodes they change and before `fs`; `fs` is
+// only held while blocks and inodes are allocated or freed.
+pub struct Journal {
+    block_dev: Arc<dyn BlockDev>,
+    start: u32
+}
+impl Journal {
+    fn new(block_dev: &Arc<dyn BlockDev>, start: u32) -> Arc<Mutex<Self>> {
+        Arc::new(Mutex::new(Self { block_dev: Arc::clone(block_dev), start }))
+    }
+    // run `f` as one transaction: committed if it succeeds, dropped if not
+    pub fn transaction<V>(&mut self, fs: &Mutex<EzFileSys>, f: impl FnOnce(&mut Tx) -> EzResult<V>) -> EzResult<V> {
+        let mut tx = Tx { journal: self, fs };
+        match f(&mut tx).and_then(|v| tx.commit().map(|_| v)) {
+            Ok(v) => Ok(v),
+            Err(e) => {
+                // forget the changes made since the last commit
+                discard_dirty_caches(&self.block_dev);
+                let mut fs = fs.lock();
+                (fs.free_inodes, fs.free_blocks) = fs.committed_free;
+                Err(e)
+            }
+        }
+    }
+}
+
+// a running transaction, the allocator of the file system behind it
+pub struct Tx<'a> {
+    journal: &'a Journal,
+    fs: &'a Mutex<EzFileSys>
+}
+impl Tx<'_> {
+    // commit what has been done so far, the transaction carries on
+    pub fn commit(&mut self) -> EzResult<()> {
+        commit_dirty(&self.journal.block_dev, self.journal.start)?;
+        let mut fs = self.fs.lock();
+        fs.committed_free = (fs.free_inodes, fs.free_blocks);
+        Ok(())
+    }
+    // blocks the transaction has modified so far
+    pub fn dirty_blocks(&self) -> usize {
+        dirty_block_caches(&self.journal.block_dev).len()
+    }
+    pub fn now(&self) -> u64 {
+        self.fs.lock().now()
+    }
+    pub fn data_goal(&self, inode_id: u32) -> u32 {
+        self.fs.lock().data_goal(inode_id)
+    }
+    pub fn alloc_inode(&mut self) -> EzResult<u32> {
+        self.fs.lock().alloc_inode()
+    }
+    pub fn dealloc_inode(&mut self, inode_id: u32) -> EzResult<()> {
+        self.fs.lock().dealloc_inode(inode_id)
+    }
+    pub fn alloc_data_near(&mut self, goal: u32) -> EzResult<u32> {
+        self.fs.lock().alloc_data_near(goal)
+    }
+    pub fn dealloc_data(&mut self, block_id: u32) -> EzResult<()> {
+        self.fs.lock().dealloc_data(block_id)
+    }
+}
+
+// (block, byte offset) of inode `inode_id` in an inode area from `inode_start`
+pub(crate) fn inode_pos(inode_start: u32, inode_id: u32) -> (u32, usize) {
+    let inode_size = size_of::<DiskInode>();
+    let inode_pblk = (BLOCK_SIZE / inode_size) as u32;
+    (
+        inode_start + inode_id / inode_pblk,
+        (inode_id % inode_pblk) as usize * inode_size
+    )
+}
@@ -0,0 +1,76 @@
+use core::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EzFsError {
+    // the block device failed
+    Io,
+    // not an easyfs image, or a broken one
+    Corrupted,
+    // out of inodes or data blocks
+    NoSpace,
+    // beyond the largest file an inode can map
+    TooLarge,
+    NotFound,
+    Exists,
+    NotDir,
+    IsDir,
+    NotEmpty,
+    InvalidName,
+    // longer than NAME_MAX bytes
+    NameTooLong,
+    PermissionDenied,
+    // too many symbolic links followed in one path
+    Loop,
+    // one transaction changed more blocks than the journal holds
+    TxTooLarge,
+    // the block cache is full of blocks in use, try again later
+    Busy
+}
+
+pub type EzResult<T> = Result<T, EzFsError>;
+
+impl EzFsError {
+    // the matching linux errno, positive
+    pub fn errno(&self) -> i32 {
+        match self {
+            Self::Io => 5,                  // EIO
+            Self::Corrupted => 117,         // EUCLEAN
+            Self::NoSpace => 28,            // ENOSPC
+            Self::TooLarge => 27,           // EFBIG
+            Self::NotFound => 2,            // ENOENT
+            Self::Exists => 17,             // EEXIST
+            Self::NotDir => 20,             // ENOTDIR
+            Self::IsDir => 21,              // EISDIR
+            Self::NotEmpty => 39,           // ENOTEMPTY
+            Self::InvalidName => 22,        // EINVAL
+            Self::NameTooLong => 36,        // ENAMETOOLONG
+            Self::PermissionDenied => 13,   // EACCES
+            Self::Loop => 40,               // ELOOP
+            Self::TxTooLarge => 27,         // EFBIG
+            Self::Busy => 16                // EBUSY
+        }
+    }
+}
+
+impl fmt::Display for EzFsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let msg = match self {
+            Self::Io => "I/O error",
+            Self::Corrupted => "not a valid easyfs image",
+            Self::NoSpace => "no space left on device",
+            Self::TooLarge => "file too large",
+            Self::NotFound => "no such file or directory",
+            Self::Exists => "file exists",
+            Self::NotDir => "not a directory",
+            Self::IsDir => "is a directory",
+            Self::NotEmpty => "directory not empty",
+            Self::InvalidName => "invalid file name",
+            Self::NameTooLong => "file name too long",
+            Self::PermissionDenied => "permission denied",
+            Self::Loop => "too many levels of symbolic links",
+            Self::TxTooLarge => "transaction too large for the journal",
+            Self::Busy => "block cache full"
+        };
+        f.write_str(msg)
+    }
+}
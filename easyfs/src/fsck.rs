@@ -0,0 +1,392 @@
+use core::{cmp::min, fmt, mem::size_of};
+
+use alloc::{collections::VecDeque, string::String, sync::Arc, vec, vec::Vec};
+
+use crate::{bitmap::BLOCK_BITS, cache_man::get_block_cache, dir::{self, Records}, efs::EzFileSys, layout::{DiskInode, SuperBlock}, sync::{Mutex, MutexGuard}, vfs::open_orphans, EzResult, BLOCK_SIZE};
+
+const INDIRECT_COUNT: usize = BLOCK_SIZE / 4;
+type IndirectBlock = [u32; INDIRECT_COUNT];
+type DataBlock = [u8; BLOCK_SIZE];
+// (byte offset, inode, name) of the entries in a directory block
+type DirBlockEntries = Vec<(usize, u32, Vec<u8>)>;
+
+#[derive(Debug, PartialEq)]
+pub enum FsckIssue {
+    // the areas in SuperBlock do not add up to the device
+    BadGeometry,
+    // an entry naming a free or nonexistent inode
+    DanglingEntry { dir: u32, name: String, inode_id: u32 },
+    // a name that is empty, not in utf-8 or holding '/' or nul
+    BadEntryName { dir: u32, offset: usize },
+    // a directory block whose records do not tile it
+    BadDirBlock { dir: u32, block: usize },
+    // an entry the hash index of its directory does not lead to
+    BadIndex { dir: u32, name: String },
+    // "." or ".." pointing to the wrong inode
+    BadDotEntry { dir: u32, name: String, inode_id: u32 },
+    LinkCount { inode_id: u32, nlink: u32, found: u32 },
+    // a pointer outside the data area
+    BlockOutOfRange { inode_id: u32, block_id: u32 },
+    DuplicateBlock { inode_id: u32, block_id: u32, owner: u32 },
+    // size beyond the largest file, or not whole entries in a directory
+    SizeMismatch { inode_id: u32, size: u32 },
+    // block count in the inode not matching the blocks mapped
+    BlockCount { inode_id: u32, blocks: u32, found: u32 },
+    // allocated in the bitmap but unreachable
+    LeakedInode { inode_id: u32 },
+    LeakedBlock { block_id: u32 },
+    // in use but free in the bitmap
+    UnmarkedBlock { block_id: u32 }
+}
+
+impl FsckIssue {
+    pub fn repairable(&self) -> bool {
+        !matches!(
+            self,
+            Self::BadGeometry | Self::BlockOutOfRange { .. } | Self::DuplicateBlock { .. } | Self::SizeMismatch { .. }
+                | Self::BadDirBlock { .. } | Self::BadIndex { .. }
+        )
+    }
+}
+
+impl fmt::Display for FsckIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::BadGeometry => write!(f, "superblock: areas do not match the device size"),
+            Self::DanglingEntry { dir, name, inode_id } =>
+                write!(f, "dir {}: entry \"{}\" refers to unused inode {}", dir, name, inode_id),
+            Self::BadEntryName { dir, offset } => write!(f, "dir {}: entry at {} has a broken name", dir, offset),
+            Self::BadDirBlock { dir, block } => write!(f, "dir {}: block {} has broken records", dir, block),
+            Self::BadIndex { dir, name } => write!(f, "dir {}: entry \"{}\" is missing from the index", dir, name),
+            Self::BadDotEntry { dir, name, inode_id } =>
+                write!(f, "dir {}: \"{}\" refers to inode {}", dir, name, inode_id),
+            Self::LinkCount { inode_id, nlink, found } =>
+                write!(f, "inode {}: link count {}, found {} links", inode_id, nlink, found),
+            Self::BlockOutOfRange { inode_id, block_id } =>
+                write!(f, "inode {}: block {} is outside the data area", inode_id, block_id),
+            Self::DuplicateBlock { inode_id, block_id, owner } =>
+                write!(f, "inode {}: block {} is also used by inode {}", inode_id, block_id, owner),
+            Self::SizeMismatch { inode_id, size } =>
+                write!(f, "inode {}: size {} is not a valid size", inode_id, size),
+            Self::BlockCount { inode_id, blocks, found } =>
+                write!(f, "inode {}: block count {}, found {} blocks", inode_id, blocks, found),
+            Self::LeakedInode { inode_id } => write!(f, "inode {}: allocated but unreachable", inode_id),
+            Self::LeakedBlock { block_id } => write!(f, "block {}: allocated but unused", block_id),
+            Self::UnmarkedBlock { block_id } => write!(f, "block {}: in use but marked free", block_id)
+        }
+    }
+}
+
+struct Checker<'a> {
+    fs: MutexGuard<'a, EzFileSys>,
+    repair: bool,
+    inode_num: usize,
+    data_start: u32,
+    data_num: usize,
+    // inode owning each data block
+    owner: Vec<Option<u32>>,
+    links: Vec<u32>,
+    visited: Vec<bool>,
+    // blocks claimed for the inode being walked
+    claimed: u32,
+    // each issue and whether it was repaired
+    issues: Vec<(FsckIssue, bool)>
+}
+
+// walk an image from its root, reporting inconsistencies;
+// with `repair`, fixable ones are corrected through the journal
+pub fn check(efs: &Arc<Mutex<EzFileSys>>, repair: bool) -> EzResult<Vec<(FsckIssue, bool)>> {
+    // no transaction may run while the image is walked
+    let journal = efs.lock().journal();
+    let _journal = journal.lock();
+    let fs = efs.lock();
+    let block_dev = Arc::clone(&fs.block_dev);
+    let (geometry_ok, inode_num, data_num) = get_block_cache(0, block_dev)?
+        .lock()
+        .read(0, |sb: &SuperBlock| {
+            let inode_num = min(
+                sb.inode_bitmap_blocks as usize * BLOCK_BITS,
+                sb.inode_area_blocks as usize * (BLOCK_SIZE / size_of::<DiskInode>())
+            );
+            let used = 1 + sb.journal_blocks + sb.inode_bitmap_blocks + sb.inode_area_blocks
+                + sb.data_bitmap_blocks + sb.data_area_blocks;
+            (
+                used == sb.total_blocks && sb.data_area_blocks as usize <= sb.data_bitmap_blocks as usize * BLOCK_BITS,
+                inode_num,
+                sb.data_area_blocks as usize
+            )
+        });
+    if !geometry_ok {
+        return Ok(vec![(FsckIssue::BadGeometry, false)]);
+    }
+    let mut checker = Checker {
+        data_start: fs.data_block_pos(0),
+        fs,
+        repair,
+        inode_num,
+        data_num,
+        owner: vec![None; data_num],
+        links: vec![0; inode_num],
+        visited: vec![false; inode_num],
+        claimed: 0,
+        issues: Vec::new()
+    };
+    checker.run()?;
+    Ok(checker.issues)
+}
+
+impl Checker<'_> {
+    fn read_inode<V>(&self, inode_id: u32, f: impl FnOnce(&DiskInode) -> V) -> EzResult<V> {
+        let (block_id, block_offset) = self.fs.inode_pos(inode_id);
+        Ok(get_block_cache(block_id as usize, Arc::clone(&self.fs.block_dev))?
+            .lock()
+            .read(block_offset, f))
+    }
+    fn modify_inode<V>(&self, inode_id: u32, f: impl FnOnce(&mut DiskInode) -> V) -> EzResult<V> {
+        let (block_id, block_offset) = self.fs.inode_pos(inode_id);
+        Ok(get_block_cache(block_id as usize, Arc::clone(&self.fs.block_dev))?
+            .lock()
+            .modify(block_offset, f))
+    }
+    fn read_indirect(&self, block_id: u32) -> EzResult<IndirectBlock> {
+        Ok(get_block_cache(block_id as usize, Arc::clone(&self.fs.block_dev))?
+            .lock()
+            .read(0, |blk: &IndirectBlock| *blk))
+    }
+
+    fn report(&mut self, issue: FsckIssue, repaired: bool) {
+        self.issues.push((issue, repaired));
+    }
+
+    // record `block_id` as used by `inode_id`, false if it cannot be followed
+    fn claim(&mut self, inode_id: u32, block_id: u32) -> EzResult<bool> {
+        if block_id < self.data_start || (block_id - self.data_start) as usize >= self.data_num {
+            self.report(FsckIssue::BlockOutOfRange { inode_id, block_id }, false);
+            return Ok(false);
+        }
+        let idx = (block_id - self.data_start) as usize;
+        match self.owner[idx] {
+            Some(owner) => {
+                self.report(FsckIssue::DuplicateBlock { inode_id, block_id, owner }, false);
+                Ok(false)
+            }
+            None => {
+                self.owner[idx] = Some(inode_id);
+                self.claimed += 1;
+                Ok(true)
+            }
+        }
+    }
+
+    // claim the data and index blocks of an inode, true if all could be followed;
+    // zero pointers are holes
+    fn claim_blocks(&mut self, inode_id: u32) -> EzResult<bool> {
+        let (size, blocks, direct, indirect) = self.read_inode(inode_id, |inode| {
+            (inode.size, inode.data_blocks() as usize, inode.direct, inode.indirect)
+        })?;
+        self.claimed = 0;
+        if size > DiskInode::max_size() {
+            self.report(FsckIssue::SizeMismatch { inode_id, size }, false);
+            return Ok(false);
+        }
+        let mut ok = true;
+        for block_id in direct.iter().take(blocks).filter(|block_id| **block_id != 0) {
+            ok &= self.claim(inode_id, *block_id)?;
+        }
+        let mut rest = blocks.saturating_sub(direct.len());
+        for (level, root) in indirect.iter().enumerate() {
+            if rest == 0 {
+                break;
+            }
+            let mapped = min(rest, INDIRECT_COUNT.pow(level as u32 + 1));
+            ok &= self.claim_tree(inode_id, *root, level + 1, mapped)?;
+            rest -= mapped;
+        }
+        let (count, found) = (self.read_inode(inode_id, |inode| inode.blocks)?, self.claimed);
+        if ok && count != found {
+            if self.repair {
+                self.modify_inode(inode_id, |inode| inode.blocks = found)?;
+                self.fs.commit()?;
+            }
+            self.report(FsckIssue::BlockCount { inode_id, blocks: count, found }, self.repair);
+        }
+        Ok(ok)
+    }
+
+    // claim an index block `depth` layers above the first `count` data blocks it maps
+    fn claim_tree(&mut self, inode_id: u32, block_id: u32, depth: usize, count: usize) -> EzResult<bool> {
+        if block_id == 0 {
+            return Ok(true);
+        }
+        if !self.claim(inode_id, block_id)? {
+            return Ok(false);
+        }
+        let span = INDIRECT_COUNT.pow(depth as u32 - 1);
+        let mut ok = true;
+        for (i, child) in self.read_indirect(block_id)?.iter().take((count + span - 1) / span).enumerate() {
+            ok &= if depth == 1 {
+                *child == 0 || self.claim(inode_id, *child)?
+            } else {
+                self.claim_tree(inode_id, *child, depth - 1, min(span, count - i * span))?
+            };
+        }
+        Ok(ok)
+    }
+
+    // drop the entry at byte `offset` of a directory
+    fn remove_dirent(&mut self, dir: u32, offset: usize) -> EzResult<()> {
+        let block_dev = Arc::clone(&self.fs.block_dev);
+        self.read_inode(dir, |inode| dir::remove(inode, offset, &block_dev))??;
+        self.fs.commit()
+    }
+
+    // the entries of a directory block, None if its records are broken
+    fn read_dir_block(&self, dir: u32, inner_id: usize) -> EzResult<Option<DirBlockEntries>> {
+        let block_dev = Arc::clone(&self.fs.block_dev);
+        let block_id = self.read_inode(dir, |inode| inode.get_block_id(inner_id as u32, &block_dev))??;
+        if block_id == 0 {
+            return Ok(None);
+        }
+        Ok(get_block_cache(block_id as usize, block_dev)?
+            .lock()
+            .read(0, |blk: &DataBlock| {
+                Records::new(blk)
+                    .filter(|rec| !matches!(rec, Ok(rec) if rec.is_free()))
+                    .map(|rec| rec.map(|rec| (inner_id * BLOCK_SIZE + rec.offset, rec.inode, rec.name.to_vec())))
+                    .collect::<EzResult<_>>()
+                    .ok()
+            }))
+    }
+
+    // check the entries of a directory whose blocks are all valid
+    fn check_dir(&mut self, dir: u32, parent: u32, queue: &mut VecDeque<(u32, u32)>) -> EzResult<()> {
+        let block_dev = Arc::clone(&self.fs.block_dev);
+        let size = self.read_inode(dir, |inode| inode.size)?;
+        if size == 0 || size as usize % BLOCK_SIZE != 0 {
+            self.report(FsckIssue::SizeMismatch { inode_id: dir, size }, false);
+            return Ok(());
+        }
+        for inner_id in 0..size as usize / BLOCK_SIZE {
+            let Some(entries) = self.read_dir_block(dir, inner_id)? else {
+                self.report(FsckIssue::BadDirBlock { dir, block: inner_id }, false);
+                continue;
+            };
+            for (offset, inode_id, raw) in entries {
+                let name = core::str::from_utf8(&raw)
+                    .ok()
+                    .filter(|name| !name.is_empty() && !name.contains(['/', '\0']))
+                    .map(String::from);
+                let Some(name) = name else {
+                    if self.repair {
+                        self.remove_dirent(dir, offset)?;
+                    }
+                    self.report(FsckIssue::BadEntryName { dir, offset }, self.repair);
+                    continue;
+                };
+                if name == "." || name == ".." {
+                    let expected = if name == "." { dir } else { parent };
+                    if inode_id != expected {
+                        if self.repair {
+                            self.read_inode(dir, |inode| dir::set_inode(inode, offset, expected, &block_dev))??;
+                            self.fs.commit()?;
+                        }
+                        self.report(FsckIssue::BadDotEntry { dir, name, inode_id }, self.repair);
+                    }
+                    self.links[expected as usize] += 1;
+                    continue;
+                }
+                // the index must lead to every entry of a large directory
+                if size as usize > BLOCK_SIZE
+                    && !matches!(self.read_inode(dir, |inode| dir::lookup(inode, &name, &block_dev))?, Ok(Some((found, _))) if found == offset) {
+                    self.report(FsckIssue::BadIndex { dir, name: name.clone() }, false);
+                }
+                if inode_id as usize >= self.inode_num || !self.fs.inode_bitmap.is_set(&block_dev, inode_id as usize)? {
+                    if self.repair {
+                        self.remove_dirent(dir, offset)?;
+                    }
+                    self.report(FsckIssue::DanglingEntry { dir, name, inode_id }, self.repair);
+                    continue;
+                }
+                self.links[inode_id as usize] += 1;
+                if self.visited[inode_id as usize] {
+                    continue;
+                }
+                self.visited[inode_id as usize] = true;
+                let blocks_ok = self.claim_blocks(inode_id)?;
+                if blocks_ok && self.read_inode(inode_id, |inode| inode.is_dir())? {
+                    queue.push_back((inode_id, dir));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn run(&mut self) -> EzResult<()> {
+        let block_dev = Arc::clone(&self.fs.block_dev);
+        self.visited[0] = true;
+        let mut queue: VecDeque<(u32, u32)> = VecDeque::new();
+        if self.claim_blocks(0)? {
+            queue.push_back((0, 0));
+        }
+        while let Some((dir, parent)) = queue.pop_front() {
+            self.check_dir(dir, parent, &mut queue)?;
+        }
+        // unlinked but still open, they keep their blocks until closed
+        for inode_id in open_orphans(&block_dev) {
+            if (inode_id as usize) < self.inode_num && !self.visited[inode_id as usize] {
+                self.visited[inode_id as usize] = true;
+                self.claim_blocks(inode_id)?;
+            }
+        }
+        for inode_id in 0..self.inode_num as u32 {
+            if !self.visited[inode_id as usize] {
+                continue;
+            }
+            let (nlink, found) = (self.read_inode(inode_id, |inode| inode.nlink)?, self.links[inode_id as usize]);
+            if nlink != found {
+                if self.repair {
+                    self.modify_inode(inode_id, |inode| inode.nlink = found)?;
+                    self.fs.commit()?;
+                }
+                self.report(FsckIssue::LinkCount { inode_id, nlink, found }, self.repair);
+            }
+        }
+        // an unfollowed pointer hides part of the tree, so nothing is freed
+        let complete = self.issues.iter().all(|(issue, _)| issue.repairable());
+        let free = self.repair && complete;
+        for inode_id in 0..self.inode_num {
+            if !self.visited[inode_id] && self.fs.inode_bitmap.is_set(&block_dev, inode_id)? {
+                if free {
+                    self.fs.dealloc_inode(inode_id as u32)?;
+                    self.fs.commit()?;
+                }
+                self.report(FsckIssue::LeakedInode { inode_id: inode_id as u32 }, free);
+            }
+        }
+        for idx in 0..self.data_num {
+            let block_id = self.data_start + idx as u32;
+            match (self.owner[idx].is_some(), self.fs.data_bitmap.is_set(&block_dev, idx)?) {
+                (false, true) => {
+                    if free {
+                        self.fs.dealloc_data(block_id)?;
+                        self.fs.commit()?;
+                    }
+                    self.report(FsckIssue::LeakedBlock { block_id }, free);
+                }
+                (true, false) => {
+                    if self.repair {
+                        self.fs.data_bitmap.set(&block_dev, idx)?;
+                        self.fs.commit()?;
+                    }
+                    self.report(FsckIssue::UnmarkedBlock { block_id }, self.repair);
+                }
+                _ => {}
+            }
+        }
+        // the free counts followed the bitmaps as they were found
+        if self.repair {
+            self.fs.recount()?;
+        }
+        Ok(())
+    }
+}
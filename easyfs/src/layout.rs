@@ -1,16 +1,26 @@
-use core::{cmp::min, ffi::CStr};
+use core::{cmp::min, ops::Range};
 
 use alloc::{sync::Arc, vec::Vec};
 
-use crate::{BLOCK_SIZE, BlockDev, cache_man::get_block_cache};
+use crate::{BLOCK_SIZE, BlockDev, EzFsError, EzResult, cache_man::{get_block_cache, peek_block_cache, read_ahead_block_caches}};
 
 const EZFS_MAGIC: u32 = 0x53465A45;     //  b"EZFS"
-const INODE_DIRECT_COUNT: usize = 28;
+// 2: timestamps and mode bits in DiskInode
+// 3: journal after the superblock
+// 4: a triple indirect tree in DiskInode
+// 5: holes and a block count in DiskInode
+// 6: variable length directory records and a hash index
+// 7: symbolic link inodes
+const EZFS_VERSION: u32 = 7;
+const INODE_DIRECT_COUNT: usize = 18;
 
 #[repr(C)]
 pub struct SuperBlock {
     magic_num: u32,
+    version: u32,
     pub total_blocks: u32,
+    pub journal_start: u32,
+    pub journal_blocks: u32,
     pub inode_bitmap_blocks: u32,
     pub inode_area_blocks: u32,
     pub data_bitmap_blocks: u32,
@@ -20,6 +30,7 @@ impl SuperBlock {
     pub fn init(
         &mut self,
         total_blocks: u32,
+        journal_blocks: u32,
         inode_bitmap_blocks: u32,
         inode_area_blocks: u32,
         data_bitmap_blocks: u32,
@@ -27,7 +38,11 @@ impl SuperBlock {
     ) {
         *self = Self {
             magic_num: EZFS_MAGIC,
+            version: EZFS_VERSION,
             total_blocks,
+            // right after the superblock
+            journal_start: 1,
+            journal_blocks,
             inode_bitmap_blocks,
             inode_area_blocks,
             data_bitmap_blocks,
@@ -36,249 +51,326 @@ impl SuperBlock {
     }
     
     pub fn is_valid(&self) -> bool {
-        self.magic_num == EZFS_MAGIC
+        self.magic_num == EZFS_MAGIC && self.version == EZFS_VERSION
     }
 }
 
-#[derive(PartialEq)]
+pub const JOURNAL_LOG_BLOCKS: usize = 32;
+
+// first block of the journal, followed by the logged copies;
+// a transaction is committed once this block is written with count > 0
+#[repr(C)]
+pub struct JournalHeader {
+    pub count: u32,
+    pub blocks: [u32; JOURNAL_LOG_BLOCKS]
+}
+
+#[derive(PartialEq, Clone, Copy)]
 pub enum DiskInodeType {
     File,
-    Dir
+    Dir,
+    // the target path is kept as the data of the inode
+    Symlink
 }
 
 #[repr(C)]
+#[derive(Clone)]
 pub struct DiskInode {
     pub size: u32,
     pub direct: [u32; INODE_DIRECT_COUNT],
-    pub indirect: [u32; 2],
+    // roots of the single, double and triple indirect trees
+    pub indirect: [u32; INODE_INDIRECT_LEVELS],
+    // milliseconds from the clock of EzFileSys
+    pub atime: u64,
+    pub mtime: u64,
+    pub ctime: u64,
+    pub nlink: u32,
+    // rwx permission bits
+    pub mode: u32,
+    // data and index blocks allocated, holes take none
+    pub blocks: u32,
     ty_inode: DiskInodeType
 }
 
 const INODE_INDIRECT_COUNT: usize = BLOCK_SIZE / 4;
-const INODE_MAX_COUNT: usize = INODE_DIRECT_COUNT + INODE_INDIRECT_COUNT + INODE_INDIRECT_COUNT * INODE_INDIRECT_COUNT;
+pub const INODE_INDIRECT_LEVELS: usize = 3;
+const INODE_MAX_COUNT: usize = INODE_DIRECT_COUNT + indirect_span(1) + indirect_span(2) + indirect_span(3);
+// blocks loaded into the cache along with a small read that misses it
+const READ_AHEAD_BLOCKS: usize = 8;
 type IndirectBlock = [u32; BLOCK_SIZE / 4];
+
+// data blocks under an indirect block `depth` layers above them
+const fn indirect_span(depth: usize) -> usize {
+    INODE_INDIRECT_COUNT.pow(depth as u32)
+}
 type DataBlock = [u8; BLOCK_SIZE];
 impl DiskInode {
-    pub fn init(&mut self, ty_inode: DiskInodeType) {
+    pub fn init(&mut self, ty_inode: DiskInodeType, now: u64) {
         self.size = 0;
         self.direct.iter_mut().for_each(|v| *v = 0);
-        self.indirect = [0, 0];
+        self.indirect = [0; INODE_INDIRECT_LEVELS];
+        self.atime = now;
+        self.mtime = now;
+        self.ctime = now;
+        // a directory is also linked by its own "."
+        self.nlink = if ty_inode == DiskInodeType::Dir { 2 } else { 1 };
+        self.mode = match ty_inode {
+            DiskInodeType::File => 0o644,
+            DiskInodeType::Dir => 0o755,
+            DiskInodeType::Symlink => 0o777
+        };
+        self.blocks = 0;
         self.ty_inode = ty_inode;
     }
     pub fn is_dir(&self) -> bool { self.ty_inode == DiskInodeType::Dir }
     pub fn is_file(&self) -> bool { self.ty_inode == DiskInodeType::File }
-    pub fn get_block_id(&self, inner_id: u32, block_dev: &Arc<dyn BlockDev>) -> u32 {
-        let inner_id = inner_id as usize;
-        if inner_id < INODE_DIRECT_COUNT {
-            self.direct[inner_id]
-        } else if inner_id < INODE_DIRECT_COUNT + INODE_INDIRECT_COUNT {
-            get_block_cache(self.indirect[0] as usize, Arc::clone(block_dev))
-                .lock()
-                .read(0, |indir_blk: &IndirectBlock| {
-                    indir_blk[inner_id - INODE_DIRECT_COUNT]
-                })
-        } else {
-            let tail = inner_id - INODE_DIRECT_COUNT - INODE_INDIRECT_COUNT;
-            let indir1 = get_block_cache(self.indirect[1] as usize, Arc::clone(block_dev))
-                .lock()
-                .read(0, |indir_blk: &IndirectBlock| {
-                    indir_blk[tail / INODE_INDIRECT_COUNT]
-                });
-            get_block_cache(indir1 as usize, Arc::clone(block_dev))
+    pub fn is_symlink(&self) -> bool { self.ty_inode == DiskInodeType::Symlink }
+    // the block backing block `inner_id` of the file, 0 for a hole
+    pub fn get_block_id(&self, inner_id: u32, block_dev: &Arc<dyn BlockDev>) -> EzResult<u32> {
+        let (level, pos) = Self::locate(inner_id as usize);
+        if level == 0 {
+            return Ok(self.direct[pos]);
+        }
+        let mut block_id = self.indirect[level - 1];
+        for depth in (0..level).rev() {
+            if block_id == 0 {
+                break;
+            }
+            let idx = pos / indirect_span(depth) % INODE_INDIRECT_COUNT;
+            block_id = get_block_cache(block_id as usize, Arc::clone(block_dev))?
                 .lock()
-                .read(0, |indir_blk: &IndirectBlock| {
-                    indir_blk[tail % INODE_INDIRECT_COUNT]
-                })
+                .read(0, |indir_blk: &IndirectBlock| indir_blk[idx]);
         }
+        Ok(block_id)
+    }
+    // the tree mapping block `inner_id` of the file, 0 for the direct pointers
+    // or the depth of an indirect one, and the position of the block in it
+    fn locate(inner_id: usize) -> (usize, usize) {
+        if inner_id < INODE_DIRECT_COUNT {
+            return (0, inner_id);
+        }
+        let mut pos = inner_id - INODE_DIRECT_COUNT;
+        for level in 1..=INODE_INDIRECT_LEVELS {
+            if pos < indirect_span(level) {
+                return (level, pos);
+            }
+            pos -= indirect_span(level);
+        }
+        panic!("Block {} beyond the largest file", inner_id);
     }
     pub fn data_blocks(&self) -> u32 {
         (self.size + BLOCK_SIZE as u32 - 1) / BLOCK_SIZE as u32
     }
-    fn _data_blocks(size: u32) -> u32 {
-        (size + BLOCK_SIZE as u32 - 1) / BLOCK_SIZE as u32
-    }
-    pub fn total_blocks(size: u32) -> u32 {
-        let blks = Self::_data_blocks(size) as usize;
-        let total: usize = blks + 
-            if blks > INODE_DIRECT_COUNT { 1 } else { 0 } +
-            if blks > INODE_DIRECT_COUNT + INODE_INDIRECT_COUNT { 
-                (blks - INODE_DIRECT_COUNT - INODE_INDIRECT_COUNT + INODE_INDIRECT_COUNT - 1) / INODE_INDIRECT_COUNT + 1
-            } else { 0 };
-        total as u32
-    }
-    pub fn blocks_needed(&self, new_size: u32) -> u32 {
-        assert!(new_size >= self.size);
-        Self::total_blocks(new_size) - Self::total_blocks(self.size)
+    // largest size the direct and indirect blocks can map
+    pub fn max_size() -> u32 {
+        (INODE_MAX_COUNT * BLOCK_SIZE) as u32
     }
 
+    // the block backing block `inner_id` of the file, filling a hole there and
+    // any missing index blocks above it with blocks from `alloc`
+    pub fn map_block(
+        &mut self,
+        inner_id: u32,
+        alloc: &mut dyn FnMut() -> EzResult<u32>,
+        block_dev: &Arc<dyn BlockDev>
+    ) -> EzResult<u32> {
+        let (level, pos) = Self::locate(inner_id as usize);
+        let root = if level == 0 { &mut self.direct[pos] } else { &mut self.indirect[level - 1] };
+        if *root == 0 {
+            *root = alloc()?;
+            self.blocks += 1;
+        }
+        let mut block_id = *root;
+        for depth in (0..level).rev() {
+            let idx = pos / indirect_span(depth) % INODE_INDIRECT_COUNT;
+            let indir_blk = get_block_cache(block_id as usize, Arc::clone(block_dev))?;
+            let mut child = indir_blk.lock().read(0, |indir_blk: &IndirectBlock| indir_blk[idx]);
+            if child == 0 {
+                // not under the lock of the block, `alloc` may wait for `fs`
+                child = alloc()?;
+                indir_blk.lock().modify(0, |indir_blk: &mut IndirectBlock| indir_blk[idx] = child);
+                self.blocks += 1;
+            }
+            block_id = child;
+        }
+        Ok(block_id)
+    }
 
-
-
-    pub fn increase_size(&mut self, new_size: u32, new_blocks: Vec<u32>, block_dev: &Arc<dyn BlockDev>) {
-        let mut curr_blks = self.data_blocks();
-        let mut goal_blks = Self::_data_blocks(new_size);
-        let mut iter_blks = new_blocks.into_iter();
-        assert!(goal_blks <= INODE_MAX_COUNT as u32, "new_size too large");
-        // direct
-        while curr_blks < min(goal_blks, INODE_DIRECT_COUNT as u32) {
-            self.direct[curr_blks as usize] = iter_blks.next()
-                .expect("Not enough block id for increase_size");
-            curr_blks += 1;
+    // one past the last block of the file that is not a hole
+    pub fn mapped_blocks(&self, block_dev: &Arc<dyn BlockDev>) -> EzResult<u32> {
+        let mut base = INODE_DIRECT_COUNT + (1..=INODE_INDIRECT_LEVELS).map(indirect_span).sum::<usize>();
+        for level in (1..=INODE_INDIRECT_LEVELS).rev() {
+            base -= indirect_span(level);
+            if let Some(end) = Self::tree_end(self.indirect[level - 1], level, block_dev)? {
+                return Ok(min((base + end) as u32, self.data_blocks()));
+            }
         }
-        if goal_blks <= INODE_DIRECT_COUNT as u32 {
-            self.size = new_size;
-            return;
+        let end = self.direct.iter().rposition(|block_id| *block_id != 0).map_or(0, |pos| pos + 1);
+        Ok(min(end as u32, self.data_blocks()))
+    }
+    // one past the last data block under index block `block_id`, if there is one
+    fn tree_end(block_id: u32, depth: usize, block_dev: &Arc<dyn BlockDev>) -> EzResult<Option<usize>> {
+        if block_id == 0 {
+            return Ok(None);
         }
-        // indirect 1
-        self.indirect[0] = iter_blks.next()
-            .expect("Not enough block id for increase_size");
-        curr_blks -= INODE_DIRECT_COUNT as u32;
-        goal_blks -= INODE_DIRECT_COUNT as u32;
-        get_block_cache(self.indirect[0] as usize, Arc::clone(block_dev))
+        let indir_blk = get_block_cache(block_id as usize, Arc::clone(block_dev))?
             .lock()
-            .modify(0, |indir_blk: &mut IndirectBlock| {
-                while curr_blks < min(goal_blks, INODE_INDIRECT_COUNT as u32) {
-                    indir_blk[curr_blks as usize] = iter_blks.next()
-                        .expect("Not enough block id for increase_size");
-                    curr_blks += 1;
-                }
-            });
-        if goal_blks <= INODE_INDIRECT_COUNT as u32 {
-            self.size = new_size;
-            return;
+            .read(0, |indir_blk: &IndirectBlock| *indir_blk);
+        for (idx, child) in indir_blk.iter().enumerate().rev() {
+            let end = if depth == 1 {
+                (*child != 0).then_some(1)
+            } else {
+                Self::tree_end(*child, depth - 1, block_dev)?
+            };
+            if let Some(end) = end {
+                return Ok(Some(idx * indirect_span(depth - 1) + end));
+            }
         }
-        // indirect 2
-        self.indirect[1] = iter_blks.next()
-            .expect("Not enough block id for increase_size");
-        curr_blks -= INODE_INDIRECT_COUNT as u32;
-        goal_blks -= INODE_INDIRECT_COUNT as u32;
-        let mut a0 = curr_blks as usize / INODE_INDIRECT_COUNT;
-        let a1 = goal_blks as usize / INODE_INDIRECT_COUNT;
-        let b1 = goal_blks as usize % INODE_INDIRECT_COUNT;
-        get_block_cache(self.indirect[1] as usize, Arc::clone(block_dev))
-            .lock()
-            .modify(0, |indir2:&mut IndirectBlock| {
-                while a0 <= a1 {
-                    if a0 == a1 && b1 == 0 { break; }
-                    indir2[a0] = iter_blks.next()
-                        .expect("Not enough block id for increase_size");
-                    // second layer
-                    get_block_cache(indir2[a0] as usize, Arc::clone(block_dev))
-                        .lock()
-                        .modify(0, |indir: &mut IndirectBlock| {
-                            for b0 in 0..(
-                                if a0 < a1 { INODE_INDIRECT_COUNT } else { b1 }
-                            ) {
-                                indir[b0] = iter_blks.next()
-                                    .expect("Not enough block id for increase_size");
-                            }
-                        });
-                    a0 += 1;
-                }
-            });
-        self.size = new_size;
+        Ok(None)
     }
 
-
-
-    pub fn clear_size(&mut self, block_dev: &Arc<dyn BlockDev>) -> Vec<u32> {
+    // unmap the blocks past `new_size`, returning the data and index blocks
+    // that are no longer used; the rest of a cut block is zeroed, so it reads
+    // as zeros should the file grow again
+    pub fn decrease_size(&mut self, new_size: u32, block_dev: &Arc<dyn BlockDev>) -> EzResult<Vec<u32>> {
+        assert!(new_size <= self.size);
         let mut v: Vec<u32> = Vec::new();
-        let mut tot_blks = self.data_blocks() as usize;
-        let mut curr_blks = 0usize;
-        // direct
-        while curr_blks < min(tot_blks, INODE_DIRECT_COUNT) {
-            v.push(self.direct[curr_blks]);
-            self.direct[curr_blks] = 0;
-            curr_blks += 1;
+        let keep = ((new_size as usize + BLOCK_SIZE - 1) / BLOCK_SIZE).min(self.data_blocks() as usize);
+        if new_size as usize % BLOCK_SIZE != 0 {
+            let block_id = self.get_block_id(keep as u32 - 1, block_dev)?;
+            if block_id != 0 {
+                get_block_cache(block_id as usize, Arc::clone(block_dev))?
+                    .lock()
+                    .modify(0, |blk: &mut DataBlock| blk[new_size as usize % BLOCK_SIZE..].fill(0));
+            }
         }
-        if tot_blks <= INODE_DIRECT_COUNT {
-            self.size = 0;
-            return v;
+        for block_id in self.direct.iter_mut().skip(keep) {
+            if *block_id != 0 {
+                v.push(*block_id);
+                *block_id = 0;
+            }
         }
-        // indirect 1
-        v.push(self.indirect[0]);
-        tot_blks -= INODE_DIRECT_COUNT;
-        curr_blks = 0;
-        get_block_cache(self.indirect[0] as usize, Arc::clone(block_dev))
-            .lock()
-            .modify(0, |indir: &mut IndirectBlock| {
-                while curr_blks < min(tot_blks, INODE_INDIRECT_COUNT) {
-                    v.push(indir[curr_blks]);
-                    curr_blks += 1;
+        let mut base = INODE_DIRECT_COUNT;
+        for level in 1..=INODE_INDIRECT_LEVELS {
+            let tree_keep = keep.saturating_sub(base);
+            if tree_keep < indirect_span(level) {
+                Self::free_tree(self.indirect[level - 1], level, tree_keep, &mut v, block_dev)?;
+                if tree_keep == 0 {
+                    self.indirect[level - 1] = 0;
                 }
-            });
-        self.indirect[0] = 0;
-        if tot_blks <= INODE_INDIRECT_COUNT {
-            self.size = 0;
-            return v;
+            }
+            base += indirect_span(level);
         }
-        // indirect 2
-        v.push(self.indirect[1]);
-        tot_blks -= INODE_INDIRECT_COUNT;
-        let a1 = tot_blks / INODE_INDIRECT_COUNT;
-        let b1 = tot_blks % INODE_INDIRECT_COUNT;
-        get_block_cache(self.indirect[1] as usize, Arc::clone(block_dev))
-            .lock()
-            .modify(0, |indir2: &mut IndirectBlock| {
-                for x in indir2.iter_mut().take(a1) {
-                    v.push(*x);
-                    get_block_cache(*x as usize, Arc::clone(block_dev))
-                        .lock()
-                        .modify(0, |indir: &mut IndirectBlock| {
-                            for x in indir.iter() {
-                                v.push(*x);
-                            }
-                        })
-                }
-                // recycle last l1 indirect block
-                if b1 > 0 {
-                    v.push(indir2[a1]);
-                    get_block_cache(indir2[a1] as usize, Arc::clone(block_dev))
-                        .lock()
-                        .modify(0, |indir: &mut IndirectBlock| {
-                            for x in indir.iter().take(b1) {
-                                v.push(*x);
-                            }
-                        })
+        self.blocks -= v.len() as u32;
+        self.size = new_size;
+        Ok(v)
+    }
+    // collect what index block `block_id`, `depth` layers above the data,
+    // maps from position `keep` on, and the block itself if `keep` is 0
+    fn free_tree(block_id: u32, depth: usize, keep: usize, v: &mut Vec<u32>, block_dev: &Arc<dyn BlockDev>) -> EzResult<()> {
+        if block_id == 0 {
+            return Ok(());
+        }
+        let span = indirect_span(depth - 1);
+        let indir_blk = get_block_cache(block_id as usize, Arc::clone(block_dev))?;
+        let children = indir_blk.lock().read(0, |indir_blk: &IndirectBlock| *indir_blk);
+        for (idx, child) in children.iter().enumerate().skip(keep / span) {
+            let child_keep = keep.saturating_sub(idx * span);
+            if depth == 1 {
+                if *child != 0 {
+                    v.push(*child);
                 }
-            });
-        self.indirect[1] = 0;
-        self.size = 0;
-        v
+            } else {
+                Self::free_tree(*child, depth - 1, child_keep, v, block_dev)?;
+            }
+        }
+        if keep == 0 {
+            v.push(block_id);
+        } else {
+            // entries wholly past `keep` are gone, a partly kept child stays
+            let first_gone = (keep + span - 1) / span;
+            if children[first_gone..].iter().any(|child| *child != 0) {
+                indir_blk.lock().modify(0, |indir_blk: &mut IndirectBlock| indir_blk[first_gone..].fill(0));
+            }
+        }
+        Ok(())
     }
 
-    pub fn read_at(&self, offset: usize, buf: &mut [u8], block_dev: &Arc<dyn BlockDev>) -> usize {
-        let mut start = offset;
+    pub fn clear_size(&mut self, block_dev: &Arc<dyn BlockDev>) -> EzResult<Vec<u32>> {
+        self.decrease_size(0, block_dev)
+    }
+
+    pub fn read_at(&self, offset: usize, buf: &mut [u8], block_dev: &Arc<dyn BlockDev>) -> EzResult<usize> {
         let end = min(offset + buf.len(), self.size as usize);
-        if start >= end {
-            return 0;
+        if offset >= end {
+            return Ok(0);
         }
-        let mut start_blk = start / BLOCK_SIZE;
-        let mut read_size = 0usize;
-        loop {
-            let mut curr_blk_end = (start / BLOCK_SIZE + 1) * BLOCK_SIZE;
-            curr_blk_end = min(curr_blk_end, end);
-            let curr_blk_size = curr_blk_end - start;
-            let dst = &mut buf[read_size..read_size + curr_blk_size];
-            get_block_cache(
-                self.get_block_id(start_blk as u32, block_dev) as usize,
-                Arc::clone(block_dev))
-                .lock()
-                .read(0, |blk: &DataBlock| {
-                    let src = &blk[start % BLOCK_SIZE..start % BLOCK_SIZE + curr_blk_size];
-                    dst.copy_from_slice(src);
+        // whole blocks that are not cached are read straight into the buffer,
+        // so streaming a large file does not flush the cache; blocks that are
+        // consecutive on disk go to the device as one request; holes read as
+        // zeros
+        let mut run: Option<(usize, Range<usize>)> = None;
+        let mut start = offset;
+        while start < end {
+            let inner_id = start / BLOCK_SIZE;
+            let blk_end = min((inner_id + 1) * BLOCK_SIZE, end);
+            let dst = start - offset..blk_end - offset;
+            let block_id = self.get_block_id(inner_id as u32, block_dev)? as usize;
+            if block_id == 0 {
+                if let Some((first, range)) = run.take() {
+                    block_dev.read_blocks(first, &mut buf[range])?;
+                }
+                buf[dst].fill(0);
+                start = blk_end;
+                continue;
+            }
+            let cache = peek_block_cache(block_id, block_dev);
+            if cache.is_none() && dst.len() == BLOCK_SIZE {
+                match &mut run {
+                    Some((first, range)) if *first + range.len() / BLOCK_SIZE == block_id => range.end = dst.end,
+                    _ => {
+                        if let Some((first, range)) = run.replace((block_id, dst)) {
+                            block_dev.read_blocks(first, &mut buf[range])?;
+                        }
+                    }
+                }
+            } else {
+                if let Some((first, range)) = run.take() {
+                    block_dev.read_blocks(first, &mut buf[range])?;
+                }
+                let cache = match cache {
+                    Some(cache) => cache,
+                    None => {
+                        self.read_ahead(inner_id, block_id, block_dev)?;
+                        get_block_cache(block_id, Arc::clone(block_dev))?
+                    }
+                };
+                cache.lock().read(0, |blk: &DataBlock| {
+                    buf[dst].copy_from_slice(&blk[start % BLOCK_SIZE..start % BLOCK_SIZE + blk_end - start]);
                 });
-            read_size += curr_blk_size;
-            if curr_blk_end >= end {
-                break;
             }
-            start_blk += 1;
-            start += curr_blk_size;
+            start = blk_end;
+        }
+        if let Some((first, range)) = run {
+            block_dev.read_blocks(first, &mut buf[range])?;
+        }
+        Ok(end - offset)
+    }
+
+    // small reads that miss the cache pull in the blocks after them as well,
+    // as far as they are consecutive on disk
+    fn read_ahead(&self, inner_id: usize, block_id: usize, block_dev: &Arc<dyn BlockDev>) -> EzResult<()> {
+        let last = min(inner_id + READ_AHEAD_BLOCKS, self.data_blocks() as usize);
+        let mut count = 1;
+        while inner_id + count < last
+            && self.get_block_id((inner_id + count) as u32, block_dev)? as usize == block_id + count {
+            count += 1;
         }
-        read_size
+        read_ahead_block_caches(block_id, count, block_dev)
     }
 
-    pub fn write_at(&mut self, offset: usize, buf: &[u8], block_dev: &Arc<dyn BlockDev>) -> usize {
+    // the blocks written to must have been mapped with `map_block`
+    pub fn write_at(&mut self, offset: usize, buf: &[u8], block_dev: &Arc<dyn BlockDev>) -> EzResult<usize> {
         let mut start = offset;
         let end = min(offset + buf.len(), self.size as usize);
         assert!(start <= end);
@@ -288,9 +380,11 @@ impl DiskInode {
             let mut curr_blk_end = (start / BLOCK_SIZE + 1) * BLOCK_SIZE;
             curr_blk_end = min(curr_blk_end, end);
             let curr_blk_size = curr_blk_end - start;
-            get_block_cache(
-                self.get_block_id(start_blk as u32, block_dev) as usize,
-                Arc::clone(block_dev))
+            let block_id = self.get_block_id(start_blk as u32, block_dev)?;
+            if block_id == 0 {
+                return Err(EzFsError::Corrupted);
+            }
+            get_block_cache(block_id as usize, Arc::clone(block_dev))?
                 .lock()
                 .modify(0, |blk: &mut DataBlock| {
                     let src = &buf[write_size..write_size + curr_blk_size];
@@ -304,48 +398,6 @@ impl DiskInode {
             start_blk += 1;
             start += curr_blk_size;
         }
-        write_size
-    }
-}
-
-const FILENAME_LIM: usize = 27;
-pub const DIRENT_SIZE: usize = 32;
-#[repr(C)]
-pub struct DirEntry {
-    name: [u8; FILENAME_LIM + 1],   // +1 for \0
-    inode: u32
-}
-
-impl DirEntry {
-    pub fn new() -> Self {
-        Self {
-            name: [0u8; FILENAME_LIM + 1],
-            inode: 0
-        }
-    }
-    pub fn with_name_inode(name: &str, inode: u32) -> Self {
-        let mut bytes = [0u8; FILENAME_LIM + 1];
-        let bname = name.as_bytes();
-        bytes[..bname.len()].copy_from_slice(bname);
-        Self {
-            name: bytes,
-            inode
-        }
-    }
-
-    pub fn as_bytes(&self) -> &[u8] {
-        unsafe {
-            core::slice::from_raw_parts(self as *const _ as usize as *const u8, DIRENT_SIZE)
-        }
-    }
-    pub fn as_bytes_mut(&mut self) -> &mut [u8] {
-        unsafe {
-            core::slice::from_raw_parts_mut(self as *mut _ as usize as *mut u8, DIRENT_SIZE)
-        }
-    }
-
-    pub fn name(&self) -> &str {
-        CStr::from_bytes_until_nul(&self.name).unwrap().to_str().unwrap()
+        Ok(write_size)
     }
-    pub fn inode(&self) -> u32 { self.inode }
 }
@@ -2,15 +2,20 @@
 
 extern crate alloc;
 
+pub mod error;
 pub mod block_dev;
 pub mod block_cache;
 pub mod cache_man;
 pub mod layout;
 pub mod efs;
+pub mod dir;
 pub mod vfs;
+pub mod fsck;
+pub mod sync;
 mod bitmap;
 
 pub use block_dev::*;
+pub use error::{EzFsError, EzResult};
 pub use efs::EzFileSys;
 
 pub const BLOCK_SIZE: usize = 512;
\ No newline at end of file
@@ -0,0 +1,27 @@
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use spin::RelaxStrategy;
+
+// called by a thread waiting for a lock of easyfs, 0 to spin
+static RELAX_HOOK: AtomicUsize = AtomicUsize::new(0);
+
+// a lock may be held across device I/O, so a kernel whose block device
+// sleeps should let its waiters yield to other threads instead of spinning
+pub fn set_relax(hook: fn()) {
+    RELAX_HOOK.store(hook as usize, Ordering::Release);
+}
+
+pub struct Relax;
+impl RelaxStrategy for Relax {
+    fn relax() {
+        match RELAX_HOOK.load(Ordering::Acquire) {
+            0 => core::hint::spin_loop(),
+            hook => unsafe { core::mem::transmute::<usize, fn()>(hook)() }
+        }
+    }
+}
+
+pub type Mutex<T> = spin::mutex::Mutex<T, Relax>;
+pub type MutexGuard<'a, T> = spin::mutex::MutexGuard<'a, T>;
+pub type RwLock<T> = spin::rwlock::RwLock<T, Relax>;
+pub type RwLockWriteGuard<'a, T> = spin::rwlock::RwLockWriteGuard<'a, T, Relax>;
@@ -1,169 +1,789 @@
 
-use alloc::{string::String, sync::Arc, vec::Vec};
-use spin::{Mutex, MutexGuard};
+use alloc::{string::String, sync::{Arc, Weak}, vec, vec::Vec};
+use hashbrown::{HashMap, HashSet};
+use lazy_static::lazy_static;
 
-use crate::{cache_man::{get_block_cache, sync_block_cache}, efs::EzFileSys, layout::{DirEntry, DiskInode, DiskInodeType, DIRENT_SIZE}, BlockDev};
+use crate::{cache_man::{dev_id, get_block_cache}, dir::{self, DirRecord, NAME_MAX}, efs::{inode_pos, EzFileSys, Journal, StatFs, Tx}, layout::{DiskInode, DiskInodeType, JOURNAL_LOG_BLOCKS}, sync::{Mutex, RwLock, RwLockWriteGuard}, BlockDev, EzFsError, EzResult, BLOCK_SIZE};
 
+pub const S_IFMT: u32 = 0o170000;
+pub const S_IFIFO: u32 = 0o010000;
+pub const S_IFCHR: u32 = 0o020000;
+pub const S_IFDIR: u32 = 0o040000;
+pub const S_IFREG: u32 = 0o100000;
+pub const S_IFLNK: u32 = 0o120000;
+pub const S_IRUSR: u32 = 0o400;
+pub const S_IWUSR: u32 = 0o200;
+pub const S_IXUSR: u32 = 0o100;
+
+#[repr(C)]
+#[derive(Clone, Copy, Default, Debug)]
+pub struct Stat {
+    pub dev: u64,
+    pub ino: u64,
+    pub mode: u32,
+    pub nlink: u32,
+    pub size: u64,
+    // in units of 512 bytes
+    pub blocks: u64,
+    // milliseconds
+    pub atime: u64,
+    pub mtime: u64,
+    pub ctime: u64
+}
+
+const WRITE_CHUNK: usize = 8 * BLOCK_SIZE;
+// blocks freed at a time when shrinking a file
+const SHRINK_STEP: usize = 8;
+// longest symbolic link target, with room for a nul
+pub const PATH_MAX: usize = 4096;
+
+pub struct DirItem {
+    pub inode_id: u32,
+    // S_IF* bits of the entry
+    pub mode: u32,
+    pub name: String
+}
+
+// Readers of an inode share its lock. A writer takes the locks of the inodes
+// it changes, in order of inode id, then the journal to run its transaction,
+// holding them until the transaction is committed or dropped; `fs` is only
+// taken to allocate or free, so lookups and reads elsewhere carry on while a
+// transaction is written out.
 pub struct VirtInode {
+    inode_id: u32,
     block_id: usize,
     block_offset: usize,
+    inode_start: u32,
+    lock: Arc<RwLock<()>>,
     fs: Arc<Mutex<EzFileSys>>,
+    journal: Arc<Mutex<Journal>>,
     block_dev: Arc<dyn BlockDev>
 }
 
+// the lock of every inode in use, by device and inode id; each VirtInode of
+// an inode holds it, so it also counts the references to the inode
+type InodeLocks = HashMap<(usize, u32), Weak<RwLock<()>>>;
+
+lazy_static! {
+    static ref INODE_LOCKS: Mutex<InodeLocks> = Mutex::new(HashMap::new());
+    // inodes that lost their last link while open, freed with their last reference
+    static ref ORPHANS: Mutex<HashSet<(usize, u32)>> = Mutex::new(HashSet::new());
+}
+
+fn inode_lock(inode_id: u32, block_dev: &Arc<dyn BlockDev>) -> Arc<RwLock<()>> {
+    let key = (dev_id(block_dev), inode_id);
+    let mut locks = INODE_LOCKS.lock();
+    if let Some(lock) = locks.get(&key).and_then(Weak::upgrade) {
+        return lock;
+    }
+    locks.retain(|_, lock| lock.strong_count() > 0);
+    let lock = Arc::new(RwLock::new(()));
+    locks.insert(key, Arc::downgrade(&lock));
+    lock
+}
+
+// inodes of `block_dev` without links, kept for those that still have them open
+pub(crate) fn open_orphans(block_dev: &Arc<dyn BlockDev>) -> Vec<u32> {
+    let dev = dev_id(block_dev);
+    ORPHANS.lock().iter().filter(|(d, _)| *d == dev).map(|(_, inode_id)| *inode_id).collect()
+}
+
+// write locks of `inodes`, each taken once, lowest inode id first
+fn lock_all<'a>(inodes: &[&'a VirtInode]) -> Vec<RwLockWriteGuard<'a, ()>> {
+    let mut inodes = inodes.to_vec();
+    inodes.sort_by_key(|inode| inode.inode_id);
+    inodes.dedup_by_key(|inode| inode.inode_id);
+    inodes.iter().map(|inode| inode.lock.write()).collect()
+}
+
 impl VirtInode {
     pub fn new(
-        block_id: u32,
-        block_offset: usize,
+        inode_id: u32,
+        inode_start: u32,
         fs: Arc<Mutex<EzFileSys>>,
+        journal: Arc<Mutex<Journal>>,
         block_dev: Arc<dyn BlockDev>
     ) -> Self {
+        let (block_id, block_offset) = inode_pos(inode_start, inode_id);
         Self {
+            inode_id,
             block_id: block_id as usize,
             block_offset,
+            inode_start,
+            lock: inode_lock(inode_id, &block_dev),
             fs,
+            journal,
             block_dev
         }
     }
-    fn read_disk_inode<V>(&self, f: impl FnOnce(&DiskInode) -> V) -> V {
-        get_block_cache(self.block_id as usize, Arc::clone(&self.block_dev))
+    // `f` gets a copy, so the block of inodes is not held while it goes to
+    // the device
+    fn read_disk_inode<V>(&self, f: impl FnOnce(&DiskInode) -> V) -> EzResult<V> {
+        let inode = get_block_cache(self.block_id, Arc::clone(&self.block_dev))?
             .lock()
-            .read(self.block_offset, f)
+            .read(self.block_offset, |inode: &DiskInode| inode.clone());
+        Ok(f(&inode))
     }
-    fn modify_disk_inode<V>(&self, f: impl FnOnce(&mut DiskInode) -> V) -> V {
-        get_block_cache(self.block_id as usize, Arc::clone(&self.block_dev))
+    fn modify_disk_inode<V>(&self, f: impl FnOnce(&mut DiskInode) -> V) -> EzResult<V> {
+        let mut inode = self.read_disk_inode(|inode| inode.clone())?;
+        let v = f(&mut inode);
+        get_block_cache(self.block_id, Arc::clone(&self.block_dev))?
             .lock()
-            .modify(self.block_offset, f)
-    }
-    
-    fn find_inode_id(&self, name: &str, disk_inode: &DiskInode) -> Option<u32> {
-        assert!(disk_inode.is_dir());
-        let file_cnt = (disk_inode.size as usize) / DIRENT_SIZE;
-        let mut dirent = DirEntry::new();
-        for i in 0..file_cnt {
-            assert_eq!(
-                disk_inode.read_at(DIRENT_SIZE * i, dirent.as_bytes_mut(), &self.block_dev),
-                DIRENT_SIZE
-            );
-            if dirent.name() == name {
-                return Some(dirent.inode());
+            .modify(self.block_offset, |disk_inode: &mut DiskInode| *disk_inode = inode);
+        Ok(v)
+    }
+    fn vinode(&self, inode_id: u32) -> Arc<VirtInode> {
+        Arc::new(Self::new(
+            inode_id,
+            self.inode_start,
+            self.fs.clone(),
+            self.journal.clone(),
+            self.block_dev.clone()
+        ))
+    }
+
+    pub fn inode_id(&self) -> u32 { self.inode_id }
+    pub fn is_dir(&self) -> EzResult<bool> {
+        let _inode = self.lock.read();
+        self.read_disk_inode(|inode| inode.is_dir())
+    }
+    pub fn is_file(&self) -> EzResult<bool> {
+        let _inode = self.lock.read();
+        self.read_disk_inode(|inode| inode.is_file())
+    }
+    pub fn is_symlink(&self) -> EzResult<bool> {
+        let _inode = self.lock.read();
+        self.read_disk_inode(|inode| inode.is_symlink())
+    }
+
+    // usage of the file system holding this inode
+    pub fn statfs(&self) -> StatFs {
+        self.fs.lock().statfs()
+    }
+
+    pub fn stat(&self) -> EzResult<Stat> {
+        let _inode = self.lock.read();
+        self.read_disk_inode(|inode| Stat {
+            dev: 0,
+            ino: self.inode_id as u64,
+            mode: file_type(inode) | inode.mode,
+            nlink: inode.nlink,
+            size: inode.size as u64,
+            blocks: (inode.blocks as usize * BLOCK_SIZE / 512) as u64,
+            atime: inode.atime,
+            mtime: inode.mtime,
+            ctime: inode.ctime
+        })
+    }
+
+    pub fn set_mode(&self, mode: u32) -> EzResult<()> {
+        let _inode = self.lock.write();
+        self.journal.lock().transaction(&self.fs, |tx| {
+            self.modify_disk_inode(|inode| {
+                inode.mode = mode & 0o7777;
+                inode.ctime = tx.now();
+            })
+        })
+    }
+
+    // (byte offset, inode id) of the entry named `name`
+    fn find_dirent(&self, name: &str, disk_inode: &DiskInode) -> EzResult<Option<(usize, u32)>> {
+        if !disk_inode.is_dir() {
+            return Err(EzFsError::NotDir);
+        }
+        dir::lookup(disk_inode, name, &self.block_dev)
+    }
+
+    // the first entry at or after byte `pos` of a directory
+    fn next_dirent(&self, pos: usize, disk_inode: &DiskInode) -> EzResult<Option<(DirRecord, String)>> {
+        if !disk_inode.is_dir() {
+            return Err(EzFsError::NotDir);
+        }
+        match dir::next_entry(disk_inode, pos, &self.block_dev)? {
+            Some(rec) => {
+                let name = String::from_utf8(rec.name.clone()).map_err(|_| EzFsError::Corrupted)?;
+                Ok(Some((rec, name)))
             }
+            None => Ok(None)
         }
-        None
     }
 
-    pub fn find(&self, name: &str) -> Option<Arc<VirtInode>> {
-        let fs = self.fs.lock();
+    fn find_inode_id(&self, name: &str, disk_inode: &DiskInode) -> EzResult<Option<u32>> {
+        Ok(self.find_dirent(name, disk_inode)?.map(|(_, inode_id)| inode_id))
+    }
+
+    pub fn find(&self, name: &str) -> EzResult<Arc<VirtInode>> {
+        let _inode = self.lock.read();
         self.read_disk_inode(|inode: &DiskInode| {
-            self.find_inode_id(name, inode).map(|inode_id| {
-                let (block_id, block_offset) = fs.inode_pos(inode_id);
-                Arc::new(Self::new(
-                    block_id,
-                    block_offset,
-                    self.fs.clone(),
-                    self.block_dev.clone()
-                ))
-            })
-        })
+            self.find_inode_id(name, inode)?
+                .map(|inode_id| self.vinode(inode_id))
+                .ok_or(EzFsError::NotFound)
+        })?
+    }
+
+    // resolve a '/'-separated path relative to this inode
+    pub fn lookup_path(self: &Arc<Self>, path: &str) -> EzResult<Arc<VirtInode>> {
+        path.split('/')
+            .filter(|name| !name.is_empty())
+            .try_fold(Arc::clone(self), |inode, name| inode.find(name))
+    }
+
+    // first entry at or after byte `pos`, and the position following it
+    pub fn read_dir(&self, pos: usize) -> EzResult<Option<(DirItem, usize)>> {
+        let _inode = self.lock.read();
+        let found = self.read_disk_inode(|inode| self.next_dirent(pos, inode))??;
+        let Some((DirRecord { inode: inode_id, next, .. }, name)) = found else {
+            return Ok(None);
+        };
+        // the type of an inode never changes, its lock is not needed
+        let (block_id, block_offset) = inode_pos(self.inode_start, inode_id);
+        let mode = get_block_cache(block_id as usize, Arc::clone(&self.block_dev))?
+            .lock()
+            .read(block_offset, file_type);
+        Ok(Some((DirItem { inode_id, mode, name }, next)))
     }
 
-    pub fn ls(&self) -> Vec<String> {
-        let _fs = self.fs.lock();
+    pub fn ls(&self) -> EzResult<Vec<String>> {
+        let _inode = self.lock.read();
         self.read_disk_inode(|inode: &DiskInode| {
-            let file_cnt = (inode.size as usize) / DIRENT_SIZE;
             let mut v: Vec<String> = Vec::new();
-            for i in 0..file_cnt {
-                let mut dirent = DirEntry::new();
-                assert_eq!(
-                    inode.read_at(i * DIRENT_SIZE, dirent.as_bytes_mut(), &self.block_dev),
-                    DIRENT_SIZE
-                );
-                v.push(String::from(dirent.name()));
+            let mut pos = 0;
+            while let Some((rec, name)) = self.next_dirent(pos, inode)? {
+                v.push(name);
+                pos = rec.next;
             }
-            v
-        })
+            Ok(v)
+        })?
     }
 
-    fn increase_size(
+    // back the bytes `start..end` with blocks, filling whatever holes there
+    // are; the size is left alone
+    fn map_range(
         &self,
-        new_size: u32,
+        start: usize,
+        end: usize,
         inode: &mut DiskInode,
-        fs: &mut MutexGuard<EzFileSys>
-    ) {
-        if new_size < inode.size {
-            return;
+        tx: &mut Tx
+    ) -> EzResult<()> {
+        if end > DiskInode::max_size() as usize {
+            return Err(EzFsError::TooLarge);
+        }
+        if start >= end {
+            return Ok(());
         }
-        let blk_needed = inode.blocks_needed(new_size);
-        let mut v: Vec<u32> = Vec::new();
-        for _ in 0..blk_needed {
-            v.push(fs.alloc_data());
-        }
-        assert!(v.len() == blk_needed as usize);
-        inode.increase_size(new_size, v, &self.block_dev);
-    }
-
-    pub fn create(&self, name: &str) -> Option<Arc<VirtInode>> {
-        let mut fs = self.fs.lock();
-        if self.read_disk_inode(|inode| {
-            assert!(inode.is_dir());
-            self.find_inode_id(name, inode)
-        }).is_some() {
-            None
-        } else {
-            let new_inode_id = fs.alloc_inode();
-            let (new_inode_block_id, new_inode_offset) = fs.inode_pos(new_inode_id);
-            get_block_cache(new_inode_block_id as usize, Arc::clone(&self.block_dev))
-                .lock().modify(new_inode_offset, |inode: &mut DiskInode| {
-                    inode.init(DiskInodeType::File)
-                });
+        let first = (start / BLOCK_SIZE) as u32;
+        // carry on after the block before, so the file stays contiguous
+        let mut goal = match first {
+            0 => 0,
+            _ => inode.get_block_id(first - 1, &self.block_dev)?
+        };
+        if goal == 0 {
+            goal = tx.data_goal(self.inode_id);
+        }
+        for inner_id in first..((end + BLOCK_SIZE - 1) / BLOCK_SIZE) as u32 {
+            let block_id = inode.map_block(inner_id, &mut || {
+                let block_id = tx.alloc_data_near(goal)?;
+                goal = block_id + 1;
+                Ok(block_id)
+            }, &self.block_dev)?;
+            goal = block_id + 1;
+        }
+        Ok(())
+    }
+
+    // free the blocks past `new_size` as far as the journal has room for them
+    // in the current transaction, true once all of them are gone
+    fn shrink(&self, new_size: u32, tx: &mut Tx) -> EzResult<bool> {
+        loop {
+            let size = self.read_disk_inode(|inode| inode.size)?;
+            if size <= new_size {
+                return Ok(true);
+            }
+            // every freed block may dirty a bitmap block of its own
+            if tx.dirty_blocks() + 2 * SHRINK_STEP > JOURNAL_LOG_BLOCKS {
+                return Ok(false);
+            }
             self.modify_disk_inode(|inode| {
-                let file_cnt = (inode.size as usize) / DIRENT_SIZE;
-                let new_sz = (file_cnt + 1) * DIRENT_SIZE;
-                self.increase_size(new_sz as u32, inode, &mut fs);
-                let dirent = DirEntry::with_name_inode(name, new_inode_id);
-                inode.write_at(
-                    file_cnt * DIRENT_SIZE, 
-                    dirent.as_bytes(), 
-                    &self.block_dev
-                )
-            });
-            sync_block_cache();
-            Some(Arc::new(Self::new(
-                new_inode_block_id,
-                new_inode_offset, 
-                self.fs.clone(),
-                self.block_dev.clone()
-            )))
-        }
-    }
-
-    pub fn clear(&self) {
-        let mut fs = self.fs.lock();
-        self.modify_disk_inode(|inode| {
-            let sz = inode.size;
-            let data_dealloc = inode.clear_size(&self.block_dev);
-            assert!(data_dealloc.len() == DiskInode::total_blocks(sz) as usize);
-            for block in data_dealloc.into_iter() {
-                fs.dealloc_data(block)
+                // a hole at the end is cut off at once
+                let mapped = inode.mapped_blocks(&self.block_dev)? * BLOCK_SIZE as u32;
+                let size = size.min(mapped.max(new_size));
+                let step_size = new_size.max(size.saturating_sub((SHRINK_STEP * BLOCK_SIZE) as u32));
+                for block in inode.decrease_size(step_size, &self.block_dev)? {
+                    tx.dealloc_data(block)?;
+                }
+                Ok(())
+            })??;
+        }
+    }
+
+    // shrink to `new_size` over as many transactions as it takes, the first
+    // of them already running
+    fn shrink_all(&self, new_size: u32, tx: &mut Tx) -> EzResult<()> {
+        while !self.shrink(new_size, tx)? {
+            tx.commit()?;
+        }
+        Ok(())
+    }
+
+    // the directory grows at its end, next to its last block
+    fn add_dirent(
+        &self,
+        name: &str,
+        inode_id: u32,
+        disk_inode: &mut DiskInode,
+        tx: &mut Tx
+    ) -> EzResult<()> {
+        let mut goal = disk_inode.get_block_id(disk_inode.data_blocks() - 1, &self.block_dev)? + 1;
+        dir::insert(disk_inode, name, inode_id, &mut || {
+            let block_id = tx.alloc_data_near(goal)?;
+            goal = block_id + 1;
+            Ok(block_id)
+        }, &self.block_dev)
+    }
+
+    fn is_empty_dir(&self, disk_inode: &DiskInode) -> EzResult<bool> {
+        let mut pos = 0;
+        while let Some((rec, name)) = self.next_dirent(pos, disk_inode)? {
+            if name != "." && name != ".." {
+                return Ok(false);
+            }
+            pos = rec.next;
+        }
+        Ok(true)
+    }
+
+    // fails unless this is a directory without an entry `name`
+    fn check_new_name(&self, name: &str) -> EzResult<()> {
+        if !is_valid_name(name) {
+            return Err(EzFsError::InvalidName);
+        }
+        if name.len() > NAME_MAX {
+            return Err(EzFsError::NameTooLong);
+        }
+        self.check_linked()?;
+        match self.read_disk_inode(|inode| self.find_inode_id(name, inode))?? {
+            Some(_) => Err(EzFsError::Exists),
+            None => Ok(())
+        }
+    }
+
+    // a directory removed while open takes no new entries
+    fn check_linked(&self) -> EzResult<()> {
+        match self.read_disk_inode(|inode| inode.nlink)? {
+            0 => Err(EzFsError::NotFound),
+            _ => Ok(())
+        }
+    }
+
+    // an inode that lost its last link is freed at once, unless it is still
+    // open; then the last reference to it frees it as it goes
+    fn release(&self, tx: &mut Tx) -> EzResult<()> {
+        if Arc::strong_count(&self.lock) > 1 {
+            ORPHANS.lock().insert((dev_id(&self.block_dev), self.inode_id));
+            return Ok(());
+        }
+        self.free(tx)
+    }
+
+    fn free(&self, tx: &mut Tx) -> EzResult<()> {
+        ORPHANS.lock().remove(&(dev_id(&self.block_dev), self.inode_id));
+        // a crash part way leaves an unlinked inode, freed at the next mount
+        self.shrink_all(0, tx)?;
+        tx.dealloc_inode(self.inode_id)
+    }
+
+    // free an inode left without links, like one found at mount
+    pub(crate) fn free_unlinked(&self) -> EzResult<()> {
+        let _inode = self.lock.write();
+        self.journal.lock().transaction(&self.fs, |tx| self.free(tx))
+    }
+
+    // a new inode named `name` holding `data`
+    fn create_inode(&self, name: &str, ty_inode: DiskInodeType, data: &[u8]) -> EzResult<Arc<VirtInode>> {
+        // no one can reach the new inode before the directory is unlocked
+        let _dir = self.lock.write();
+        self.journal.lock().transaction(&self.fs, |tx| {
+            self.check_new_name(name)?;
+            let new_inode_id = tx.alloc_inode()?;
+            let new_inode = self.vinode(new_inode_id);
+            let dirent_blk = if ty_inode == DiskInodeType::Dir {
+                tx.alloc_data_near(tx.data_goal(new_inode_id))?
+            } else {
+                0
+            };
+            new_inode.modify_disk_inode(|inode| {
+                inode.init(ty_inode, tx.now());
+                if inode.is_dir() {
+                    dir::init(inode, new_inode_id, self.inode_id, dirent_blk, &self.block_dev)?;
+                }
+                Ok(())
+            })??;
+            self.modify_disk_inode(|inode| {
+                self.add_dirent(name, new_inode_id, inode, tx)?;
+                // ".." of the new directory
+                if ty_inode == DiskInodeType::Dir {
+                    inode.nlink += 1;
+                }
+                inode.mtime = tx.now();
+                inode.ctime = inode.mtime;
+                Ok(())
+            })??;
+            if !data.is_empty() {
+                new_inode.modify_disk_inode(|inode| {
+                    new_inode.map_range(0, data.len(), inode, tx)?;
+                    inode.size = data.len() as u32;
+                    inode.write_at(0, data, &self.block_dev)
+                })??;
             }
-        });
-        sync_block_cache();
+            Ok(new_inode)
+        })
+    }
+
+    pub fn create(&self, name: &str) -> EzResult<Arc<VirtInode>> {
+        self.create_inode(name, DiskInodeType::File, &[])
     }
 
-    pub fn read_at(&self, offset: usize, buf: &mut [u8]) -> usize {
-        let _fs = self.fs.lock();
+    pub fn mkdir(&self, name: &str) -> EzResult<Arc<VirtInode>> {
+        self.create_inode(name, DiskInodeType::Dir, &[])
+    }
+
+    // a symbolic link `name` to `target`, which need not exist
+    pub fn symlink(&self, name: &str, target: &str) -> EzResult<Arc<VirtInode>> {
+        if target.is_empty() || target.contains('\0') {
+            return Err(EzFsError::InvalidName);
+        }
+        if target.len() >= PATH_MAX {
+            return Err(EzFsError::NameTooLong);
+        }
+        self.create_inode(name, DiskInodeType::Symlink, target.as_bytes())
+    }
+
+    // the target of a symbolic link
+    pub fn readlink(&self) -> EzResult<String> {
+        let _inode = self.lock.read();
         self.read_disk_inode(|inode| {
-            inode.read_at(offset, buf, &self.block_dev)
+            if !inode.is_symlink() {
+                return Err(EzFsError::InvalidName);
+            }
+            let mut target = vec![0u8; inode.size as usize];
+            inode.read_at(0, &mut target, &self.block_dev)?;
+            String::from_utf8(target).map_err(|_| EzFsError::Corrupted)
+        })?
+    }
+
+    pub fn link(&self, name: &str, target: &VirtInode) -> EzResult<()> {
+        let _locks = lock_all(&[self, target]);
+        self.journal.lock().transaction(&self.fs, |tx| {
+            self.check_new_name(name)?;
+            // no hard links to directories
+            target.modify_disk_inode(|inode| {
+                if inode.is_dir() {
+                    return Err(EzFsError::IsDir);
+                }
+                inode.nlink += 1;
+                inode.ctime = tx.now();
+                Ok(())
+            })??;
+            self.modify_disk_inode(|inode| {
+                self.add_dirent(name, target.inode_id, inode, tx)?;
+                inode.mtime = tx.now();
+                inode.ctime = inode.mtime;
+                Ok(())
+            })?
+        })
+    }
+
+    // drop the entry `name`; the inode is freed with its last link
+    fn remove(&self, name: &str, is_dir: bool) -> EzResult<()> {
+        loop {
+            let victim = self.find(name)?;
+            let inode_id = victim.inode_id;
+            let _locks = lock_all(&[self, &victim]);
+            // the entry may have changed before the directory was locked again
+            let (offset, found) = self.read_disk_inode(|dir| self.find_dirent(name, dir))??
+                .ok_or(EzFsError::NotFound)?;
+            if found != inode_id {
+                continue;
+            }
+            return self.journal.lock().transaction(&self.fs, |tx| {
+                let freed = victim.modify_disk_inode(|inode| {
+                    if is_dir {
+                        if !inode.is_dir() {
+                            return Err(EzFsError::NotDir);
+                        }
+                        if !self.is_empty_dir(inode)? {
+                            return Err(EzFsError::NotEmpty);
+                        }
+                        inode.nlink = 0;
+                    } else {
+                        if inode.is_dir() {
+                            return Err(EzFsError::IsDir);
+                        }
+                        inode.nlink -= 1;
+                    }
+                    inode.ctime = tx.now();
+                    Ok(inode.nlink == 0)
+                })??;
+                self.modify_disk_inode(|dir| -> EzResult<()> {
+                    dir::remove(dir, offset, &self.block_dev)?;
+                    if is_dir {
+                        dir.nlink -= 1;
+                    }
+                    dir.mtime = tx.now();
+                    dir.ctime = dir.mtime;
+                    Ok(())
+                })??;
+                if freed {
+                    victim.release(tx)?;
+                }
+                Ok(())
+            });
+        }
+    }
+
+    pub fn unlink(&self, name: &str) -> EzResult<()> {
+        self.remove(name, false)
+    }
+
+    // remove an empty sub-directory
+    pub fn rmdir(&self, name: &str) -> EzResult<()> {
+        if name == "." || name == ".." {
+            return Err(EzFsError::InvalidName);
+        }
+        self.remove(name, true)
+    }
+
+    // move the entry `old_name` to `new_name` in `new_dir`, in place of what
+    // is there if `replace` is set; a replaced inode loses its link and is
+    // freed with its last one, all in one transaction with the move
+    pub fn rename(&self, old_name: &str, new_dir: &VirtInode, new_name: &str, replace: bool) -> EzResult<()> {
+        if old_name == "." || old_name == ".." || !is_valid_name(new_name) {
+            return Err(EzFsError::InvalidName);
+        }
+        if new_name.len() > NAME_MAX {
+            return Err(EzFsError::NameTooLong);
+        }
+        loop {
+            let src = self.find(old_name)?;
+            let inode_id = src.inode_id;
+            let target = {
+                let _dir = new_dir.lock.read();
+                new_dir.read_disk_inode(|dir| new_dir.find_dirent(new_name, dir))??
+            };
+            let victim = match target {
+                Some((_, target_id)) if target_id != inode_id && replace => Some(self.vinode(target_id)),
+                _ => None
+            };
+            let mut inodes = vec![self, new_dir, &src];
+            inodes.extend(victim.as_deref());
+            let _locks = lock_all(&inodes);
+            // either entry may have changed before the directories were locked again
+            if self.read_disk_inode(|dir| self.find_inode_id(old_name, dir))?? != Some(inode_id)
+                || new_dir.read_disk_inode(|dir| new_dir.find_dirent(new_name, dir))?? != target {
+                continue;
+            }
+            return self.journal.lock().transaction(&self.fs, |tx| self.move_entry(old_name, (new_dir, new_name), &src, target, victim.as_deref(), tx));
+        }
+    }
+
+    // the body of `rename`, with every inode it touches locked
+    fn move_entry(
+        &self,
+        old_name: &str,
+        (new_dir, new_name): (&VirtInode, &str),
+        src: &VirtInode,
+        target: Option<(usize, u32)>,
+        victim: Option<&VirtInode>,
+        tx: &mut Tx
+    ) -> EzResult<()> {
+        let inode_id = src.inode_id;
+        let is_dir = src.read_disk_inode(|inode| inode.is_dir())?;
+        // the tree only changes in transactions, so it holds still for this
+        if is_dir {
+            self.check_not_below(inode_id, new_dir.inode_id)?;
+        }
+        match target {
+            // another name of the same file, nothing to move
+            Some((_, target_id)) if target_id == inode_id => return Ok(()),
+            Some(_) if victim.is_none() => return Err(EzFsError::Exists),
+            _ => {}
+        }
+        let now = tx.now();
+        match (target, victim) {
+            (Some((new_offset, _)), Some(victim)) => {
+                let freed = victim.modify_disk_inode(|inode| {
+                    match (is_dir, inode.is_dir()) {
+                        (true, false) => return Err(EzFsError::NotDir),
+                        (false, true) => return Err(EzFsError::IsDir),
+                        (true, true) if !victim.is_empty_dir(inode)? => return Err(EzFsError::NotEmpty),
+                        (true, true) => inode.nlink = 0,
+                        (false, false) => inode.nlink -= 1
+                    }
+                    inode.ctime = now;
+                    Ok(inode.nlink == 0)
+                })??;
+                new_dir.modify_disk_inode(|dir| -> EzResult<()> {
+                    dir::set_inode(dir, new_offset, inode_id, &self.block_dev)?;
+                    // ".." of the replaced directory
+                    if is_dir {
+                        dir.nlink -= 1;
+                    }
+                    dir.mtime = now;
+                    dir.ctime = now;
+                    Ok(())
+                })??;
+                if freed {
+                    victim.release(tx)?;
+                }
+            }
+            _ => new_dir.modify_disk_inode(|dir| -> EzResult<()> {
+                if dir.nlink == 0 {
+                    return Err(EzFsError::NotFound);
+                }
+                new_dir.add_dirent(new_name, inode_id, dir, tx)?;
+                dir.mtime = now;
+                dir.ctime = now;
+                Ok(())
+            })??
+        }
+        // adding to the same directory may have moved the old entry
+        self.modify_disk_inode(|dir| -> EzResult<()> {
+            let (old_offset, _) = self.find_dirent(old_name, dir)?.ok_or(EzFsError::Corrupted)?;
+            dir::remove(dir, old_offset, &self.block_dev)?;
+            dir.mtime = now;
+            dir.ctime = now;
+            Ok(())
+        })??;
+        if is_dir && self.inode_id != new_dir.inode_id {
+            // ".." follows the directory to its new parent
+            src.modify_disk_inode(|inode| -> EzResult<()> {
+                let (offset, _) = dir::lookup(inode, "..", &self.block_dev)?.ok_or(EzFsError::Corrupted)?;
+                dir::set_inode(inode, offset, new_dir.inode_id, &self.block_dev)
+            })??;
+            self.modify_disk_inode(|dir| dir.nlink -= 1)?;
+            new_dir.modify_disk_inode(|dir| dir.nlink += 1)?;
+        }
+        src.modify_disk_inode(|inode| inode.ctime = now)
+    }
+
+    // fails if `dir_id` is directory `inode_id` or lies below it
+    fn check_not_below(&self, inode_id: u32, dir_id: u32) -> EzResult<()> {
+        let mut curr = dir_id;
+        loop {
+            if curr == inode_id {
+                return Err(EzFsError::InvalidName);
+            }
+            if curr == 0 {
+                return Ok(());
+            }
+            let dir = self.vinode(curr);
+            curr = dir.read_disk_inode(|inode| dir.find_inode_id("..", inode))??.ok_or(EzFsError::Corrupted)?;
+        }
+    }
+
+    pub fn clear(&self) -> EzResult<()> {
+        self.truncate(0)
+    }
+
+    // cut the file down to `new_size` bytes, or extend it with a hole
+    pub fn truncate(&self, new_size: usize) -> EzResult<()> {
+        if new_size > DiskInode::max_size() as usize {
+            return Err(EzFsError::TooLarge);
+        }
+        let _inode = self.lock.write();
+        self.journal.lock().transaction(&self.fs, |tx| {
+            let size = self.modify_disk_inode(|inode| {
+                if inode.is_dir() {
+                    return Err(EzFsError::IsDir);
+                }
+                inode.mtime = tx.now();
+                inode.ctime = inode.mtime;
+                Ok(inode.size)
+            })??;
+            if new_size as u32 > size {
+                self.modify_disk_inode(|inode| inode.size = new_size as u32)
+            } else {
+                self.shrink_all(new_size as u32, tx)
+            }
         })
     }
 
-    pub fn write_at(&self, offset: usize, buf: &[u8]) -> usize {
-        let mut fs = self.fs.lock();
-        let size = self.modify_disk_inode(|inode| {
-            self.increase_size((offset + buf.len()) as u32, inode, &mut fs);
-            inode.write_at(offset, buf, &self.block_dev)
-        });
-        sync_block_cache();
-        size
+    pub fn read_at(&self, offset: usize, buf: &mut [u8]) -> EzResult<usize> {
+        let inode = self.lock.read();
+        let (len, touched) = self.read_disk_inode(|inode| Ok((
+            inode.read_at(offset, buf, &self.block_dev)?,
+            // relatime: only the first read after a change is recorded
+            inode.atime <= inode.mtime || inode.atime <= inode.ctime
+        )))??;
+        drop(inode);
+        if touched {
+            let _inode = self.lock.write();
+            self.journal.lock().transaction(&self.fs, |tx| self.modify_disk_inode(|inode| inode.atime = tx.now()))?;
+        }
+        Ok(len)
     }
-}
\ No newline at end of file
+
+    // one transaction per chunk keeps large writes within the journal;
+    // a failure after some chunks made it returns the bytes written so far
+    pub fn write_at(&self, offset: usize, buf: &[u8]) -> EzResult<usize> {
+        let _inode = self.lock.write();
+        if offset + buf.len() > DiskInode::max_size() as usize {
+            return Err(EzFsError::TooLarge);
+        }
+        let mut written = 0usize;
+        for chunk in buf.chunks(WRITE_CHUNK) {
+            let start = offset + written;
+            let res = self.journal.lock().transaction(&self.fs, |tx| {
+                self.modify_disk_inode(|inode| {
+                    // anything skipped over before `start` stays a hole
+                    self.map_range(start, start + chunk.len(), inode, tx)?;
+                    inode.size = inode.size.max((start + chunk.len()) as u32);
+                    inode.mtime = tx.now();
+                    inode.ctime = inode.mtime;
+                    inode.write_at(start, chunk, &self.block_dev)
+                })?
+            });
+            match res {
+                Ok(len) => written += len,
+                Err(_) if written > 0 => break,
+                Err(e) => return Err(e)
+            }
+        }
+        Ok(written)
+    }
+}
+
+impl Drop for VirtInode {
+    fn drop(&mut self) {
+        if Arc::strong_count(&self.lock) > 1 {
+            return;
+        }
+        if ORPHANS.lock().contains(&(dev_id(&self.block_dev), self.inode_id)) {
+            // should it fail, the inode is freed at the next mount
+            let _ = self.free_unlinked();
+        }
+    }
+}
+
+// the S_IF* bits of an inode
+fn file_type(inode: &DiskInode) -> u32 {
+    if inode.is_dir() {
+        S_IFDIR
+    } else if inode.is_symlink() {
+        S_IFLNK
+    } else {
+        S_IFREG
+    }
+}
+
+fn is_valid_name(name: &str) -> bool {
+    !name.is_empty() && name != "." && name != ".." && !name.contains(['/', '\0'])
+}
+
+// split a path into (parent, final component)
+pub fn split_path(path: &str) -> (&str, &str) {
+    match path.trim_end_matches('/').rsplit_once('/') {
+        Some((parent, name)) => (parent, name),
+        None => ("", path.trim_end_matches('/'))
+    }
+}
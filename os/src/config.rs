@@ -7,6 +7,12 @@ pub const MEM_END: usize = 0x81000000;
 pub const PAGE_SIZE_BITS: usize = 12;
 pub const PAGE_SIZE: usize = 0x1000;
 
+// user addresses are the lower half of sv39, mmap places areas from MMAP_BASE on
+pub const USER_SPACE_END: usize = 1 << 38;
+pub const MMAP_BASE: usize = 0x10_0000_0000;
+// thread stacks from here on, the heap grows from the end of the elf up to it
+pub const USER_STACK_BASE: usize = 0x8_0000_0000;
+
 pub const ADDR_TRAMPOLINE: usize = usize::MAX - PAGE_SIZE + 1;
 pub const ADDR_TRAPCONTEXT: usize = ADDR_TRAMPOLINE - PAGE_SIZE;
 
@@ -15,8 +21,10 @@ pub const CLOCK_FREQ: usize = 12500000;
 pub const VIRT_PLIC: usize = 0x0c00_0000;
 pub const VIRT_UART: usize = 0x1000_0000;
 pub const VIRT_MMIO1: usize = 0x10001000;
+pub const VIRT_RTC: usize = 0x0010_1000;
 
 pub const MMIO: &[(usize, usize)] = &[
+    (0x0010_1000, 0x1000),      // RTC
     (0x2000000, 0x10000),
     (0x0c00_0000, 0x600000),    // PLIC
     (0x1000_0000, 0x9000),      // VIRTIO MMIO 
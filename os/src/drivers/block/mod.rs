@@ -1,5 +1,5 @@
 use alloc::sync::Arc;
-use easyfs::BlockDev;
+use easyfs::{BlockDev, EzResult};
 use crate::sync::UThrCell;
 
 use super::Device;
@@ -7,7 +7,14 @@ use lazy_static::lazy_static;
 
 pub mod virtio_blk;
 
-pub trait BlockDevice: BlockDev + Device {}
+pub trait BlockDevice: BlockDev + Device {
+    // size in blocks
+    fn capacity(&self) -> usize;
+    // spin until the request is done instead of sleeping on the interrupt,
+    // for callers that may not give up the cpu
+    fn read_blocks_polled(&self, start_block: usize, buf: &mut [u8]) -> EzResult<()>;
+    fn write_blocks_polled(&self, start_block: usize, buf: &[u8]) -> EzResult<()>;
+}
 
 lazy_static! {
     pub static ref BLOCK_DEV: UThrCell<Option<Arc<dyn BlockDevice>>> = unsafe {
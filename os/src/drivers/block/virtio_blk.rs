@@ -1,12 +1,17 @@
+use core::ops::Range;
+
 use alloc::vec::Vec;
-use easyfs::BlockDev;
+use easyfs::{BlockDev, EzFsError, EzResult, BLOCK_SIZE};
 use log::debug;
-use crate::{drivers::{Device, VirtioHal}, sync::{CondVar, UThrCell}, task::processor::schedule, DEV_NONBLOCKING_ACCESS};
+use crate::{drivers::{Device, VirtioHal}, mm::{memset::kern_token, pagetab::PageTab}, sync::{CondVar, UThrCell}, task::processor::schedule, DEV_NONBLOCKING_ACCESS};
 use virtio_drivers::{device::blk::{BlkReq, BlkResp, VirtIOBlk}, transport::mmio::MmioTransport};
 
 
 use super::BlockDevice;
 
+// upper bound on the blocks moved by a single request
+const MAX_REQ_BLOCKS: usize = 128;
+
 
 pub struct VirtioBlock {
     virtio_blk: UThrCell<VirtIOBlk<VirtioHal, MmioTransport>>,
@@ -32,8 +37,25 @@ impl VirtioBlock {
     }
 }
 
-impl BlockDev for VirtioBlock {
-    fn read_block(&self, block_id: usize, buf: &mut [u8]) {
+impl VirtioBlock {
+    // a request takes its buffer as one dma segment, so split `buf` into runs
+    // of blocks that are also contiguous in physical memory
+    fn dma_runs(buf: &[u8]) -> Vec<Range<usize>> {
+        let pagetab = PageTab::from_token(kern_token());
+        let pa = |offset: usize| pagetab.trans_va((buf.as_ptr() as usize + offset).into()).unwrap().0;
+        let mut runs = Vec::new();
+        let mut start = 0;
+        for offset in (BLOCK_SIZE..buf.len()).step_by(BLOCK_SIZE) {
+            if offset - start == MAX_REQ_BLOCKS * BLOCK_SIZE || pa(offset) != pa(start) + offset - start {
+                runs.push(start..offset);
+                start = offset;
+            }
+        }
+        runs.push(start..buf.len());
+        runs
+    }
+
+    fn read_request(&self, block_id: usize, buf: &mut [u8]) -> EzResult<()> {
         let flag_nb = *DEV_NONBLOCKING_ACCESS.get_refmut();
         if flag_nb {
             let mut resp = BlkResp::default();
@@ -41,23 +63,24 @@ impl BlockDev for VirtioBlock {
             let mut token = 0u16;
             let task_cx = self.virtio_blk.then(|blk| {
                 token = unsafe {
-                    blk.read_blocks_nb(block_id, &mut req, buf, &mut resp).unwrap()
+                    blk.read_blocks_nb(block_id, &mut req, buf, &mut resp)
+                        .map_err(|_| EzFsError::Io)?
                 };
-                self.condvars[token as usize].wait_without_schd()
-            });
+                Ok(self.condvars[token as usize].wait_without_schd())
+            })?;
             schedule(task_cx);
             unsafe {
                 self.virtio_blk.get_refmut().complete_read_blocks(token, &mut req, buf, &mut resp)
-                    .expect("Error when reading VirtIOBlk");
+                    .map_err(|_| EzFsError::Io)
             }
         } else {
             self.virtio_blk.get_refmut()
                 .read_blocks(block_id, buf)
-                .expect("Error when reading VirtIOBlk");
+                .map_err(|_| EzFsError::Io)
         }
     }
 
-    fn write_block(&self, block_id: usize, buf: &[u8]) {
+    fn write_request(&self, block_id: usize, buf: &[u8]) -> EzResult<()> {
         let flag_nb = *DEV_NONBLOCKING_ACCESS.get_refmut();
         if flag_nb {
             let mut resp = BlkResp::default();
@@ -65,22 +88,46 @@ impl BlockDev for VirtioBlock {
             let mut token = 0u16;
             let task_cx = self.virtio_blk.then(|blk| {
                 token = unsafe {
-                    blk.write_blocks_nb(block_id, &mut req, buf, &mut resp).unwrap()
+                    blk.write_blocks_nb(block_id, &mut req, buf, &mut resp)
+                        .map_err(|_| EzFsError::Io)?
                 };
-                self.condvars[token as usize].wait_without_schd()
-            });
+                Ok(self.condvars[token as usize].wait_without_schd())
+            })?;
             schedule(task_cx);
             unsafe {
                 self.virtio_blk.get_refmut().complete_write_blocks(token, &mut req, buf, &mut resp)
-                    .expect("Error when writing VirtIOBlk");
+                    .map_err(|_| EzFsError::Io)
             }
         } else {
             self.virtio_blk.get_refmut()
                 .write_blocks(block_id, buf)
-                .expect("Error when writing VirtIOBlk");
+                .map_err(|_| EzFsError::Io)
         }
     }
+}
+
+impl BlockDev for VirtioBlock {
+    fn read_block(&self, block_id: usize, buf: &mut [u8]) -> EzResult<()> {
+        self.read_request(block_id, buf)
+    }
 
+    fn write_block(&self, block_id: usize, buf: &[u8]) -> EzResult<()> {
+        self.write_request(block_id, buf)
+    }
+
+    fn read_blocks(&self, start_block: usize, buf: &mut [u8]) -> EzResult<()> {
+        for run in Self::dma_runs(buf) {
+            self.read_request(start_block + run.start / BLOCK_SIZE, &mut buf[run])?;
+        }
+        Ok(())
+    }
+
+    fn write_blocks(&self, start_block: usize, buf: &[u8]) -> EzResult<()> {
+        for run in Self::dma_runs(buf) {
+            self.write_request(start_block + run.start / BLOCK_SIZE, &buf[run])?;
+        }
+        Ok(())
+    }
 }
 
 impl Device for VirtioBlock {
@@ -94,5 +141,22 @@ impl Device for VirtioBlock {
     }
 }
 
-impl BlockDevice for VirtioBlock {}
+// buffers here are frames, contiguous and within one request
+impl BlockDevice for VirtioBlock {
+    fn capacity(&self) -> usize {
+        self.virtio_blk.get_refmut().capacity() as usize
+    }
+
+    fn read_blocks_polled(&self, start_block: usize, buf: &mut [u8]) -> EzResult<()> {
+        self.virtio_blk.get_refmut()
+            .read_blocks(start_block, buf)
+            .map_err(|_| EzFsError::Io)
+    }
+
+    fn write_blocks_polled(&self, start_block: usize, buf: &[u8]) -> EzResult<()> {
+        self.virtio_blk.get_refmut()
+            .write_blocks(start_block, buf)
+            .map_err(|_| EzFsError::Io)
+    }
+}
 
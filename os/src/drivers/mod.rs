@@ -9,14 +9,14 @@ use alloc::{sync::Arc, vec::Vec};
 use lazy_static::lazy_static;
 
 use log::{debug, info, warn};
-pub use block::BLOCK_DEV;
+pub use block::{BlockDevice, BLOCK_DEV};
 pub use uart::SERIAL_DEV;
 pub use input::INPUT_DEV;
 pub use gpu::GPU_DEV;
 use plic::{IntrTargetPriority, Plic};
 use virtio_drivers::{transport::{mmio::{MmioTransport, VirtIOHeader}, DeviceType, Transport}, Hal};
 
-use crate::{config::{VIRT_MMIO1, VIRT_PLIC}, drivers::{block::virtio_blk::VirtioBlock, gpu::virtio_gpu::VirtioGpu, input::virtio_input::VirtioInput}, mm::{address::PhysAddr, frame_allocator::{frame_alloc, frame_dealloc, FrameTracker}, memset::KERN_SPACE, pagetab::PageTab}, sync::UThrCell};
+use crate::{config::{VIRT_MMIO1, VIRT_PLIC}, drivers::{block::virtio_blk::VirtioBlock, gpu::virtio_gpu::VirtioGpu, input::virtio_input::VirtioInput}, mm::{address::PhysAddr, frame_allocator::{frame_alloc, frame_dealloc, FrameTracker}, memset::kern_token, pagetab::PageTab, swap}, sync::UThrCell};
 
 lazy_static! {
     static ref VIRTIO_REGDEV: UThrCell<Vec<Option<Arc<dyn Device>>>> = unsafe {
@@ -55,9 +55,13 @@ pub fn device_init() {
             );
             match transport.device_type() {
                 DeviceType::Block => {
+                    let block_dev = Arc::new(VirtioBlock::new(transport));
+                    // the swap device is only used polled, without its interrupt
+                    if swap::swap_on(block_dev.clone()) {
+                        continue;
+                    }
                     plic.enable(hart_id, smod, i + 1);
                     plic.set_priority(i + 1, 1);
-                    let block_dev = Arc::new(VirtioBlock::new(transport));
                     *BLOCK_DEV.get_refmut() = Some(block_dev.clone());
                     VIRTIO_REGDEV.get_refmut()[i] = Some(block_dev.clone());
                 }
@@ -107,16 +111,17 @@ pub struct VirtioHal;
 
 unsafe impl Hal for VirtioHal {
     fn dma_alloc(pages: usize, _direction: virtio_drivers::BufferDirection) -> (virtio_drivers::PhysAddr, core::ptr::NonNull<u8>) {
-        let frame = frame_alloc().unwrap();
-        let ppn_base = frame.ppn;
-        QUEUE_FRAMES.get_refmut().push(frame);
-
-        for i in 1..pages {
-            let frame = frame_alloc().unwrap();
-            assert_eq!(frame.ppn.0, ppn_base.0 + i);
-            QUEUE_FRAMES.get_refmut().push(frame);
+        // a zero address tells the driver there was no memory, the frames
+        // taken so far are given back as they are dropped
+        let mut frames: Vec<FrameTracker> = Vec::new();
+        for i in 0..pages {
+            match frame_alloc() {
+                Some(frame) if frames.is_empty() || frame.ppn.0 == frames[0].ppn.0 + i => frames.push(frame),
+                _ => return (0, NonNull::dangling())
+            }
         }
-        let pa = PhysAddr::from(ppn_base).0;
+        let pa = PhysAddr::from(frames[0].ppn).0;
+        QUEUE_FRAMES.get_refmut().extend(frames);
         let ptr = NonNull::new(pa as _).unwrap();
         (pa, ptr)
     }
@@ -136,7 +141,7 @@ unsafe impl Hal for VirtioHal {
 
     unsafe fn share(buffer: core::ptr::NonNull<[u8]>, _direction: virtio_drivers::BufferDirection) -> virtio_drivers::PhysAddr {
         let va = buffer.as_ptr() as *mut u8 as usize;
-        PageTab::from_token(KERN_SPACE.get_refmut().get_atp_token())
+        PageTab::from_token(kern_token())
             .trans_va(va.into()).unwrap().0
     }
 
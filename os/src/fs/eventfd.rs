@@ -1,5 +1,6 @@
 use alloc::{collections::VecDeque, sync::Arc};
 use bitflags::bitflags;
+use easyfs::vfs::Stat;
 
 use crate::{sync::UThrCell, task::{block_curr_task, processor::curr_task, task::TaskControlBlock, wakeup_task}};
 
@@ -157,6 +158,11 @@ impl File for EventFd {
     fn seek(&self, _offset: isize, _whence: usize) {
         panic!("Unable to seek");
     }
+
+    // anonymous inode, no file type
+    fn stat(&self) -> Stat {
+        Stat { mode: 0o600, nlink: 1, ..Default::default() }
+    }
 }
 
 struct EventFdMut {
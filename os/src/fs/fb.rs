@@ -1,5 +1,6 @@
 use core::ptr::write_volatile;
 
+use easyfs::vfs::{Stat, S_IFCHR};
 use log::debug;
 
 use crate::{drivers::GPU_DEV, sync::UThrCell};
@@ -96,4 +97,14 @@ impl File for Fb {
         }
         debug!("gpu fb offset: {}", *inner);
     }
+    fn stat(&self) -> Stat {
+        let gpu_mut = GPU_DEV.get_refmut();
+        let gpu = gpu_mut.as_ref().unwrap().clone();
+        Stat {
+            mode: S_IFCHR | 0o660,
+            nlink: 1,
+            size: gpu.get_framebuf().len() as u64,
+            ..Default::default()
+        }
+    }
 }
\ No newline at end of file
@@ -1,8 +1,10 @@
-use alloc::{sync::Arc, vec::Vec};
+use core::{any::Any, sync::atomic::{AtomicUsize, Ordering}};
+
+use alloc::{string::{String, ToString}, sync::Arc, vec::Vec};
 use bitflags::bitflags;
 use lazy_static::lazy_static;
-use crate::{drivers::BLOCK_DEV, sync::UThrCell};
-use easyfs::{vfs::VirtInode, EzFileSys};
+use crate::{drivers::BLOCK_DEV, mm::pagetab::UserBuffer, sync::UThrCell, task::suspend_curr_task, timer::get_unix_time_ms};
+use easyfs::{cache_man::{periodic_write_back, set_cache_capacity}, efs::StatFs, sync::set_relax, vfs::{split_path, Stat, VirtInode, S_IFDIR, S_IFLNK, S_IRUSR, S_IWUSR}, EzFileSys, EzFsError, EzResult};
 
 use super::File;
 
@@ -26,54 +28,179 @@ impl OSInode {
             })}
         }
     }
-    pub fn read_app(&self) -> Vec<u8> {
+    pub fn from_file(file: Arc<dyn File + Send + Sync>) -> Option<Arc<OSInode>> {
+        (file as Arc<dyn Any + Send + Sync>).downcast::<OSInode>().ok()
+    }
+    // fill `buf` with linux_dirent64 records, the offset counts directory slots
+    pub fn getdents(&self, mut buf: UserBuffer) -> isize {
         let mut inner = self.inner.get_refmut();
-        let mut buf = [0u8; 512];
-        let mut v: Vec<u8> = Vec::new();
+        let mut records: Vec<u8> = Vec::new();
         loop {
-            let len = inner.inode.read_at(inner.offset, &mut buf);
-            if len == 0 {
+            let (item, next) = match inner.inode.read_dir(inner.offset) {
+                Ok(Some(entry)) => entry,
+                Ok(None) => break,
+                Err(e) if records.is_empty() => return -(e.errno() as isize),
+                Err(_) => break
+            };
+            // d_ino, d_off, d_reclen, d_type, d_name with nul, aligned to 8
+            let reclen = (19 + item.name.len() + 1 + 7) & !7;
+            if records.len() + reclen > buf.len() {
+                if records.is_empty() {
+                    return -1;
+                }
                 break;
             }
-            inner.offset += len;
-            v.extend_from_slice(&buf[..len])
+            let d_type: u8 = match item.mode {
+                S_IFDIR => DT_DIR,
+                S_IFLNK => DT_LNK,
+                _ => DT_REG
+            };
+            records.extend_from_slice(&(item.inode_id as u64).to_ne_bytes());
+            records.extend_from_slice(&(next as i64).to_ne_bytes());
+            records.extend_from_slice(&(reclen as u16).to_ne_bytes());
+            records.push(d_type);
+            records.extend_from_slice(item.name.as_bytes());
+            records.resize(records.len() + reclen - 19 - item.name.len(), 0);
+            inner.offset = next;
+        }
+        buf.copy_from_slice(&records) as isize
+    }
+    // the file behind the descriptor, for mappings
+    pub fn inode(&self) -> Arc<VirtInode> {
+        Arc::clone(&self.inner.get_refmut().inode)
+    }
+    // cut the file to `len` bytes or extend it with a hole
+    pub fn truncate(&self, len: usize) -> EzResult<()> {
+        if !self.writable {
+            return Err(EzFsError::PermissionDenied);
         }
-        v
+        self.inner.get_refmut().inode.truncate(len)
     }
-    pub fn open(name: &str, flags: OpenFlag) -> Option<Arc<OSInode>> {
+    pub fn open(path: &str, flags: OpenFlag) -> EzResult<Arc<OSInode>> {
         let (readable, writable) = flags.into_readwrite();
-        if flags.contains(OpenFlag::CREATE) {
-            if let Some(inode) = ROOT_INODE.find(name) {
-                inode.clear();
-                Some(Arc::new(OSInode::new(
-                    readable,
-                    writable,
-                    inode
-                )))
-            } else {
-                ROOT_INODE.create(name)
-                    .map(|inode| {
-                        Arc::new(OSInode::new(
-                            readable,
-                            writable,
-                            inode
-                        ))
-                    })
+        let inode = match resolve(path, !flags.contains(OpenFlag::NOFOLLOW)) {
+            // only left unfollowed with O_NOFOLLOW
+            Ok(inode) if inode.is_symlink()? => return Err(EzFsError::Loop),
+            Ok(inode) => {
+                let trunc = flags.intersects(OpenFlag::CREATE | OpenFlag::TRUNC);
+                // directories can only be opened for reading
+                if inode.is_dir()? && (writable || trunc) {
+                    return Err(EzFsError::IsDir);
+                }
+                if !permits(&inode, readable, writable || trunc)? {
+                    return Err(EzFsError::PermissionDenied);
+                }
+                if trunc {
+                    inode.clear()?;
+                }
+                inode
             }
-        } else {
-            ROOT_INODE.find(name)
-                .map(|inode| {
-                    if flags.contains(OpenFlag::TRUNC) {
-                        inode.clear()
-                    }
-                    Arc::new(OSInode::new(
-                        readable,
-                        writable,
-                        inode
-                    ))
-                })
+            Err(EzFsError::NotFound) if flags.contains(OpenFlag::CREATE) => {
+                let (parent, name) = split_path(path);
+                resolve(parent, true)?.create(name)?
+            }
+            Err(e) => return Err(e)
+        };
+        Ok(Arc::new(OSInode::new(readable, writable, inode)))
+    }
+}
+
+// links followed in one path resolution before giving up with ELOOP
+const MAX_SYMLINKS: usize = 40;
+
+// walk `path` from the root, following symbolic links on the way and, if
+// `follow_last` is set, the one the path ends at
+fn resolve(path: &str, follow_last: bool) -> EzResult<Arc<VirtInode>> {
+    let mut inode = ROOT_INODE.clone();
+    // components still to walk, the next one last
+    let mut rest: Vec<String> = path.split('/')
+        .filter(|name| !name.is_empty())
+        .rev()
+        .map(|name| name.to_string())
+        .collect();
+    let mut links = 0;
+    while let Some(name) = rest.pop() {
+        let next = inode.find(&name)?;
+        if !next.is_symlink()? || (rest.is_empty() && !follow_last) {
+            inode = next;
+            continue;
         }
+        links += 1;
+        if links > MAX_SYMLINKS {
+            return Err(EzFsError::Loop);
+        }
+        // a relative target starts from the directory holding the link
+        let target = next.readlink()?;
+        if target.starts_with('/') {
+            inode = ROOT_INODE.clone();
+        }
+        rest.extend(target.split('/')
+            .filter(|name| !name.is_empty())
+            .rev()
+            .map(|name| name.to_string()));
     }
+    Ok(inode)
+}
+
+// only the owner bits are checked, as there are no users
+fn permits(inode: &VirtInode, read: bool, write: bool) -> EzResult<bool> {
+    let mode = inode.stat()?.mode;
+    Ok((!read || mode & S_IRUSR != 0) && (!write || mode & S_IWUSR != 0))
+}
+
+pub fn mkdir(path: &str, mode: u32) -> EzResult<()> {
+    let (parent, name) = split_path(path);
+    resolve(parent, true)?
+        .mkdir(name)?
+        .set_mode(mode)
+}
+
+pub fn chmod(path: &str, mode: u32) -> EzResult<()> {
+    resolve(path, true)?.set_mode(mode)
+}
+
+pub fn rmdir(path: &str) -> EzResult<()> {
+    let (parent, name) = split_path(path);
+    resolve(parent, true)?.rmdir(name)
+}
+
+pub fn unlink(path: &str) -> EzResult<()> {
+    let (parent, name) = split_path(path);
+    resolve(parent, true)?.unlink(name)
+}
+
+// a link to a symbolic link itself, as linkat does without AT_SYMLINK_FOLLOW
+pub fn link(old_path: &str, new_path: &str) -> EzResult<()> {
+    let target = resolve(old_path, false)?;
+    let (parent, name) = split_path(new_path);
+    resolve(parent, true)?.link(name, &target)
+}
+
+pub fn symlink(target: &str, link_path: &str) -> EzResult<()> {
+    let (parent, name) = split_path(link_path);
+    resolve(parent, true)?.symlink(name, target).map(|_| ())
+}
+
+pub fn readlink(path: &str) -> EzResult<String> {
+    resolve(path, false)?.readlink()
+}
+
+// usage of the file system holding `path`
+pub fn statfs(path: &str) -> EzResult<StatFs> {
+    Ok(resolve(path, true)?.statfs())
+}
+
+// replace an existing `new_path` unless `no_replace` is set
+pub fn rename(old_path: &str, new_path: &str, no_replace: bool) -> EzResult<()> {
+    let (old_parent, old_name) = split_path(old_path);
+    let (new_parent, new_name) = split_path(new_path);
+    let new_dir = resolve(new_parent, true)?;
+    resolve(old_parent, true)?.rename(old_name, &new_dir, new_name, !no_replace)
+}
+
+// on failure, a short count if anything was transferred, the negated errno otherwise
+fn io_error(done: usize, e: EzFsError) -> usize {
+    if done == 0 { (-(e.errno() as isize)) as usize } else { done }
 }
 
 impl File for OSInode {
@@ -84,7 +211,10 @@ impl File for OSInode {
         let mut inner = self.inner.get_refmut();
         let mut tot_read_sz = 0usize;
         for slice in buf.buffers.iter_mut() {
-            let read_sz = inner.inode.read_at(inner.offset, *slice);
+            let read_sz = match inner.inode.read_at(inner.offset, *slice) {
+                Ok(read_sz) => read_sz,
+                Err(e) => return io_error(tot_read_sz, e)
+            };
             if read_sz == 0 {
                 break;
             }
@@ -97,10 +227,16 @@ impl File for OSInode {
         let mut inner = self.inner.get_refmut();
         let mut tot_write_sz = 0usize;
         for slice in buf.buffers.iter() {
-            let write_sz = inner.inode.write_at(inner.offset, *slice);
-            assert_eq!(write_sz, slice.len());
+            let write_sz = match inner.inode.write_at(inner.offset, *slice) {
+                Ok(write_sz) => write_sz,
+                Err(e) => return io_error(tot_write_sz, e)
+            };
             inner.offset += write_sz;
             tot_write_sz += write_sz;
+            // the disk filled up part way through
+            if write_sz < slice.len() {
+                break;
+            }
         }
         tot_write_sz
     }
@@ -112,15 +248,43 @@ impl File for OSInode {
             inner.offset += offset as usize;
         }
     }
+    fn stat(&self) -> Stat {
+        self.inner.get_refmut().inode.stat().unwrap_or_default()
+    }
 }
 
+// 128 KiB of block cache
+const BLOCK_CACHE_NUM: usize = 256;
+
 lazy_static! {
     pub static ref ROOT_INODE: Arc<VirtInode> = {
-        let efs = EzFileSys::from_device(BLOCK_DEV.get_refmut().as_ref().unwrap().clone());
+        // nothing is cached yet, so nothing to write back
+        set_cache_capacity(BLOCK_CACHE_NUM).unwrap();
+        // a thread waiting for a lock of easyfs makes way for the one holding
+        // it, which may be asleep on the disk
+        set_relax(suspend_curr_task);
+        let efs = EzFileSys::from_device(BLOCK_DEV.get_refmut().as_ref().unwrap().clone())
+            .expect("Invalid easyfs image");
+        efs.lock().set_clock(|| get_unix_time_ms() as u64);
         Arc::new(EzFileSys::root_vinode(&efs))
     };
 }
 
+// timer ticks between two rounds of write-back, about a second
+const WRITE_BACK_TICKS: usize = 50;
+static TICKS: AtomicUsize = AtomicUsize::new(0);
+
+// run on a timer tick from user mode, where no lock of easyfs is held
+pub fn write_back_tick() {
+    if TICKS.fetch_add(1, Ordering::Relaxed) % WRITE_BACK_TICKS == WRITE_BACK_TICKS - 1 {
+        // a failed block stays dirty for the next round
+        let _ = periodic_write_back();
+    }
+}
+
+const DT_DIR: u8 = 4;
+const DT_REG: u8 = 8;
+const DT_LNK: u8 = 10;
 
 bitflags! {
     pub struct OpenFlag: u32 {
@@ -129,14 +293,17 @@ bitflags! {
         const RDWR   = 2;
         const CREATE = 1 << 9;
         const TRUNC  = 1 << 10;
+        // fail with ELOOP if the path ends at a symbolic link
+        const NOFOLLOW = 1 << 17;
     }
 }
 
 impl OpenFlag {
     pub fn into_readwrite(&self) -> (bool, bool) {
-        if self.is_empty() {
+        let access = *self - Self::NOFOLLOW;
+        if access.is_empty() {
             (true, false)
-        } else if self.contains(Self::WRONLY) {
+        } else if access.contains(Self::WRONLY) {
             (false, true)
         } else {
             (true, true)
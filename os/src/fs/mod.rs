@@ -3,20 +3,25 @@ pub mod stdio;
 pub mod pipe;
 pub mod eventfd;
 pub mod fb;
+use core::any::Any;
+
+use easyfs::vfs::Stat;
+
 use crate::{fs::inode::ROOT_INODE, mm::pagetab::UserBuffer};
 
-pub trait File: Send + Sync {
+pub trait File: Send + Sync + Any {
     fn readable(&self) -> bool;
     fn writable(&self) -> bool;
     fn seekable(&self) -> bool;
     fn read(&self, buf: UserBuffer) -> usize;
     fn write(&self, buf: UserBuffer) -> usize;
     fn seek(&self, offset: isize, whence: usize);
+    fn stat(&self) -> Stat;
 }
 
 pub fn list_apps() {
     println!("/**** APPS ****");
-    for app in ROOT_INODE.ls().iter() {
+    for app in ROOT_INODE.ls().unwrap_or_default().iter().filter(|name| *name != "." && *name != "..") {
         println!("{}", app);
     }
     println!("**************/");
@@ -1,4 +1,5 @@
 use alloc::sync::{Arc, Weak};
+use easyfs::vfs::{Stat, S_IFIFO};
 use spin::Mutex;
 
 use crate::task::suspend_curr_task;
@@ -133,6 +134,15 @@ impl File for Pipe {
     fn seek(&self, _offset: isize, _whence: usize) {
         panic!("Unable to seek");
     }
+
+    fn stat(&self) -> Stat {
+        Stat {
+            mode: S_IFIFO | 0o600,
+            nlink: 1,
+            size: self.buffer.lock().bytes_contain() as u64,
+            ..Default::default()
+        }
+    }
 }
 
 impl RingBuffer {
@@ -1,5 +1,7 @@
 use core::str::from_utf8;
 
+use easyfs::vfs::{Stat, S_IFCHR};
+
 use crate::drivers::SERIAL_DEV;
 
 use super::File;
@@ -31,6 +33,10 @@ impl File for Stdin {
     fn seek(&self, _offset: isize, _whence: usize) {
         panic!("Unable to seek");
     }
+
+    fn stat(&self) -> Stat {
+        Stat { mode: S_IFCHR | 0o620, nlink: 1, ..Default::default() }
+    }
 }
 
 impl File for Stdout {
@@ -54,4 +60,8 @@ impl File for Stdout {
     fn seek(&self, _offset: isize, _whence: usize) {
         panic!("Unable to seek");
     }
+
+    fn stat(&self) -> Stat {
+        Stat { mode: S_IFCHR | 0o620, nlink: 1, ..Default::default() }
+    }
 }
\ No newline at end of file
@@ -41,6 +41,7 @@ pub fn rust_main() -> ! {
     println!("[kernel] Devices init success");
     trap::init();
     println!("[kernel] trap entry set");
+    timer::init();
     trap::enable_timer_int();
     timer::set_trig();
     println!("[kernel] timer interrupt enabled");
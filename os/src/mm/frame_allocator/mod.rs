@@ -3,7 +3,7 @@ use core::{ops::Deref, fmt::{Debug, Formatter, self}};
 
 use lazy_static::lazy_static;
 
-use crate::{sync::UThrCell, config::MEM_END, mm::address::PhysAddr};
+use crate::{sync::UThrCell, config::MEM_END, mm::{address::PhysAddr, swap::reclaim}};
 
 use super::address::PhysPageNum;
 use self::stack_frame_alloc::StackFrameAlloc;
@@ -61,9 +61,17 @@ pub fn init_frame_alloc() {
     )
 }
 
+// when memory runs out, a user page is swapped out to make room
 pub fn frame_alloc() -> Option<FrameTracker> {
-    FRAME_ALLOC.get_refmut().alloc()
-        .map(|ppn| FrameTracker::new(ppn))
+    loop {
+        let ppn = FRAME_ALLOC.get_refmut().alloc();
+        if let Some(ppn) = ppn {
+            return Some(FrameTracker::new(ppn));
+        }
+        if !reclaim() {
+            return None;
+        }
+    }
 }
 pub fn frame_dealloc(ppn: PhysPageNum) {
     FRAME_ALLOC.get_refmut().dealloc(ppn);
@@ -1,9 +1,11 @@
 use core::ops::Range;
-use alloc::collections::BTreeMap;
+use alloc::{collections::BTreeMap, sync::Arc, vec, vec::Vec};
 use bitflags::bitflags;
 
+use easyfs::vfs::VirtInode;
+
 use crate::config::PAGE_SIZE;
-use super::{address::{VirtPageNum, VirtPageRange, VirtAddr, PhysPageNum}, frame_allocator::{FrameTracker, frame_alloc}, pagetab::{PageTab, PTEFlags}};
+use super::{address::{VirtPageNum, VirtPageRange, VirtAddr, PhysPageNum}, frame_allocator::{FrameTracker, frame_alloc}, pagetab::{PageTab, PTEFlags}, swap::{track, Page, PagePin}};
 
 #[derive(Clone, Copy, PartialEq, Debug)]
 pub enum MapType {
@@ -20,11 +22,80 @@ bitflags! {
     }
 }
 
+// `len` bytes of a file from byte `offset` on, placed from `va` on
+#[derive(Clone, Copy)]
+pub struct FilePart {
+    pub va: usize,
+    pub offset: usize,
+    pub len: usize
+}
+
+// what the pages of a lazy area start with, zeroes where there is nothing
+#[derive(Clone)]
+pub enum AreaData {
+    // a file from byte `offset` on, placed from `va` on
+    File { va: usize, inode: Arc<VirtInode>, offset: usize },
+    // the segments of a program placed in the area, more than one if they
+    // share a page
+    Program { inode: Arc<VirtInode>, parts: Vec<FilePart> }
+}
+impl AreaData {
+    pub fn from_file(va_start: VirtAddr, inode: Arc<VirtInode>, offset: usize) -> Self {
+        Self::File { va: va_start.0, inode, offset }
+    }
+    // the parts of the file in the page at `page_va`, placed from the start of the page
+    fn page_parts(&self, page_va: usize) -> (Arc<VirtInode>, Vec<FilePart>) {
+        match self {
+            Self::File { va, inode, offset } => (Arc::clone(inode), vec![FilePart {
+                va: 0,
+                offset: offset + page_va - va,
+                len: PAGE_SIZE
+            }]),
+            Self::Program { inode, parts } => (Arc::clone(inode), parts.iter().filter_map(|part| {
+                let start = page_va.max(part.va);
+                let end = (part.va + part.len).min(page_va + PAGE_SIZE);
+                (start < end).then(|| FilePart {
+                    va: start - page_va,
+                    offset: part.offset + start - part.va,
+                    len: end - start
+                })
+            }).collect())
+        }
+    }
+}
+
+// a page of a file mapping to be read in before it is mapped; the device
+// may sleep, so this is done without holding the process
+pub struct PageRead {
+    inode: Arc<VirtInode>,
+    parts: Vec<FilePart>
+}
+impl PageRead {
+    pub fn read(&self) -> Option<FrameTracker> {
+        let frame = frame_alloc()?;
+        // the part past the end of the file stays zeroed
+        for part in self.parts.iter() {
+            self.inode.read_at(part.offset, &mut frame.get_bytes()[part.va..part.va + part.len]).ok()?;
+        }
+        Some(frame)
+    }
+}
+
+pub enum PageFault {
+    Fixed,
+    Denied,
+    Read(PageRead)
+}
+
 pub struct MapArea {
     range: VirtPageRange,
-    frames: BTreeMap<VirtPageNum, FrameTracker>,
+    // shared with the forks of a process until one of them writes
+    frames: BTreeMap<VirtPageNum, Arc<Page>>,
     map_type: MapType,
-    map_perm: MapPermission
+    map_perm: MapPermission,
+    // frames are allocated as pages are first touched
+    lazy: bool,
+    data: Option<AreaData>
 }
 impl MapArea {
     pub fn new(
@@ -37,56 +108,283 @@ impl MapArea {
             range,
             frames: BTreeMap::new(),
             map_type,
-            map_perm
+            map_perm,
+            lazy: false,
+            data: None
         }
     }
-    fn ins_one(&mut self, pagetab: &mut PageTab, vpn: VirtPageNum) {
-        let ppn: PhysPageNum;
+    pub fn new_lazy(
+        va_range: Range<VirtAddr>,
+        map_perm: MapPermission,
+        data: Option<AreaData>
+    ) -> Self {
+        Self {
+            lazy: true,
+            data,
+            ..Self::new(va_range, MapType::Framed, map_perm)
+        }
+    }
+    fn ins_one(&mut self, pagetab: &mut PageTab, vpn: VirtPageNum) -> bool {
         match self.map_type {
-            MapType::Identical => {
-                ppn = PhysPageNum(vpn.0);
-            }
+            MapType::Identical => pagetab.ins(vpn, PhysPageNum(vpn.0), self.pte_flags()),
             MapType::Framed => {
-                let frame = frame_alloc().unwrap();
-                ppn = frame.ppn;
-                self.frames.insert(vpn, frame);
+                let Some(frame) = frame_alloc() else {
+                    return false;
+                };
+                let page = Page::new(vpn, frame, pagetab.get_atp_token());
+                self.add_page(pagetab, vpn, page)
             }
         }
-        let flags = PTEFlags::from_bits(self.map_perm.bits).unwrap();
-        pagetab.ins(vpn, ppn, flags)
     }
     fn del_one(&mut self, pagetab: &mut PageTab, vpn: VirtPageNum) {
-        if let MapType::Framed = self.map_type {
-            self.frames.remove(&vpn);
+        // pages of a lazy area may never have been touched
+        let page = self.frames.remove(&vpn);
+        if self.map_type == MapType::Framed && page.is_none() {
+            return;
+        }
+        // nor mapped, if they are in swap or the area allows no access
+        let pte = pagetab.find(vpn).map(|_| pagetab.del(vpn));
+        if let Some(page) = page {
+            page.remove_owner(pagetab.get_atp_token());
+            if let Some(pte) = pte {
+                page.unmapped(pte);
+            }
         }
-        pagetab.del(vpn)
     }
-    pub fn ins(&mut self, pagetab: &mut PageTab) {
+    // a new page of the area, user pages may be swapped out from now on;
+    // false, and the page dropped, if it could not be mapped
+    fn add_page(&mut self, pagetab: &mut PageTab, vpn: VirtPageNum, page: Arc<Page>) -> bool {
+        if !self.map_page(pagetab, vpn, &page) {
+            return false;
+        }
+        if self.is_user() {
+            track(&page);
+        }
+        self.frames.insert(vpn, page);
+        true
+    }
+    // map a page that is in memory with the flags of the area,
+    // false if there was no frame for the page table
+    fn map_page(&self, pagetab: &mut PageTab, vpn: VirtPageNum, page: &Page) -> bool {
+        let (Some(ppn), Some(flags)) = (page.ppn(), self.page_flags(page)) else {
+            return true;
+        };
+        // a new page table frame may be made by swapping a page out, not this one
+        let _pin = PagePin::new(ppn);
+        pagetab.ins(vpn, ppn, flags)
+    }
+    // false if memory ran out, with nothing of the area left behind
+    pub fn ins(&mut self, pagetab: &mut PageTab) -> bool {
+        if self.lazy {
+            return true;
+        }
         for vpn in self.range {
-            self.ins_one(pagetab, vpn);
+            if !self.ins_one(pagetab, vpn) {
+                self.del(pagetab);
+                return false;
+            }
         }
+        true
     }
+    // only the pages of a framed area that were touched have anything to
+    // undo, however large the area
     pub fn del(&mut self, pagetab: &mut PageTab) {
-        for vpn in self.range {
+        let vpns: Vec<VirtPageNum> = match self.map_type {
+            MapType::Identical => self.range.collect(),
+            MapType::Framed => self.frames.keys().copied().collect()
+        };
+        for vpn in vpns {
             self.del_one(pagetab, vpn);
         }
     }
-    pub fn copy_data(&mut self, pagetab: &mut PageTab, data: &[u8]) {
-        assert_eq!(self.map_type, MapType::Framed);
-        let mut start: usize = 0;
-        let mut curr_vpn = self.range.start;
-        let len = data.len();
-        loop {
-            let src = &data[start..len.min(start + PAGE_SIZE)];
-            let dst = &mut pagetab.find(curr_vpn)
-                .unwrap().ppn().get_bytes()[..src.len()];
-            dst.copy_from_slice(src);
-            start += src.len();
-            if start >= len {
-                break
+    fn pte_flags(&self) -> PTEFlags {
+        PTEFlags::from_bits(self.map_perm.bits).unwrap()
+    }
+    // None for an area allowing no access, whose pages are not mapped
+    // as a pte without R, W and X would point to another table
+    fn page_flags(&self, page: &Page) -> Option<PTEFlags> {
+        if !self.map_perm.intersects(MapPermission::R | MapPermission::W | MapPermission::X) {
+            return None;
+        }
+        // shared pages are written to a copy
+        if self.is_cow() && page.shared() {
+            Some(self.pte_flags() - PTEFlags::W)
+        } else {
+            Some(self.pte_flags())
+        }
+    }
+    // copy-on-write pages are user pages of a writable area,
+    // the trap context is written by the kernel and has to stay private
+    fn is_cow(&self) -> bool {
+        self.map_type == MapType::Framed && self.map_perm.contains(MapPermission::U | MapPermission::W)
+    }
+    // the same area in the address space of a child: user frames are shared,
+    // and writable ones are mapped read-only on both sides until written.
+    // None if memory ran out, with nothing left behind in `child_pagetab`
+    pub fn fork(&self, pagetab: &mut PageTab, child_pagetab: &mut PageTab) -> Option<Self> {
+        let mut child = self.clone();
+        if self.map_type == MapType::Framed && !self.map_perm.contains(MapPermission::U) {
+            if !child.ins(child_pagetab) {
+                return None;
+            }
+            for vpn in self.range {
+                let src = pagetab.find(vpn).unwrap().ppn();
+                let dst = child_pagetab.find(vpn).unwrap().ppn();
+                dst.get_bytes().copy_from_slice(src.get_bytes());
+            }
+            return Some(child);
+        }
+        let mapped = match self.map_type {
+            MapType::Identical => {
+                let mut range = self.range;
+                range.all(|vpn| child_pagetab.ins(vpn, PhysPageNum(vpn.0), self.pte_flags()))
+            }
+            MapType::Framed => self.frames.iter().all(|(vpn, page)| {
+                // shared before anything is mapped, so it stays where it is meanwhile
+                page.add_owner(child_pagetab.get_atp_token());
+                child.frames.insert(*vpn, Arc::clone(page));
+                if let (Some(ppn), Some(flags)) = (page.ppn(), self.page_flags(page)) {
+                    if self.is_cow() && pagetab.find(*vpn).is_some() {
+                        page.unmapped(pagetab.remap(*vpn, ppn, flags));
+                    }
+                }
+                child.map_page(child_pagetab, *vpn, page)
+            })
+        };
+        if !mapped {
+            child.del(child_pagetab);
+            return None;
+        }
+        Some(child)
+    }
+    // a page not mapped here: one in memory or swap, or on the first touch
+    // of a page of a lazy area, a zeroed frame with its part of the data
+    pub fn fill(&mut self, pagetab: &mut PageTab, vpn: VirtPageNum) -> PageFault {
+        if let Some(page) = self.frames.get(&vpn).cloned() {
+            if page.ppn().is_none() {
+                let Some(frame) = frame_alloc() else {
+                    return PageFault::Denied;
+                };
+                if !page.swap_in(frame) {
+                    return PageFault::Denied;
+                }
+            }
+            if !self.map_page(pagetab, vpn, &page) {
+                return PageFault::Denied;
             }
-            curr_vpn.0 += 1;
+            return PageFault::Fixed;
+        }
+        if !self.lazy {
+            return PageFault::Denied;
+        }
+        let page_va = VirtAddr::from(vpn).0;
+        if let Some((inode, parts)) = self.data.as_ref().map(|data| data.page_parts(page_va)) {
+            if !parts.is_empty() {
+                return PageFault::Read(PageRead { inode, parts });
+            }
+        }
+        let Some(frame) = frame_alloc() else {
+            return PageFault::Denied;
+        };
+        if !self.install(pagetab, vpn, frame) {
+            return PageFault::Denied;
+        }
+        PageFault::Fixed
+    }
+    // map a frame filled for a page not touched before
+    pub fn install(&mut self, pagetab: &mut PageTab, vpn: VirtPageNum, frame: FrameTracker) -> bool {
+        if self.frames.contains_key(&vpn) {
+            return true;
         }
+        let page = Page::new(vpn, frame, pagetab.get_atp_token());
+        self.add_page(pagetab, vpn, page)
+    }
+    // change the permission of the area and of its pages in `pagetab`
+    pub fn protect(&mut self, pagetab: &mut PageTab, perm: MapPermission) {
+        self.map_perm = perm;
+        for (vpn, page) in self.frames.iter() {
+            // pages in swap get the new flags when they come back
+            let Some(ppn) = page.ppn() else {
+                continue;
+            };
+            match (self.page_flags(page), pagetab.find(*vpn).is_some()) {
+                (Some(flags), true) => page.unmapped(pagetab.remap(*vpn, ppn, flags)),
+                // left to the next fault if there is no frame for the page table
+                (Some(_), false) => { self.map_page(pagetab, *vpn, page); }
+                (None, true) => page.unmapped(pagetab.del(*vpn)),
+                (None, false) => {}
+            }
+        }
+    }
+    // take in a segment sharing a page with the area
+    pub fn merge(&mut self, perm: MapPermission, parts: &[FilePart]) {
+        self.map_perm |= perm;
+        if let Some(AreaData::Program { parts: own, .. }) = &mut self.data {
+            own.extend_from_slice(parts);
+        }
+    }
+    // grow a lazy area up to `end`, the new pages are filled when touched
+    pub fn extend_to(&mut self, end: VirtPageNum) {
+        assert!(self.lazy && end >= self.range.end);
+        self.range.end = end;
+    }
+    // cut the area at `at`, keeping the part below it
+    pub fn split_off(&mut self, at: VirtPageNum) -> Self {
+        let mut tail = self.clone();
+        tail.range.start = at;
+        tail.frames = self.frames.split_off(&at);
+        self.range.end = at;
+        tail
+    }
+    // a write to a shared page: copy it unless no other process uses it any more
+    pub fn copy_on_write(&mut self, pagetab: &mut PageTab, vpn: VirtPageNum) -> bool {
+        if !self.is_cow() {
+            return false;
+        }
+        let flags = self.pte_flags();
+        let page = Arc::clone(self.frames.get(&vpn).unwrap());
+        if !page.shared() {
+            page.unmapped(pagetab.remap(vpn, page.ppn().unwrap(), flags));
+            return true;
+        }
+        // a shared page is not swapped out while it is copied
+        let Some(frame) = frame_alloc() else {
+            return false;
+        };
+        page.copy_to(&frame);
+        let token = pagetab.get_atp_token();
+        page.remove_owner(token);
+        let copy = Page::new(vpn, frame, token);
+        page.unmapped(pagetab.remap(vpn, copy.ppn().unwrap(), flags));
+        track(&copy);
+        self.frames.insert(vpn, copy);
+        true
+    }
+    pub fn permits(&self, access: MapPermission) -> bool {
+        self.map_perm.contains(access)
+    }
+    pub fn contains(&self, vpn: VirtPageNum) -> bool {
+        self.range.start <= vpn && vpn < self.range.end
+    }
+    pub fn overlaps(&self, range: VirtPageRange) -> bool {
+        self.range.start < range.end && range.start < self.range.end
+    }
+    // areas the process may unmap or protect, not the trap context
+    pub fn is_user(&self) -> bool {
+        self.map_perm.contains(MapPermission::U)
+    }
+    // zero-filled pages with permission `perm`, like those of the heap
+    pub fn is_anonymous(&self, perm: MapPermission) -> bool {
+        self.lazy && self.data.is_none() && self.map_perm == perm
+    }
+    // set up by the kernel, like the stacks of the threads and their trap
+    // contexts; all the areas a process maps itself are lazy
+    pub fn is_fixed(&self) -> bool {
+        !self.lazy
+    }
+    // the pages the area has in `range`
+    pub fn pages_in(&self, range: VirtPageRange) -> usize {
+        self.range.end.min(range.end).0.saturating_sub(self.range.start.max(range.start).0)
     }
     pub fn get_vpn_range(&self) -> VirtPageRange {
         self.range
@@ -101,7 +399,9 @@ impl Clone for MapArea {
             range: self.range.clone(),
             frames: BTreeMap::new(),
             map_type: self.map_type,
-            map_perm: self.map_perm
+            map_perm: self.map_perm,
+            lazy: self.lazy,
+            data: self.data.clone()
         }
     }
 }
\ No newline at end of file
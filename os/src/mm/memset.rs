@@ -1,12 +1,13 @@
-use core::{ops::Range, arch::asm};
-use alloc::{vec::Vec, sync::Arc};
+use core::{cmp, ops::Range, arch::asm, sync::atomic::{AtomicUsize, Ordering}};
+use alloc::{vec, vec::Vec, sync::Arc};
 
-use elf::{ElfBytes, endian::AnyEndian, abi::{PT_LOAD, PF_W, PF_R, PF_X}};
+use easyfs::vfs::VirtInode;
+use elf::{abi::{EI_NIDENT, PF_R, PF_W, PF_X, PT_LOAD}, endian::AnyEndian, file::{parse_ident, FileHeader, ELF64_EHDR_TAILSIZE}, parse::ParseAt, segment::{ProgramHeader, SegmentTable}};
 use lazy_static::lazy_static;
 use riscv::register::satp;
 
-use crate::{config::{ADDR_TRAMPOLINE, MEM_END, MMIO, PAGE_SIZE}, sync::UThrCell};
-use super::{address::{VirtAddr, PhysAddr, VirtPageNum}, pagetab::{PageTab, PTEFlags, PageTabEntry}, memarea::*};
+use crate::{config::{ADDR_TRAMPOLINE, MEM_END, MMAP_BASE, MMIO, PAGE_SIZE, USER_SPACE_END, USER_STACK_BASE}, sync::UThrCell};
+use super::{address::{VirtAddr, PhysAddr, VirtPageNum, VirtPageRange}, frame_allocator::FrameTracker, pagetab::{PageTab, PTEFlags, PageTabEntry}, memarea::*};
 
 extern "C" {
     fn stext(); fn etext();
@@ -19,80 +20,84 @@ extern "C" {
 
 pub struct MemSet {
     pagetab: PageTab,
-    areas: Vec<MapArea>
+    areas: Vec<MapArea>,
+    // the heap runs from `heap_start` to the program break
+    heap_start: usize,
+    brk: usize
 }
 impl MemSet {
-    pub fn new_empty() -> Self {
-        Self {
-            pagetab: PageTab::new(),
-            areas: Vec::new()
-        }
+    // None where memory ran out, here and below
+    fn new_empty() -> Option<Self> {
+        Some(Self {
+            pagetab: PageTab::new()?,
+            areas: Vec::new(),
+            heap_start: 0,
+            brk: 0
+        })
     }
-    fn push(&mut self, mut map_area: MapArea, data: Option<&[u8]>) {
-        map_area.ins(&mut self.pagetab);
-        if let Some(data) = data {
-            map_area.copy_data(&mut self.pagetab, data);
-        }
+    fn push(&mut self, mut map_area: MapArea) -> Option<()> {
+        map_area.ins(&mut self.pagetab).then_some(())?;
         self.areas.push(map_area);
+        Some(())
     }
     pub fn insert_framed_area(
         &mut self,
         va_range: Range<VirtAddr>,
         perm: MapPermission
-    ) {
+    ) -> Option<()> {
         self.push(MapArea::new(
             va_range,
             MapType::Framed,
             perm
-        ), None);
+        ))
     }
-    fn map_trampoline(&mut self) {
+    fn map_trampoline(&mut self) -> Option<()> {
         self.pagetab.ins(
             VirtAddr::from(ADDR_TRAMPOLINE).into(),
             PhysAddr::from(strampoline as usize).into(),
             PTEFlags::R | PTEFlags::X
-        );
+        ).then_some(())
     }
-    pub fn new_kernel() -> Self {
-        let mut memset = Self::new_empty();
+    pub fn new_kernel() -> Option<Self> {
+        let mut memset = Self::new_empty()?;
 
         //  trampoline (Not collected by memarea)
-        memset.map_trampoline();
+        memset.map_trampoline()?;
 
         //  text
         memset.push(MapArea::new(
             (stext as usize).into()..(etext as usize).into(),
             MapType::Identical,
             MapPermission::R | MapPermission::X
-        ), None);
+        ))?;
 
         //  rodata
         memset.push(MapArea::new(
             (srodata as usize).into()..(erodata as usize).into(),
             MapType::Identical,
             MapPermission::R
-        ), None);
+        ))?;
 
         //  data
         memset.push(MapArea::new(
             (sdata as usize).into()..(edata as usize).into(),
             MapType::Identical,
             MapPermission::R | MapPermission::W
-        ), None);
+        ))?;
 
         //  bss
         memset.push(MapArea::new(
             (sbss_stack as usize).into()..(ebss as usize).into(),
             MapType::Identical,
             MapPermission::R | MapPermission::W
-        ), None);
+        ))?;
 
         //  physical mem
         memset.push(MapArea::new(
             (ekernel as usize).into()..(MEM_END).into(),
             MapType::Identical,
             MapPermission::R | MapPermission::W
-        ), None);
+        ))?;
 
         // MMIO
         for pair in MMIO {
@@ -102,28 +107,39 @@ impl MemSet {
                 va_start..va_end, 
                 MapType::Identical,
                 MapPermission::R | MapPermission::W
-                ), None
-            );
+            ))?;
         }
-        memset
+        Some(memset)
+    }
+    //  an address space with nothing but the trampoline, for a program
+    pub fn new_user() -> Option<Self> {
+        let mut memset = Self::new_empty()?;
+        memset.map_trampoline()?;
+        Some(memset)
     }
-    //  Memset, user_sp, entry point
-    pub fn from_elf(elf_data: &[u8]) -> (Self, usize, usize) {
-        let mut memset = Self::new_empty();
-        //  trampoline
-        memset.map_trampoline();
+    //  user_sp, entry point, None if `inode` is not a program; only the headers
+    //  are read here, the segments are read from the file as their pages are first touched
+    pub fn load_elf(&mut self, inode: &Arc<VirtInode>) -> Option<(usize, usize)> {
+        let mut ehdr = [0u8; EI_NIDENT + ELF64_EHDR_TAILSIZE];
+        read_exact(inode, 0, &mut ehdr)?;
+        let ident = parse_ident::<AnyEndian>(&ehdr).ok()?;
+        let ehdr = FileHeader::parse_tail(ident, &ehdr[EI_NIDENT..]).ok()?;
+        let entsize = ProgramHeader::validate_entsize(ehdr.class, ehdr.e_phentsize as usize).ok()?;
+        // a page of program headers is plenty
+        let size = entsize * ehdr.e_phnum as usize;
+        if size > PAGE_SIZE {
+            return None;
+        }
+        let mut phdrs = vec![0u8; size];
+        read_exact(inode, ehdr.e_phoff as usize, &mut phdrs)?;
 
         //  elf sections
         let mut elf_end_vpn = VirtPageNum(0);
-        let elf = ElfBytes::<AnyEndian>::minimal_parse(elf_data)
-            .expect("[kernel] parsing error encountered for elf.");
-        for phdr in elf
-            .segments()
-            .unwrap()
+        for phdr in SegmentTable::new(ehdr.endianness, ehdr.class, &phdrs)
             .iter()
             .filter(|phdr| phdr.p_type == PT_LOAD)
         {
-            let va_start:VirtAddr = (phdr.p_vaddr as usize).into();
+            let mut va_start:VirtAddr = (phdr.p_vaddr as usize).into();
             let va_end:VirtAddr = ((phdr.p_vaddr + phdr.p_memsz) as usize).into();
             let map_perm = MapPermission::from_bits_truncate(
                 (1u8 << 4) |    //  MapPermission::U
@@ -131,23 +147,41 @@ impl MemSet {
                  ((phdr.p_flags & PF_W) << 1) |
                  ((phdr.p_flags & PF_X) << 3)) as u8
             );
-            let map_area = MapArea::new(
+            let parts: Vec<FilePart> = (phdr.p_filesz > 0).then(|| FilePart {
+                va: phdr.p_vaddr as usize,
+                offset: phdr.p_offset as usize,
+                len: phdr.p_filesz.min(phdr.p_memsz) as usize
+            }).into_iter().collect();
+            // a page shared with the segment before gets an area of its own,
+            // with the permissions and the data of both
+            let first = va_start.vpn_floor();
+            if let Some(last) = self.areas.last_mut().filter(|area| area.contains(first)) {
+                if last.get_start_vpn() == first {
+                    last.merge(map_perm, &parts);
+                } else {
+                    let mut shared = last.split_off(first);
+                    shared.merge(map_perm, &parts);
+                    self.areas.push(shared);
+                }
+                va_start = VirtAddr::from(VirtPageNum(first.0 + 1));
+            }
+            elf_end_vpn = elf_end_vpn.max(va_end.vpn_ceil());
+            if va_start >= va_end {
+                continue;
+            }
+            self.insert_lazy_area(
                 va_start..va_end,
-                MapType::Framed,
-                map_perm
-            );
-            elf_end_vpn = va_end.vpn_ceil();
-            memset.push(map_area, Some(elf.segment_data(&phdr)
-                .expect("[kernel] parsing error encountered for elf."))
+                map_perm,
+                Some(AreaData::Program { inode: Arc::clone(inode), parts })
             );
         }
 
-        let user_stack_bottom = VirtAddr::from(elf_end_vpn).0 + PAGE_SIZE;
-        (
-            memset,
-            user_stack_bottom,          //  user_stack_bottom
-            elf.ehdr.e_entry as usize   //  entry_point
-        )
+        self.heap_start = VirtAddr::from(elf_end_vpn).0;
+        self.brk = self.heap_start;
+        Some((
+            USER_STACK_BASE,            //  user_stack_bottom
+            ehdr.e_entry as usize       //  entry_point
+        ))
     }
     pub fn activate(&self) {
         let satp = self.pagetab.get_atp_token();
@@ -167,6 +201,140 @@ impl MemSet {
     pub fn get_atp_token(&self) -> usize {
         self.pagetab.get_atp_token()
     }
+    // the address space of a child, sharing the user pages with this one
+    pub fn fork(&mut self) -> Option<Self> {
+        let mut memset = Self::new_user()?;
+        memset.heap_start = self.heap_start;
+        memset.brk = self.brk;
+        for area in self.areas.iter() {
+            let new_area = area.fork(&mut self.pagetab, &mut memset.pagetab)?;
+            memset.areas.push(new_area);
+        }
+        Some(memset)
+    }
+    // resolve a fault of an `access` (R, W or X, with U from user mode)
+    // at `vpn`, denied if the area there does not allow it
+    pub fn handle_fault(&mut self, vpn: VirtPageNum, access: MapPermission) -> PageFault {
+        let Some(area) = self.areas.iter_mut().find(|area| area.contains(vpn)) else {
+            return PageFault::Denied;
+        };
+        if !area.permits(access) {
+            return PageFault::Denied;
+        }
+        match self.pagetab.find(vpn) {
+            Some(pte) if access.contains(MapPermission::W) && !pte.writable() => {
+                if area.copy_on_write(&mut self.pagetab, vpn) { PageFault::Fixed } else { PageFault::Denied }
+            }
+            // stale translation, already resolved
+            Some(_) => PageFault::Fixed,
+            None => area.fill(&mut self.pagetab, vpn)
+        }
+    }
+    // map a page read in for a fault, unless its area went away meanwhile
+    pub fn install(&mut self, vpn: VirtPageNum, frame: FrameTracker) -> bool {
+        match self.areas.iter_mut().find(|area| area.contains(vpn)) {
+            Some(area) => area.install(&mut self.pagetab, vpn, frame),
+            None => true
+        }
+    }
+    pub fn insert_lazy_area(
+        &mut self,
+        va_range: Range<VirtAddr>,
+        perm: MapPermission,
+        data: Option<AreaData>
+    ) {
+        // nothing is mapped yet, which can't fail
+        self.push(MapArea::new_lazy(va_range, perm, data));
+    }
+    // the lowest `pages` free pages from MMAP_BASE on
+    pub fn find_free(&self, pages: usize) -> Option<VirtPageNum> {
+        let mut ranges: Vec<VirtPageRange> = self.areas.iter().map(|area| area.get_vpn_range()).collect();
+        ranges.sort_by_key(|range| range.start);
+        let mut start = VirtAddr::from(MMAP_BASE).vpn_floor();
+        for range in ranges {
+            if range.start.0 >= start.0 + pages {
+                break;
+            }
+            start = start.max(range.end);
+        }
+        (start.0 + pages <= VirtAddr::from(USER_SPACE_END).vpn_floor().0).then_some(start)
+    }
+    // cut the area holding `at` in two, so that an area starts there
+    fn split_at(&mut self, at: VirtPageNum) {
+        if let Some(area) = self.areas.iter_mut()
+            .find(|area| area.is_user() && area.contains(at) && area.get_start_vpn() != at)
+        {
+            let tail = area.split_off(at);
+            self.areas.push(tail);
+        }
+    }
+    // true if `range` reaches into an area the process may not change
+    pub fn is_fixed(&self, range: VirtPageRange) -> bool {
+        self.areas.iter().any(|area| area.is_fixed() && area.overlaps(range))
+    }
+    pub fn unmap(&mut self, range: VirtPageRange) {
+        self.split_at(range.start);
+        self.split_at(range.end);
+        let mut idx = 0;
+        while idx < self.areas.len() {
+            if self.areas[idx].is_user() && self.areas[idx].overlaps(range) {
+                let mut area = self.areas.swap_remove(idx);
+                area.del(&mut self.pagetab);
+            } else {
+                idx += 1;
+            }
+        }
+    }
+    // false if part of `range` is not mapped
+    pub fn protect(&mut self, range: VirtPageRange, perm: MapPermission) -> bool {
+        // the areas do not overlap, so their pages in `range` add up
+        let covered: usize = self.areas.iter()
+            .filter(|area| area.is_user())
+            .map(|area| area.pages_in(range))
+            .sum();
+        if covered != range.end.0 - range.start.0 {
+            return false;
+        }
+        self.split_at(range.start);
+        self.split_at(range.end);
+        for area in self.areas.iter_mut().filter(|area| area.is_user() && area.overlaps(range)) {
+            area.protect(&mut self.pagetab, perm);
+        }
+        true
+    }
+    // move the program break to `new_brk`, the break after the move,
+    // which stays where it was if the heap cannot grow that far
+    pub fn brk(&mut self, new_brk: usize) -> usize {
+        // leave a guard page below the first stack
+        if new_brk < self.heap_start || new_brk > USER_STACK_BASE - PAGE_SIZE {
+            return self.brk;
+        }
+        let old_end = VirtAddr::from(self.brk).vpn_ceil();
+        let new_end = VirtAddr::from(new_brk).vpn_ceil();
+        let perm = MapPermission::U | MapPermission::R | MapPermission::W;
+        match new_end.cmp(&old_end) {
+            cmp::Ordering::Greater => {
+                let range = VirtPageRange { start: old_end, end: new_end };
+                if self.areas.iter().any(|area| area.overlaps(range)) {
+                    return self.brk;
+                }
+                // the heap is one area, grown in place; a new one is only needed
+                // at first or if the process unmapped the top of the heap
+                let heap_start = VirtAddr::from(self.heap_start).vpn_floor();
+                match self.areas.iter_mut().find(|area| {
+                    area.get_vpn_range().end == old_end && area.get_start_vpn() >= heap_start && area.is_anonymous(perm)
+                }) {
+                    Some(heap) => heap.extend_to(new_end),
+                    None => self.insert_lazy_area(old_end.into()..new_end.into(), perm, None)
+                }
+            }
+            // cuts the top off the heap area, or drops it if it is all gone
+            cmp::Ordering::Less => self.unmap(VirtPageRange { start: new_end, end: old_end }),
+            cmp::Ordering::Equal => {}
+        }
+        self.brk = new_brk;
+        new_brk
+    }
     pub fn del_area_by_start_vpn(&mut self, start_vpn: VirtPageNum) {
         if let Some((idx, area)) = self
             .areas
@@ -179,31 +347,36 @@ impl MemSet {
         }
     }
 }
-impl Clone for MemSet {
-    fn clone(&self) -> Self {
-        let mut memset = Self::new_empty();
-        memset.map_trampoline();
-        for area in self.areas.iter() {
-            let new_area = area.clone();
-            memset.push(new_area, None);
-            for vpn in area.get_vpn_range() {
-                let src_ppn = self.find(vpn).unwrap().ppn();
-                let dst_ppn = memset.find(vpn).unwrap().ppn();
-                dst_ppn.get_bytes().copy_from_slice(&src_ppn.get_bytes());
-            }
-        }
-        memset
+// a replaced address space lets go of its pages like an exited one
+impl Drop for MemSet {
+    fn drop(&mut self) {
+        self.mem_recycle();
     }
 }
 
+// `buf` filled from byte `offset` of the file, None if it ends before
+fn read_exact(inode: &VirtInode, offset: usize, buf: &mut [u8]) -> Option<()> {
+    (inode.read_at(offset, buf).ok()? == buf.len()).then_some(())
+}
+
 lazy_static! {
     pub static ref KERN_SPACE: Arc<UThrCell<MemSet>> = Arc::new(
         unsafe {
-            UThrCell::new(MemSet::new_kernel())
+            UThrCell::new(MemSet::new_kernel().expect("no memory for the kernel address space"))
         }
     );
 }
 
+// the kernel page table never moves, so devices find it without
+// borrowing KERN_SPACE, which a frame allocation may be holding
+static KERN_TOKEN: AtomicUsize = AtomicUsize::new(0);
+
 pub fn kern_mem_init() {
-    KERN_SPACE.get_refmut().activate();
+    let kern_space = KERN_SPACE.get_refmut();
+    KERN_TOKEN.store(kern_space.get_atp_token(), Ordering::Relaxed);
+    kern_space.activate();
+}
+
+pub fn kern_token() -> usize {
+    KERN_TOKEN.load(Ordering::Relaxed)
 }
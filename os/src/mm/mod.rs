@@ -4,6 +4,7 @@ pub mod pagetab;
 pub mod frame_allocator;
 pub mod memset;
 pub mod memarea;
+pub mod swap;
 
 pub fn init() {
     heap_allocator::init_heap();
@@ -1,10 +1,13 @@
 use alloc::{vec, string::String};
 use alloc::vec::Vec;
+use core::mem::{size_of, MaybeUninit};
+use core::ops::{Deref, DerefMut};
 use bitflags::*;
 
-use crate::config::{PAGE_SIZE, PAGE_SIZE_BITS};
+use crate::config::{PAGE_SIZE, PAGE_SIZE_BITS, USER_SPACE_END};
 
-use super::{address::{PhysPageNum, PPN_MASK, VirtPageNum, VirtAddr, PhysAddr}, frame_allocator::{FrameTracker, frame_alloc}};
+use crate::task::handle_user_fault;
+use super::{memarea::MapPermission, address::{PhysPageNum, PPN_MASK, VirtPageNum, VirtAddr, PhysAddr}, frame_allocator::{FrameTracker, frame_alloc}, swap::PagePin};
 
 bitflags! {
     pub struct PTEFlags: u8 {
@@ -48,6 +51,12 @@ impl PageTabEntry {
     pub fn executable(&self) -> bool {
         (self.flags() & PTEFlags::X) != PTEFlags::empty()
     }
+    pub fn accessed(&self) -> bool {
+        (self.flags() & PTEFlags::A) != PTEFlags::empty()
+    }
+    pub fn dirty(&self) -> bool {
+        (self.flags() & PTEFlags::D) != PTEFlags::empty()
+    }
 }
 
 pub struct PageTab {
@@ -55,22 +64,44 @@ pub struct PageTab {
     frames: Vec<FrameTracker>
 }
 impl PageTab {
-    pub fn new() -> Self {
-        let frame = frame_alloc().unwrap();
-        Self {
+    pub fn new() -> Option<Self> {
+        let frame = frame_alloc()?;
+        Some(Self {
             root_ppn: frame.ppn,
             frames: vec![frame]
-        }
+        })
     }
-    pub fn ins(&mut self, vpn: VirtPageNum, ppn: PhysPageNum, flags: PTEFlags) {
-        let pte = self.find_pte_create(vpn).unwrap();
+    // false if there was no frame for a table on the way
+    pub fn ins(&mut self, vpn: VirtPageNum, ppn: PhysPageNum, flags: PTEFlags) -> bool {
+        let Some(pte) = self.find_pte_create(vpn) else {
+            return false;
+        };
         assert!(!pte.is_valid(), "vpn {:?} already exists in page table, ppn = {:?}, flag = {:?}. ", vpn, pte.ppn(), pte.flags());
         *pte = PageTabEntry::new(ppn, PTEFlags::V | flags);
+        true
     }
-    pub fn del(&mut self, vpn: VirtPageNum) {
+    // the entry taken out, with the accessed and dirty bits it had
+    pub fn del(&mut self, vpn: VirtPageNum) -> PageTabEntry {
         let pte = self.find_pte(vpn).unwrap();
         assert!(pte.is_valid(), "vpn {:?} already invalid", vpn);
-        *pte = PageTabEntry::zeros();
+        core::mem::replace(pte, PageTabEntry::zeros())
+    }
+    // point a mapped page somewhere else, or change its flags
+    pub fn remap(&mut self, vpn: VirtPageNum, ppn: PhysPageNum, flags: PTEFlags) -> PageTabEntry {
+        let pte = self.find_pte(vpn).unwrap();
+        assert!(pte.is_valid(), "vpn {:?} is not mapped", vpn);
+        core::mem::replace(pte, PageTabEntry::new(ppn, PTEFlags::V | flags))
+    }
+    pub fn clear_accessed(&mut self, vpn: VirtPageNum) {
+        if let Some(pte) = self.find_pte(vpn) {
+            pte.bits &= !(PTEFlags::A.bits as usize);
+        }
+    }
+    // the kernel wrote to the page, which the hardware does not record
+    fn mark_dirty(&self, vpn: VirtPageNum) {
+        if let Some(pte) = self.find_pte(vpn) {
+            pte.bits |= (PTEFlags::A | PTEFlags::D).bits as usize;
+        }
     }
     pub fn from_token(satp: usize) -> Self {
         Self {
@@ -86,41 +117,87 @@ impl PageTab {
         let offset = va.page_offset();
         Some(PhysAddr::from((ppn.0 << PAGE_SIZE_BITS) | offset))
     }
-    pub fn trans_cstr(&self, ptr: *const u8) -> String {
+    // a page of the running process the kernel is about to access, with a
+    // private copy made first if it is to be written; None if the process
+    // may not access it that way. The page stays in memory while the pin is held
+    fn user_page(&self, vpn: VirtPageNum, write: bool) -> Option<(PhysPageNum, PagePin)> {
+        let usable = |pte: &PageTabEntry| pte.flags().contains(PTEFlags::U) && (!write || pte.writable());
+        let ppn = match self.find(vpn) {
+            Some(pte) if usable(&pte) => pte.ppn(),
+            _ => {
+                let access = if write { MapPermission::W } else { MapPermission::R };
+                if !handle_user_fault(self.get_atp_token(), vpn, access) {
+                    return None;
+                }
+                self.find(vpn).filter(usable)?.ppn()
+            }
+        };
+        // a copy left in swap would be stale
+        if write {
+            self.mark_dirty(vpn);
+        }
+        Some((ppn, PagePin::new(ppn)))
+    }
+    pub fn trans_cstr(&self, ptr: *const u8) -> Option<String> {
         let mut string = String::new();
         let mut va = VirtAddr::from(ptr as usize);
         loop {
-            let ch: u8 = *(self.trans_va(va).unwrap().get_mut());
-            if ch == 0 {
-                break;
-            } else {
+            let (ppn, _pin) = self.user_page(va.vpn_floor(), false)?;
+            for &ch in &ppn.get_bytes()[va.page_offset()..] {
+                if ch == 0 {
+                    return Some(string);
+                }
                 string.push(ch as char);
-                va.0 += 1;
             }
+            va = VirtAddr::from(VirtPageNum(va.vpn_floor().0 + 1));
         }
-        string
     }
 
-    #[allow(unused)]
-    pub fn trans_ref<T>(&self, ptr: *const T) -> &'static T {
-        self.trans_va((ptr as usize).into()).unwrap().get_ref()
+    // a copy of a value the process passed in
+    pub fn trans_ref<T: Copy>(&self, ptr: *const T) -> Option<T> {
+        let mut value = MaybeUninit::<T>::uninit();
+        let bytes = unsafe {
+            core::slice::from_raw_parts_mut(value.as_mut_ptr() as *mut u8, size_of::<T>())
+        };
+        let buf = self.trans_bytes_buffer(ptr as *const u8, bytes.len())?;
+        let mut copied = 0;
+        for part in buf.buffers.iter() {
+            bytes[copied..copied + part.len()].copy_from_slice(part);
+            copied += part.len();
+        }
+        Some(unsafe { value.assume_init() })
     }
-    pub fn trans_mut<T>(&self, ptr: *mut T) -> &'static mut T {
-        self.trans_va((ptr as usize).into()).unwrap().get_mut()
+    // a value of the process the kernel writes to, not crossing a page
+    pub fn trans_mut<T>(&self, ptr: *mut T) -> Option<UserMut<T>> {
+        let va = VirtAddr::from(ptr as usize);
+        if va.page_offset() + size_of::<T>() > PAGE_SIZE {
+            return None;
+        }
+        let (ppn, pin) = self.user_page(va.vpn_floor(), true)?;
+        let pa = PhysAddr::from((ppn.0 << PAGE_SIZE_BITS) | va.page_offset());
+        Some(UserMut { value: pa.get_mut(), _pin: pin })
     }
 
-    pub fn trans_bytes_buffer(&self, ptr: *const u8, len: usize) -> Vec<&'static mut [u8]> {
+    // a user buffer the kernel reads from
+    pub fn trans_bytes_buffer(&self, ptr: *const u8, len: usize) -> Option<UserBuffer> {
+        self.trans_buffer(ptr, len, false)
+    }
+    // a user buffer the kernel fills
+    pub fn trans_bytes_buffer_mut(&self, ptr: *const u8, len: usize) -> Option<UserBuffer> {
+        self.trans_buffer(ptr, len, true)
+    }
+    // the pages stay in memory as long as the buffer is held
+    fn trans_buffer(&self, ptr: *const u8, len: usize, write: bool) -> Option<UserBuffer> {
         let mut va_start = VirtAddr::from(ptr as usize);
-        let va_end = VirtAddr(va_start.0 + len);
+        let va_end = VirtAddr((ptr as usize).checked_add(len).filter(|end| *end <= USER_SPACE_END)?);
         let mut ret = Vec::new();
+        let mut pins = Vec::new();
         while va_start < va_end {
             let vpn = va_start.vpn_floor();
-            let ppn = self
-                .find(vpn)
-                .unwrap()
-                .ppn();
+            let (ppn, pin) = self.user_page(vpn, write)?;
+            pins.push(pin);
             let va_page_end: VirtAddr = (VirtAddr::from(vpn).0 + PAGE_SIZE).into();
-            if va_page_end >= va_end {
+            if va_page_end > va_end {
                 ret.push(&mut ppn.get_bytes()[
                     va_start.page_offset()..va_end.page_offset()
                 ]);
@@ -131,7 +208,7 @@ impl PageTab {
             }
             va_start = va_page_end;
         }
-        ret
+        Some(UserBuffer { buffers: ret, pins })
     }
     fn find_pte_create(&mut self, vpn: VirtPageNum) -> Option<&mut PageTabEntry> {
         let idxs = vpn.indexes();
@@ -144,7 +221,7 @@ impl PageTab {
                 break;
             }
             if !pte.is_valid() {
-                let frame = frame_alloc().unwrap();
+                let frame = frame_alloc()?;
                 *pte = PageTabEntry::new(*frame, PTEFlags::V);
                 self.frames.push(frame);
             }
@@ -173,13 +250,44 @@ impl PageTab {
     }
 }
 
+// a value in a page of a process, kept in memory while it is held
+pub struct UserMut<T: 'static> {
+    value: &'static mut T,
+    _pin: PagePin
+}
+impl<T> Deref for UserMut<T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        self.value
+    }
+}
+impl<T> DerefMut for UserMut<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.value
+    }
+}
+
 pub struct UserBuffer {
-    pub buffers: Vec<&'static mut [u8]>
+    pub buffers: Vec<&'static mut [u8]>,
+    pins: Vec<PagePin>
 }
 impl UserBuffer {
     #[allow(unused)]
     pub fn from(buffers: Vec<&'static mut[u8]>) -> Self {
-        Self { buffers }
+        Self { buffers, pins: Vec::new() }
+    }
+
+    pub fn copy_from_slice(&mut self, src: &[u8]) -> usize {
+        let mut copied = 0usize;
+        for buf in self.buffers.iter_mut() {
+            let len = buf.len().min(src.len() - copied);
+            buf[..len].copy_from_slice(&src[copied..copied + len]);
+            copied += len;
+            if copied == src.len() {
+                break;
+            }
+        }
+        copied
     }
 
     pub fn len(&self) -> usize {
@@ -201,6 +309,7 @@ impl IntoIterator for UserBuffer {
     fn into_iter(self) -> Self::IntoIter {
         UserBufferIterator {
             buffers: self.buffers,
+            _pins: self.pins,
             curr_buf: 0,
             curr_idx: 0
         }
@@ -209,6 +318,7 @@ impl IntoIterator for UserBuffer {
 
 pub struct UserBufferIterator {
     buffers: Vec<&'static mut[u8]>,
+    _pins: Vec<PagePin>,
     curr_buf: usize,
     curr_idx: usize
 }
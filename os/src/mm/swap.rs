@@ -0,0 +1,269 @@
+use alloc::{collections::{BTreeMap, VecDeque}, sync::{Arc, Weak}, vec, vec::Vec};
+use easyfs::BLOCK_SIZE;
+use lazy_static::lazy_static;
+use log::info;
+
+use crate::{config::PAGE_SIZE, drivers::BlockDevice, sync::UThrCell};
+use super::{address::{PhysPageNum, VirtPageNum}, frame_allocator::FrameTracker, pagetab::{PageTab, PageTabEntry}};
+
+// what mkswap leaves at the end of the first page of a swap area
+const SWAP_SIGNATURE: &[u8] = b"SWAPSPACE2";
+const PAGE_BLOCKS: usize = PAGE_SIZE / BLOCK_SIZE;
+
+// the page-sized slots of the swap device, after its first page
+struct SwapArea {
+    dev: Arc<dyn BlockDevice>,
+    current: usize,
+    end: usize,
+    recycled: Vec<usize>
+}
+impl SwapArea {
+    fn alloc(&mut self) -> Option<usize> {
+        if let Some(slot) = self.recycled.pop() {
+            Some(slot)
+        } else if self.current == self.end {
+            None
+        } else {
+            self.current += 1;
+            Some(self.current - 1)
+        }
+    }
+    fn slot_block(slot: usize) -> usize {
+        (slot + 1) * PAGE_BLOCKS
+    }
+}
+
+lazy_static! {
+    static ref SWAP_AREA: UThrCell<Option<SwapArea>> = unsafe {
+        UThrCell::new(None)
+    };
+    static ref RESIDENT: UThrCell<Resident> = unsafe {
+        UThrCell::new(Resident { queue: VecDeque::new(), live: 0 })
+    };
+    // frames the kernel is using for a process, by ppn
+    static ref PINNED: UThrCell<BTreeMap<usize, usize>> = unsafe {
+        UThrCell::new(BTreeMap::new())
+    };
+}
+
+// take `dev` as the swap area if it carries the signature
+pub fn swap_on(dev: Arc<dyn BlockDevice>) -> bool {
+    let mut block = [0u8; BLOCK_SIZE];
+    if dev.read_blocks_polled(PAGE_BLOCKS - 1, &mut block).is_err() || !block.ends_with(SWAP_SIGNATURE) {
+        return false;
+    }
+    let slots = (dev.capacity() / PAGE_BLOCKS).saturating_sub(1);
+    info!("Swap area of {} pages", slots);
+    *SWAP_AREA.get_refmut() = Some(SwapArea {
+        dev,
+        current: 0,
+        end: slots,
+        recycled: Vec::new()
+    });
+    true
+}
+
+// a slot holding a copy of a page, given back when dropped
+struct SwapSlot(usize);
+impl Drop for SwapSlot {
+    fn drop(&mut self) {
+        if let Some(swap) = SWAP_AREA.get_refmut().as_mut() {
+            swap.recycled.push(self.0);
+        }
+    }
+}
+
+fn write_slot(bytes: &[u8]) -> Option<SwapSlot> {
+    let mut swap = SWAP_AREA.get_refmut();
+    let swap = swap.as_mut()?;
+    let slot = swap.alloc()?;
+    if swap.dev.write_blocks_polled(SwapArea::slot_block(slot), bytes).is_err() {
+        swap.recycled.push(slot);
+        return None;
+    }
+    Some(SwapSlot(slot))
+}
+
+fn read_slot(slot: &SwapSlot, bytes: &mut [u8]) -> bool {
+    SWAP_AREA.get_refmut().as_ref()
+        .is_some_and(|swap| swap.dev.read_blocks_polled(SwapArea::slot_block(slot.0), bytes).is_ok())
+}
+
+// keeps a frame from being swapped out while the kernel uses it for a process
+pub struct PagePin(PhysPageNum);
+impl PagePin {
+    pub fn new(ppn: PhysPageNum) -> Self {
+        *PINNED.get_refmut().entry(ppn.0).or_insert(0) += 1;
+        Self(ppn)
+    }
+}
+impl Drop for PagePin {
+    fn drop(&mut self) {
+        let mut pinned = PINNED.get_refmut();
+        let count = pinned.get_mut(&self.0.0).unwrap();
+        *count -= 1;
+        if *count == 0 {
+            pinned.remove(&self.0.0);
+        }
+    }
+}
+
+// user pages in frames, in the order the clock hand comes to them; a freed
+// page leaves its entry behind until the queue is swept
+struct Resident {
+    queue: VecDeque<Weak<Page>>,
+    // the pages tracked and still in their frames
+    live: usize
+}
+
+enum Evict {
+    Done,
+    Keep,
+    Gone
+}
+
+// a page of a framed area, in its frame or in swap; a clean page keeps its
+// slot when read back, so it is not written again when it goes out next
+pub struct Page {
+    vpn: VirtPageNum,
+    inner: UThrCell<PageInner>
+}
+struct PageInner {
+    frame: Option<FrameTracker>,
+    slot: Option<SwapSlot>,
+    // counted in RESIDENT
+    tracked: bool,
+    // the address spaces holding the page, by token
+    owners: Vec<usize>
+}
+impl Page {
+    pub fn new(vpn: VirtPageNum, frame: FrameTracker, token: usize) -> Arc<Self> {
+        Arc::new(Self {
+            vpn,
+            inner: unsafe {
+                UThrCell::new(PageInner { frame: Some(frame), slot: None, tracked: false, owners: vec![token] })
+            }
+        })
+    }
+    // None while the page is out in swap
+    pub fn ppn(&self) -> Option<PhysPageNum> {
+        self.inner.get_refmut().frame.as_ref().map(|frame| frame.ppn)
+    }
+    // held by more than one address space after a fork
+    pub fn shared(&self) -> bool {
+        self.inner.get_refmut().owners.len() > 1
+    }
+    pub fn add_owner(&self, token: usize) {
+        self.inner.get_refmut().owners.push(token);
+    }
+    pub fn remove_owner(&self, token: usize) {
+        self.inner.get_refmut().owners.retain(|owner| *owner != token);
+    }
+    // a mapping of the page was taken down, the copy in swap is stale if it was written through
+    pub fn unmapped(&self, pte: PageTabEntry) {
+        if pte.dirty() {
+            self.inner.get_refmut().slot = None;
+        }
+    }
+    pub fn copy_to(&self, frame: &FrameTracker) {
+        let inner = self.inner.get_refmut();
+        frame.get_bytes().copy_from_slice(inner.frame.as_ref().unwrap().get_bytes());
+    }
+    // read the page back into `frame` unless it is in memory already
+    pub fn swap_in(self: &Arc<Self>, frame: FrameTracker) -> bool {
+        let mut inner = self.inner.get_refmut();
+        if inner.frame.is_some() {
+            return true;
+        }
+        if !inner.slot.as_ref().is_some_and(|slot| read_slot(slot, frame.get_bytes())) {
+            return false;
+        }
+        inner.frame = Some(frame);
+        drop(inner);
+        track(self);
+        true
+    }
+    // only pages of a single address space are swapped out,
+    // the kernel unmaps them from that one page table
+    fn evict(&self) -> Evict {
+        let mut inner = self.inner.get_refmut();
+        let Some(ppn) = inner.frame.as_ref().map(|frame| frame.ppn) else {
+            return Evict::Gone;
+        };
+        if inner.owners.len() != 1 || PINNED.get_refmut().contains_key(&ppn.0) {
+            return Evict::Keep;
+        }
+        let mut pagetab = PageTab::from_token(inner.owners[0]);
+        let pte = pagetab.find(self.vpn);
+        if let Some(pte) = &pte {
+            // used since the hand last came by: a second chance
+            if pte.accessed() {
+                pagetab.clear_accessed(self.vpn);
+                return Evict::Keep;
+            }
+            if pte.dirty() {
+                inner.slot = None;
+            }
+        }
+        if inner.slot.is_none() {
+            match write_slot(ppn.get_bytes()) {
+                Some(slot) => inner.slot = Some(slot),
+                None => return Evict::Keep
+            }
+        }
+        if pte.is_some() {
+            pagetab.del(self.vpn);
+        }
+        inner.frame = None;
+        inner.tracked = false;
+        RESIDENT.get_refmut().live -= 1;
+        Evict::Done
+    }
+}
+impl Drop for Page {
+    fn drop(&mut self) {
+        if self.inner.get_refmut().tracked {
+            RESIDENT.get_refmut().live -= 1;
+        }
+    }
+}
+
+// let the clock hand come to a user page now in memory; without a swap
+// area there is nowhere to put it, so it is not tracked at all
+pub fn track(page: &Arc<Page>) {
+    if SWAP_AREA.get_refmut().is_none() {
+        return;
+    }
+    page.inner.get_refmut().tracked = true;
+    let mut resident = RESIDENT.get_refmut();
+    resident.queue.push_back(Arc::downgrade(page));
+    resident.live += 1;
+    // entries of freed pages are swept once they outnumber the live ones
+    if resident.queue.len() > 2 * resident.live {
+        resident.queue.retain(|weak| weak.strong_count() > 0);
+    }
+}
+
+// free a frame by swapping out a user page not used lately, false if none could be
+pub fn reclaim() -> bool {
+    if SWAP_AREA.get_refmut().is_none() {
+        return false;
+    }
+    // the first round may only clear accessed bits
+    let mut steps = 2 * RESIDENT.get_refmut().queue.len();
+    while steps > 0 {
+        steps -= 1;
+        let Some(weak) = RESIDENT.get_refmut().queue.pop_front() else {
+            break;
+        };
+        let Some(page) = weak.upgrade() else {
+            continue;
+        };
+        match page.evict() {
+            Evict::Done => return true,
+            Evict::Keep => RESIDENT.get_refmut().queue.push_back(weak),
+            Evict::Gone => {}
+        }
+    }
+    false
+}
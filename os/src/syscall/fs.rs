@@ -1,4 +1,19 @@
-use crate::{fs::{inode::{OSInode, OpenFlag}, pipe::Pipe}, mm::pagetab::{PageTab, UserBuffer},  task::{proc::PCBMut, processor::{curr_atp_token, curr_proc}}};
+use core::{mem::size_of, slice};
+
+use easyfs::{efs::StatFs, vfs::Stat, EzResult};
+
+use crate::{fs::{inode::{chmod, link, mkdir, readlink, rename, rmdir, statfs, symlink, unlink, OSInode, OpenFlag}, pipe::Pipe}, mm::pagetab::PageTab,  task::{proc::PCBMut, processor::{curr_atp_token, curr_proc}}};
+
+const AT_FDCWD: isize = -100;
+const AT_REMOVEDIR: u32 = 0x200;
+const RENAME_NOREPLACE: u32 = 1;
+
+const EFAULT: isize = 14;
+
+// 0 on success, the negated errno of a file system error otherwise
+fn ret_code(res: EzResult<()>) -> isize {
+    res.map_or_else(|e| -(e.errno() as isize), |_| 0)
+}
 
 pub fn sys_write(fd: usize, buf: *const u8, len: usize) -> isize {
     let token = curr_atp_token();
@@ -13,9 +28,10 @@ pub fn sys_write(fd: usize, buf: *const u8, len: usize) -> isize {
         }
         let file = file.clone();
         drop(inner);
-        file.write(UserBuffer::from(
-            PageTab::from_token(token).trans_bytes_buffer(buf, len)
-        )) as isize
+        match PageTab::from_token(token).trans_bytes_buffer(buf, len) {
+            Some(buf) => file.write(buf) as isize,
+            None => -EFAULT
+        }
     } else {
         -1
     }
@@ -34,9 +50,10 @@ pub fn sys_read(fd: usize, buf: *const u8, len: usize) -> isize {
             return -1;
         }
         drop(inner);
-        file.read(UserBuffer::from(
-            PageTab::from_token(token).trans_bytes_buffer(buf, len)
-        )) as isize
+        match PageTab::from_token(token).trans_bytes_buffer_mut(buf, len) {
+            Some(buf) => file.read(buf) as isize,
+            None => -EFAULT
+        }
     } else {
         -1
     }
@@ -45,32 +62,37 @@ pub fn sys_read(fd: usize, buf: *const u8, len: usize) -> isize {
 pub fn sys_open(path: *const u8, flags: u32) -> isize {
     let task = curr_proc();
     let token = curr_atp_token();
-    let path = PageTab::from_token(token).trans_cstr(path);
-    if let Some(inode) = OSInode::open(path.as_str(),
-        OpenFlag::from_bits(flags).unwrap()
-    ) {
-        let mut inner = task.get_mutpart();
-        let fd = PCBMut::alloc_new_id(&mut inner.fd_table);
-        inner.fd_table[fd] = Some(inode);
-        fd as isize
-    } else {
-        -1
+    let Some(path) = PageTab::from_token(token).trans_cstr(path) else {
+        return -EFAULT;
+    };
+    match OSInode::open(path.as_str(), OpenFlag::from_bits(flags).unwrap()) {
+        Ok(inode) => {
+            let mut inner = task.get_mutpart();
+            let fd = PCBMut::alloc_new_id(&mut inner.fd_table);
+            inner.fd_table[fd] = Some(inode);
+            fd as isize
+        }
+        Err(e) => -(e.errno() as isize)
     }
 }
 
 pub fn sys_pipe(pipe: *mut usize) -> isize {
     let task = curr_proc();
     let token = curr_atp_token();
+    // the page may have to be copied, which borrows the process
+    let pt = PageTab::from_token(token);
+    let (Some(mut read_end), Some(mut writ_end)) = (pt.trans_mut(pipe), pt.trans_mut(pipe.wrapping_add(1))) else {
+        return -EFAULT;
+    };
     let mut inner = task.get_mutpart();
     let (pipe_read, pipe_writ) = Pipe::make_pipe();
     let read_fd = PCBMut::alloc_new_id(&mut inner.fd_table);
     inner.fd_table[read_fd] = Some(pipe_read);
     let writ_fd = PCBMut::alloc_new_id(&mut inner.fd_table);
     inner.fd_table[writ_fd] = Some(pipe_writ);
-    unsafe {
-        *(PageTab::from_token(token).trans_mut(pipe)) = read_fd;
-        *(PageTab::from_token(token).trans_mut(pipe.add(1))) = writ_fd;
-    }
+    drop(inner);
+    *read_end = read_fd;
+    *writ_end = writ_fd;
     0
 }
 
@@ -102,6 +124,90 @@ pub fn sys_seek(fd: usize, offset: isize, whence: usize) -> isize {
     }
 }
 
+pub fn sys_fstat(fd: usize, stat_buf: *mut Stat) -> isize {
+    let token = curr_atp_token();
+    let proc = curr_proc();
+    let inner = proc.get_mutpart();
+    if fd >= inner.fd_table.len() {
+        return -1;
+    }
+    if let Some(file) = &inner.fd_table[fd] {
+        let file = file.clone();
+        drop(inner);
+        let stat = file.stat();
+        let stat_bytes = unsafe {
+            slice::from_raw_parts(&stat as *const _ as *const u8, size_of::<Stat>())
+        };
+        match PageTab::from_token(token).trans_bytes_buffer_mut(stat_buf as *const u8, size_of::<Stat>()) {
+            Some(mut buf) => {
+                buf.copy_from_slice(stat_bytes);
+                0
+            }
+            None => -EFAULT
+        }
+    } else {
+        -1
+    }
+}
+
+pub fn sys_statfs(path: *const u8, buf: *mut StatFs) -> isize {
+    let token = curr_atp_token();
+    let Some(path) = PageTab::from_token(token).trans_cstr(path) else {
+        return -EFAULT;
+    };
+    match statfs(path.as_str()) {
+        Ok(st) => {
+            let st_bytes = unsafe {
+                slice::from_raw_parts(&st as *const _ as *const u8, size_of::<StatFs>())
+            };
+            match PageTab::from_token(token).trans_bytes_buffer_mut(buf as *const u8, size_of::<StatFs>()) {
+                Some(mut buf) => {
+                    buf.copy_from_slice(st_bytes);
+                    0
+                }
+                None => -EFAULT
+            }
+        }
+        Err(e) => -(e.errno() as isize)
+    }
+}
+
+pub fn sys_getdents64(fd: usize, buf: *const u8, len: usize) -> isize {
+    let token = curr_atp_token();
+    let proc = curr_proc();
+    let inner = proc.get_mutpart();
+    if fd >= inner.fd_table.len() {
+        return -1;
+    }
+    if let Some(file) = &inner.fd_table[fd] {
+        let file = file.clone();
+        drop(inner);
+        OSInode::from_file(file).map_or(-1, |inode| {
+            match PageTab::from_token(token).trans_bytes_buffer_mut(buf, len) {
+                Some(buf) => inode.getdents(buf),
+                None => -EFAULT
+            }
+        })
+    } else {
+        -1
+    }
+}
+
+pub fn sys_ftruncate(fd: usize, len: usize) -> isize {
+    let proc = curr_proc();
+    let inner = proc.get_mutpart();
+    if fd >= inner.fd_table.len() {
+        return -1;
+    }
+    if let Some(file) = &inner.fd_table[fd] {
+        let file = file.clone();
+        drop(inner);
+        OSInode::from_file(file).map_or(-1, |inode| ret_code(inode.truncate(len)))
+    } else {
+        -1
+    }
+}
+
 pub fn sys_dup(fd: usize) -> isize {
     let proc = curr_proc();
     let mut inner = proc.get_mutpart();
@@ -113,4 +219,117 @@ pub fn sys_dup(fd: usize) -> isize {
         inner.fd_table[newfd] = Some(inner.fd_table[fd].as_ref().unwrap().clone());
         newfd as isize
     }
-}
\ No newline at end of file
+}
+
+// no working directory yet: paths are resolved from the root
+pub fn sys_mkdirat(dirfd: isize, path: *const u8, mode: u32) -> isize {
+    if dirfd != AT_FDCWD {
+        return -1;
+    }
+    let token = curr_atp_token();
+    let Some(path) = PageTab::from_token(token).trans_cstr(path) else {
+        return -EFAULT;
+    };
+    ret_code(mkdir(path.as_str(), mode))
+}
+
+pub fn sys_unlinkat(dirfd: isize, path: *const u8, flags: u32) -> isize {
+    if dirfd != AT_FDCWD {
+        return -1;
+    }
+    let token = curr_atp_token();
+    let Some(path) = PageTab::from_token(token).trans_cstr(path) else {
+        return -EFAULT;
+    };
+    ret_code(if flags & AT_REMOVEDIR != 0 {
+        rmdir(path.as_str())
+    } else {
+        unlink(path.as_str())
+    })
+}
+
+pub fn sys_linkat(
+    olddirfd: isize,
+    oldpath: *const u8,
+    newdirfd: isize,
+    newpath: *const u8,
+    _flags: u32
+) -> isize {
+    if olddirfd != AT_FDCWD || newdirfd != AT_FDCWD {
+        return -1;
+    }
+    let token = curr_atp_token();
+    let Some(oldpath) = PageTab::from_token(token).trans_cstr(oldpath) else {
+        return -EFAULT;
+    };
+    let Some(newpath) = PageTab::from_token(token).trans_cstr(newpath) else {
+        return -EFAULT;
+    };
+    ret_code(link(oldpath.as_str(), newpath.as_str()))
+}
+
+pub fn sys_symlinkat(target: *const u8, newdirfd: isize, linkpath: *const u8) -> isize {
+    if newdirfd != AT_FDCWD {
+        return -1;
+    }
+    let token = curr_atp_token();
+    let Some(target) = PageTab::from_token(token).trans_cstr(target) else {
+        return -EFAULT;
+    };
+    let Some(linkpath) = PageTab::from_token(token).trans_cstr(linkpath) else {
+        return -EFAULT;
+    };
+    ret_code(symlink(target.as_str(), linkpath.as_str()))
+}
+
+// the target is copied without a nul, cut to `len` bytes
+pub fn sys_readlinkat(dirfd: isize, path: *const u8, buf: *const u8, len: usize) -> isize {
+    if dirfd != AT_FDCWD {
+        return -1;
+    }
+    let token = curr_atp_token();
+    let Some(path) = PageTab::from_token(token).trans_cstr(path) else {
+        return -EFAULT;
+    };
+    match readlink(path.as_str()) {
+        Ok(target) => {
+            let len = len.min(target.len());
+            PageTab::from_token(token)
+                .trans_bytes_buffer_mut(buf, len)
+                .map_or(-EFAULT, |mut buf| buf.copy_from_slice(&target.as_bytes()[..len]) as isize)
+        }
+        Err(e) => -(e.errno() as isize)
+    }
+}
+
+pub fn sys_renameat2(
+    olddirfd: isize,
+    oldpath: *const u8,
+    newdirfd: isize,
+    newpath: *const u8,
+    flags: u32
+) -> isize {
+    // RENAME_EXCHANGE and RENAME_WHITEOUT are not supported
+    if olddirfd != AT_FDCWD || newdirfd != AT_FDCWD || flags & !RENAME_NOREPLACE != 0 {
+        return -1;
+    }
+    let token = curr_atp_token();
+    let Some(oldpath) = PageTab::from_token(token).trans_cstr(oldpath) else {
+        return -EFAULT;
+    };
+    let Some(newpath) = PageTab::from_token(token).trans_cstr(newpath) else {
+        return -EFAULT;
+    };
+    ret_code(rename(oldpath.as_str(), newpath.as_str(), flags & RENAME_NOREPLACE != 0))
+}
+
+pub fn sys_fchmodat(dirfd: isize, path: *const u8, mode: u32, _flags: u32) -> isize {
+    if dirfd != AT_FDCWD {
+        return -1;
+    }
+    let token = curr_atp_token();
+    let Some(path) = PageTab::from_token(token).trans_cstr(path) else {
+        return -EFAULT;
+    };
+    ret_code(chmod(path.as_str(), mode))
+}
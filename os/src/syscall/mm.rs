@@ -0,0 +1,118 @@
+use crate::{config::{PAGE_SIZE, USER_SPACE_END}, fs::inode::OSInode, mm::{address::{VirtAddr, VirtPageRange}, memarea::{AreaData, MapPermission}}, task::processor::curr_proc};
+
+const PROT_READ: usize = 1;
+const PROT_WRITE: usize = 2;
+const PROT_EXEC: usize = 4;
+
+const MAP_SHARED: usize = 0x01;
+const MAP_PRIVATE: usize = 0x02;
+const MAP_FIXED: usize = 0x10;
+const MAP_ANONYMOUS: usize = 0x20;
+
+const EBADF: isize = 9;
+const ENOMEM: isize = 12;
+const EACCES: isize = 13;
+const ENODEV: isize = 19;
+const EINVAL: isize = 22;
+
+// the pages of [addr, addr + len), None unless aligned and in user space
+fn user_range(addr: usize, len: usize) -> Option<VirtPageRange> {
+    let end = addr.checked_add(len)?;
+    (addr % PAGE_SIZE == 0 && len > 0 && end <= USER_SPACE_END)
+        .then(|| VirtPageRange::from_va_range(addr.into()..end.into()))
+}
+
+fn map_perm(prot: usize) -> Option<MapPermission> {
+    if prot & !(PROT_READ | PROT_WRITE | PROT_EXEC) != 0 {
+        return None;
+    }
+    let mut perm = MapPermission::U;
+    if prot & PROT_READ != 0 {
+        perm |= MapPermission::R;
+    }
+    // there are no write-only pages
+    if prot & PROT_WRITE != 0 {
+        perm |= MapPermission::R | MapPermission::W;
+    }
+    if prot & PROT_EXEC != 0 {
+        perm |= MapPermission::X;
+    }
+    Some(perm)
+}
+
+// private mappings only: shared ones would have to stay in step across
+// forks and be written back; without MAP_FIXED `addr` is not looked at
+pub fn sys_mmap(addr: usize, len: usize, prot: usize, flags: usize, fd: usize, offset: usize) -> isize {
+    let Some(perm) = map_perm(prot) else {
+        return -EINVAL;
+    };
+    if flags & (MAP_SHARED | MAP_PRIVATE) != MAP_PRIVATE || offset % PAGE_SIZE != 0
+        || len == 0 || len > USER_SPACE_END
+    {
+        return -EINVAL;
+    }
+    let proc = curr_proc();
+    let inode = if flags & MAP_ANONYMOUS != 0 {
+        None
+    } else {
+        let Some(Some(file)) = proc.get_mutpart().fd_table.get(fd).cloned() else {
+            return -EBADF;
+        };
+        if !file.readable() {
+            return -EACCES;
+        }
+        match OSInode::from_file(file).map(|file| file.inode()) {
+            Some(inode) if inode.is_file().unwrap_or(false) => Some(inode),
+            _ => return -ENODEV
+        }
+    };
+    let pages = (len + PAGE_SIZE - 1) / PAGE_SIZE;
+    let mut inner = proc.get_mutpart();
+    let start = if flags & MAP_FIXED != 0 {
+        let Some(range) = user_range(addr, len).filter(|range| !inner.memset.is_fixed(*range)) else {
+            return -EINVAL;
+        };
+        inner.memset.unmap(range);
+        range.start
+    } else {
+        match inner.memset.find_free(pages) {
+            Some(start) => start,
+            None => return -ENOMEM
+        }
+    };
+    let va_start = VirtAddr::from(start);
+    inner.memset.insert_lazy_area(
+        va_start..VirtAddr::from(start.0 + pages),
+        perm,
+        inode.map(|inode| AreaData::from_file(va_start, inode, offset))
+    );
+    va_start.0 as isize
+}
+
+// brk(0) asks for the current break
+pub fn sys_brk(addr: usize) -> isize {
+    curr_proc().get_mutpart().memset.brk(addr) as isize
+}
+
+// the stacks and trap contexts the kernel set up are off limits
+pub fn sys_munmap(addr: usize, len: usize) -> isize {
+    let proc = curr_proc();
+    let mut inner = proc.get_mutpart();
+    let Some(range) = user_range(addr, len).filter(|range| !inner.memset.is_fixed(*range)) else {
+        return -EINVAL;
+    };
+    inner.memset.unmap(range);
+    0
+}
+
+pub fn sys_mprotect(addr: usize, len: usize, prot: usize) -> isize {
+    let proc = curr_proc();
+    let mut inner = proc.get_mutpart();
+    let (Some(range), Some(perm)) = (user_range(addr, len), map_perm(prot)) else {
+        return -EINVAL;
+    };
+    if inner.memset.is_fixed(range) {
+        return -EINVAL;
+    }
+    if inner.memset.protect(range, perm) { 0 } else { -ENOMEM }
+}
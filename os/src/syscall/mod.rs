@@ -1,5 +1,6 @@
 mod process;
 mod gui;
+use easyfs::{efs::StatFs, vfs::Stat};
 use log::trace;
 use process::*;
 
@@ -15,16 +16,29 @@ use sync::*;
 mod input;
 use input::*;
 
+mod mm;
+use mm::*;
+
 use crate::syscall::gui::{sys_get_fbfd, sys_get_gpures};
 
 
 const SYSCALL_DUP: usize = 24;
+const SYSCALL_MKDIRAT: usize = 34;
+const SYSCALL_UNLINKAT: usize = 35;
+const SYSCALL_SYMLINKAT: usize = 36;
+const SYSCALL_LINKAT: usize = 37;
+const SYSCALL_STATFS: usize = 43;
+const SYSCALL_FTRUNCATE: usize = 46;
+const SYSCALL_FCHMODAT: usize = 53;
 const SYSCALL_OPEN: usize = 56;
 const SYSCALL_CLOSE: usize = 57;
 const SYSCALL_PIPE: usize = 59;
+const SYSCALL_GETDENTS64: usize = 61;
 const SYSCALL_SEEK: usize = 62;
 const SYSCALL_READ: usize = 63;
 const SYSCALL_WRITE: usize = 64;
+const SYSCALL_READLINKAT: usize = 78;
+const SYSCALL_FSTAT: usize = 80;
 const SYSCALL_EXIT: usize = 93;
 const SYSCALL_SLEEP: usize = 101;
 const SYSCALL_YIELD: usize = 124;
@@ -32,8 +46,13 @@ const SYSCALL_KILL: usize = 129;
 const SYSCALL_GET_TIME: usize = 169;
 const SYSCALL_GETPID: usize = 172;
 const SYSCALL_FORK: usize = 220;
+const SYSCALL_BRK: usize = 214;
+const SYSCALL_MUNMAP: usize = 215;
 const SYSCALL_EXEC: usize = 221;
+const SYSCALL_MMAP: usize = 222;
+const SYSCALL_MPROTECT: usize = 226;
 const SYSCALL_WAITPID: usize = 260;
+const SYSCALL_RENAMEAT2: usize = 276;
 const SYSCALL_EVENTFD: usize = 290;
 
 const SYSCALL_THRDCREATE: usize = 1000;
@@ -54,16 +73,36 @@ const SYSCALL_GETFBFD: usize = 2001;
 const SYSCALL_INPUTEVENT: usize = 3000;
 
 
-pub fn syscall(syscall_id: usize, args: [usize; 3]) -> isize {
+pub fn syscall(syscall_id: usize, args: [usize; 6]) -> isize {
     trace!("syscall catched, id: {syscall_id}");
     match syscall_id {
         SYSCALL_DUP => sys_dup(args[0]),
+        SYSCALL_MKDIRAT => sys_mkdirat(args[0] as isize, args[1] as *const u8, args[2] as u32),
+        SYSCALL_UNLINKAT => sys_unlinkat(args[0] as isize, args[1] as *const u8, args[2] as u32),
+        SYSCALL_SYMLINKAT => sys_symlinkat(args[0] as *const u8, args[1] as isize, args[2] as *const u8),
+        SYSCALL_LINKAT => sys_linkat(
+            args[0] as isize, args[1] as *const u8,
+            args[2] as isize, args[3] as *const u8,
+            args[4] as u32
+        ),
+        SYSCALL_STATFS => sys_statfs(args[0] as *const u8, args[1] as *mut StatFs),
+        SYSCALL_FTRUNCATE => sys_ftruncate(args[0], args[1]),
+        SYSCALL_FCHMODAT => sys_fchmodat(
+            args[0] as isize, args[1] as *const u8,
+            args[2] as u32, args[3] as u32
+        ),
         SYSCALL_OPEN => sys_open(args[0] as *const u8, args[1] as u32),
         SYSCALL_CLOSE => sys_close(args[0] as usize),
         SYSCALL_PIPE => sys_pipe(args[0] as *mut usize),
+        SYSCALL_GETDENTS64 => sys_getdents64(args[0], args[1] as *const u8, args[2]),
         SYSCALL_SEEK => sys_seek(args[0], args[1] as isize, args[2]),
         SYSCALL_READ => sys_read(args[0], args[1] as *const u8, args[2]),
         SYSCALL_WRITE => sys_write(args[0], args[1] as *const u8, args[2]),
+        SYSCALL_READLINKAT => sys_readlinkat(
+            args[0] as isize, args[1] as *const u8,
+            args[2] as *const u8, args[3]
+        ),
+        SYSCALL_FSTAT => sys_fstat(args[0], args[1] as *mut Stat),
         SYSCALL_EXIT => sys_exit(args[0] as i32),
         SYSCALL_SLEEP => sys_sleep(args[0]),
         SYSCALL_YIELD => sys_yield(),
@@ -71,8 +110,17 @@ pub fn syscall(syscall_id: usize, args: [usize; 3]) -> isize {
         SYSCALL_GET_TIME => sys_get_time(),
         SYSCALL_GETPID => sys_getpid(),
         SYSCALL_FORK => sys_fork(),
+        SYSCALL_BRK => sys_brk(args[0]),
+        SYSCALL_MUNMAP => sys_munmap(args[0], args[1]),
         SYSCALL_EXEC => sys_exec(args[0] as *const u8, args[1] as *const usize),
+        SYSCALL_MMAP => sys_mmap(args[0], args[1], args[2], args[3], args[4], args[5]),
+        SYSCALL_MPROTECT => sys_mprotect(args[0], args[1], args[2]),
         SYSCALL_WAITPID => sys_waitpid(args[0] as isize, args[1] as *mut i32),
+        SYSCALL_RENAMEAT2 => sys_renameat2(
+            args[0] as isize, args[1] as *const u8,
+            args[2] as isize, args[3] as *const u8,
+            args[4] as u32
+        ),
         SYSCALL_EVENTFD => sys_eventfd(args[0] as u32, args[1] as i32),
         SYSCALL_THRDCREATE => sys_thrdcreate(args[0], args[1]),
         SYSCALL_GETTID => sys_gettid(),
@@ -1,7 +1,13 @@
 use alloc::{string::String, sync::Arc, vec::Vec};
+use easyfs::{vfs::S_IXUSR, EzFsError};
 use log::{debug, error};
 
-use crate::{fs::inode::{OSInode, OpenFlag}, mm::pagetab::PageTab, task::{exit_curr_task, get_proc, processor::{curr_atp_token, curr_proc}, signal::{SignalFlags, MAX_SIG}, suspend_curr_task}, timer::get_time_ms};
+use crate::{fs::{inode::{OSInode, OpenFlag}, File}, mm::pagetab::PageTab, task::{exit_curr_task, get_proc, proc::ExecError, processor::{curr_atp_token, curr_proc}, signal::{SignalFlags, MAX_SIG}, suspend_curr_task}, timer::get_time_ms};
+
+const E2BIG: isize = 7;
+const ENOEXEC: isize = 8;
+const ENOMEM: isize = 12;
+const EFAULT: isize = 14;
 
 pub fn sys_yield() -> isize {
     suspend_curr_task();
@@ -23,7 +29,9 @@ pub fn sys_getpid() -> isize {
 
 pub fn sys_fork() -> isize {
     let curr = curr_proc();
-    let new_proc = curr.fork();
+    let Some(new_proc) = curr.fork() else {
+        return -ENOMEM;
+    };
     let new_pid = new_proc.getpid();
     let new_proc_mut = new_proc.get_mutpart();
     let task = new_proc_mut.tasks[0].as_ref().unwrap();
@@ -34,26 +42,38 @@ pub fn sys_fork() -> isize {
 
 pub fn sys_exec(path: *const u8, mut args: *const usize) -> isize {
     let token = curr_atp_token();
-    let path = PageTab::from_token(token).trans_cstr(path);
+    let Some(path) = PageTab::from_token(token).trans_cstr(path) else {
+        return -EFAULT;
+    };
     debug!("sys_exec {}", path);
-    if let Some(inode) = OSInode::open(path.as_str(), OpenFlag::RDONLY) {
-        let data = inode.read_app();
-        let proc = curr_proc();
-        let mut vec_args: Vec<String> = Vec::new();
-        unsafe { loop {
-            let arg_ptr = *(PageTab::from_token(token).trans_ref(args));
-            if arg_ptr == 0 {
-                break;
-            }
-            vec_args.push(PageTab::from_token(token).trans_cstr(arg_ptr as *const u8));
-            args = args.add(1);
-        }};
-        proc.exec(data.as_slice(), &vec_args);
-        // return argc to reg a0 as first argument to user function
-        vec_args.len() as isize
-    } else {
-        -1
+    let inode = match OSInode::open(path.as_str(), OpenFlag::RDONLY) {
+        Ok(inode) if inode.stat().mode & S_IXUSR != 0 => inode,
+        Ok(_) => return -(EzFsError::PermissionDenied.errno() as isize),
+        Err(e) => return -(e.errno() as isize)
+    };
+    let proc = curr_proc();
+    let mut vec_args: Vec<String> = Vec::new();
+    loop {
+        let Some(arg_ptr) = PageTab::from_token(token).trans_ref(args) else {
+            return -EFAULT;
+        };
+        if arg_ptr == 0 {
+            break;
+        }
+        let Some(arg) = PageTab::from_token(token).trans_cstr(arg_ptr as *const u8) else {
+            return -EFAULT;
+        };
+        vec_args.push(arg);
+        args = args.wrapping_add(1);
+    }
+    match proc.exec(&inode.inode(), &vec_args) {
+        Ok(()) => {}
+        Err(ExecError::NotProgram) => return -ENOEXEC,
+        Err(ExecError::NoMemory) => return -ENOMEM,
+        Err(ExecError::TooBig) => return -E2BIG
     }
+    // return argc to reg a0 as first argument to user function
+    vec_args.len() as isize
 }
 
 pub fn sys_waitpid(pid: isize, exit_code_ptr: *mut i32) -> isize {
@@ -77,10 +97,17 @@ pub fn sys_waitpid(pid: isize, exit_code_ptr: *mut i32) -> isize {
         assert_eq!(Arc::strong_count(&child), 1);
         let child_pid = child.getpid();
         let exit_code = child.get_mutpart().exit_code;
-        *(PageTab::from_token(proc_mut.get_atp_token())
-            .trans_mut(exit_code_ptr)
-        ) = exit_code;
-        child_pid as isize
+        let token = proc_mut.get_atp_token();
+        // the page may have to be copied, which borrows the process again
+        drop(proc_mut);
+        // the child is reaped even if its exit code can't be stored
+        match PageTab::from_token(token).trans_mut(exit_code_ptr) {
+            Some(mut code) => {
+                *code = exit_code;
+                child_pid as isize
+            }
+            None => -EFAULT
+        }
     } else {
         -2
     }
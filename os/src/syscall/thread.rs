@@ -4,14 +4,19 @@ use alloc::sync::Arc;
 
 use crate::{mm::memset::KERN_SPACE, task::{add_task, processor::{curr_proc, curr_task}, task::TaskControlBlock}, trap::{context::TrapContext, trap_handler}};
 
+const ENOMEM: isize = 12;
+
 pub fn sys_thrdcreate(entry: usize, arg: usize) -> isize {
     let task = curr_task().unwrap();
     let proc = curr_proc();
-    let new_task = Arc::new(TaskControlBlock::new(
+    let Some(new_task) = TaskControlBlock::new(
         proc.clone(),
         task.get_mutpart().res.as_ref().unwrap().ustack_base,
         true
-    ));
+    ) else {
+        return -ENOMEM;
+    };
+    let new_task = Arc::new(new_task);
     add_task(new_task.clone());
     let new_task_mut = new_task.get_mutpart();
     let new_task_res = new_task_mut.res.as_ref().unwrap();
@@ -11,14 +11,15 @@ lazy_static! {
         UThrCell::new(IdAlloc::new())
     };
 }
-pub fn kstack_alloc() -> KernStack {
-    let id = KSTACK_ALLOC.get_refmut().alloc();
-    let kstack_range = kern_stack_range(id);
+// None if memory ran out
+pub fn kstack_alloc() -> Option<KernStack> {
+    let kstack = KernStack(KSTACK_ALLOC.get_refmut().alloc());
+    let kstack_range = kern_stack_range(kstack.0);
     KERN_SPACE.get_refmut().insert_framed_area(
         kstack_range.start.into()..kstack_range.end.into(),
         MapPermission::R | MapPermission::W
-    );
-    KernStack(id)
+    )?;
+    Some(kstack)
 }
 
 impl KernStack {
@@ -6,7 +6,7 @@ use processor::curr_proc;
 use signal::SignalFlags;
 use task_user_res::TaskUserRes;
 
-use crate::{fs::inode::{OSInode, OpenFlag}, timer::sleep::remove_sleeptimer};
+use crate::{fs::inode::{OSInode, OpenFlag}, mm::{address::VirtPageNum, memarea::{MapPermission, PageFault}}, timer::sleep::remove_sleeptimer};
 
 use self::{task::{TaskControlBlock, TaskStatus}, processor::{take_curr_task, schedule}, context::TaskContext};
 
@@ -27,8 +27,7 @@ pub use manager::{add_task, get_proc};
 lazy_static! {
     pub static ref INITPROC: Arc<ProcControlBlock> = {
         let inode = OSInode::open("initproc", OpenFlag::RDONLY).unwrap();
-        let v = inode.read_app();
-        ProcControlBlock::new(v.as_slice())
+        ProcControlBlock::new(&inode.inode())
     };
 }
 
@@ -36,6 +35,30 @@ pub fn add_initproc() {
     let _initproc = INITPROC.clone();
 }
 
+// resolve a fault of the running process at `vpn`, if `token` is its page table
+pub fn handle_user_fault(token: usize, vpn: VirtPageNum, access: MapPermission) -> bool {
+    let proc = curr_proc();
+    loop {
+        let mut inner = proc.get_mutpart();
+        if inner.get_atp_token() != token {
+            return false;
+        }
+        let read = match inner.memset.handle_fault(vpn, access) {
+            PageFault::Fixed => return true,
+            PageFault::Denied => return false,
+            PageFault::Read(read) => read
+        };
+        // the process is not held across the disk, then the fault is looked at again
+        drop(inner);
+        let Some(frame) = read.read() else {
+            return false;
+        };
+        if !proc.get_mutpart().memset.install(vpn, frame) {
+            return false;
+        }
+    }
+}
+
 pub fn suspend_curr_task() {
     let task = take_curr_task().unwrap();
     let mut task_mut = task.get_mutpart();
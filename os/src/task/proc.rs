@@ -3,8 +3,9 @@ use core::mem::size_of;
 use alloc::{string::String, sync::{Arc, Weak}, vec::Vec};
 
 use alloc::vec;
+use easyfs::vfs::VirtInode;
 
-use crate::{fs::{stdio::{Stdin, Stdout}, File}, mm::{memset::{MemSet, KERN_SPACE}, pagetab::PageTab}, sync::{CondVar, Mutex, Semaphore, UThrCell, UThrRefMut}, task::pid::pid_alloc, trap::{context::TrapContext, trap_handler}};
+use crate::{config::USER_STACK_SIZE, fs::{stdio::{Stdin, Stdout}, File}, mm::{memset::{MemSet, KERN_SPACE}, pagetab::PageTab}, sync::{CondVar, Mutex, Semaphore, UThrCell, UThrRefMut}, task::pid::pid_alloc, trap::{context::TrapContext, trap_handler}};
 
 use super::{add_task, allocator::IdAlloc, manager::reg_proc, pid::PidHandle, signal::SignalFlags, task::TaskControlBlock};
 
@@ -13,6 +14,13 @@ pub struct ProcControlBlock {
     mut_part: UThrCell<PCBMut>
 }
 
+// why exec left the process as it was
+pub enum ExecError {
+    NotProgram,
+    NoMemory,
+    TooBig
+}
+
 pub struct PCBMut {
     pub is_zombie: bool,
     pub memset: MemSet,
@@ -29,8 +37,11 @@ pub struct PCBMut {
 }
 
 impl ProcControlBlock {
-    pub fn new(elf_data: &[u8]) -> Arc<Self> {
-        let (memset, ustack_base, entry_point) = MemSet::from_elf(elf_data);
+    pub fn new(inode: &Arc<VirtInode>) -> Arc<Self> {
+        let mut memset = MemSet::new_user()
+            .expect("[kernel] no memory for the initial process.");
+        let (ustack_base, entry_point) = memset.load_elf(inode)
+            .expect("[kernel] parsing error encountered for elf.");
         let pid = pid_alloc();
         let proc = Arc::new(Self {
             pid,
@@ -60,7 +71,7 @@ impl ProcControlBlock {
             proc.clone(),
             ustack_base,
             true
-        ));
+        ).expect("[kernel] no memory for the initial process."));
         let task_inner = task.get_mutpart();
         let trap_cx = task_inner.get_trap_cx();
         let ustack_top = task_inner.res.as_ref().unwrap().ustack_top();
@@ -80,10 +91,11 @@ impl ProcControlBlock {
         add_task(task);
         proc
     }
-    pub fn fork(self: &Arc<Self>) -> Arc<Self> {
+    // None if memory ran out
+    pub fn fork(self: &Arc<Self>) -> Option<Arc<Self>> {
         let mut par_mut = self.get_mutpart();
         assert_eq!(par_mut.task_count(), 1);
-        let memset = par_mut.memset.clone();
+        let memset = par_mut.memset.fork()?;
         let pid = pid_alloc();
         //  copy fd table
         let new_fd_tab: Vec<Option<Arc<dyn File + Send + Sync>>> = par_mut.fd_table.iter()
@@ -110,13 +122,13 @@ impl ProcControlBlock {
                 })
             }
         });
-        par_mut.children.push(pcb.clone());
         let task = Arc::new(TaskControlBlock::new(
             pcb.clone(),
             par_mut.get_task(0).get_mutpart()
                 .res.as_ref().unwrap().ustack_base,
             false
-        ));
+        )?);
+        par_mut.children.push(pcb.clone());
         let mut child_inner = pcb.get_mutpart();
         child_inner.tasks.push(Some(task.clone()));
         drop(child_inner);
@@ -127,42 +139,51 @@ impl ProcControlBlock {
         drop(task_inner);
         reg_proc(pcb.getpid(), &pcb);
         add_task(task);
-        pcb
+        Some(pcb)
     }
-    pub fn exec(self: &Arc<Self>, elf_data: &[u8], args: &Vec<String>) {
+    // an error leaves the process as it was; should the arguments not make it
+    // onto the new stack after the old image is gone, the process is killed
+    pub fn exec(self: &Arc<Self>, inode: &Arc<VirtInode>, args: &Vec<String>) -> Result<(), ExecError> {
         assert_eq!(self.get_mutpart().task_count(), 1);
-        let (memset, ustack_base, entry_pt) = MemSet::from_elf(elf_data);
-        let new_token = memset.get_atp_token();
-        self.get_mutpart().memset = memset;
+        let mut memset = MemSet::new_user().ok_or(ExecError::NoMemory)?;
+        let (ustack_base, entry_pt) = memset.load_elf(inode).ok_or(ExecError::NotProgram)?;
+        // argv and the strings it points to go below the top of the stack
+        let argv_len = (args.len() + 1) * size_of::<usize>();
+        let strs_len: usize = args.iter().map(|arg| arg.len() + 1).sum();
+        if argv_len + strs_len > USER_STACK_SIZE {
+            return Err(ExecError::TooBig);
+        }
 
         let task = self.get_mutpart().get_task(0);
         let mut inner = task.get_mutpart();
+        inner.res.as_ref().unwrap().map_user_res(&mut memset, ustack_base)
+            .ok_or(ExecError::NoMemory)?;
+        let new_token = memset.get_atp_token();
+        self.get_mutpart().memset = memset;
         inner.res.as_mut().unwrap().ustack_base = ustack_base;
-        inner.res.as_mut().unwrap().alloc_user_res();
         inner.trap_cx_ppn = inner.res.as_ref().unwrap().trap_cx_ppn();
 
         // push args into user stack
-        let mut user_sp = inner.res.as_ref().unwrap().ustack_top();
-        user_sp -= (args.len() + 1) * size_of::<usize>();
-        let argv_base = user_sp;
-        let mut argv: Vec<_> = (0..=args.len()).map(|i| {
-            PageTab::from_token(new_token).trans_mut(
-                (argv_base + i * size_of::<usize>()) as *mut usize
-            )
-        }).collect();
-        *argv[args.len()] = 0;
-        for i in 0..args.len() {
-            user_sp -= args[i].len() + 1;
-            *argv[i] = user_sp;
-            for (j, b) in args[i].as_bytes().iter().enumerate() {
-                *(PageTab::from_token(new_token).trans_mut(
-                    (user_sp + j) as *mut u8
-                )) = *b;
-            }
-            *(PageTab::from_token(new_token).trans_mut(
-                (user_sp + args[i].len()) as *mut u8
-            )) = 0;
+        let ustack_top = inner.res.as_ref().unwrap().ustack_top();
+        let argv_base = ustack_top - argv_len;
+        let mut user_sp = argv_base - strs_len;
+        let mut stack = vec![0u8; ustack_top - user_sp];
+        let mut arg_va = argv_base;
+        for (i, arg) in args.iter().enumerate() {
+            arg_va -= arg.len() + 1;
+            let at = arg_va - user_sp;
+            stack[at..at + arg.len()].copy_from_slice(arg.as_bytes());
+            let at = argv_base - user_sp + i * size_of::<usize>();
+            stack[at..at + size_of::<usize>()].copy_from_slice(&arg_va.to_ne_bytes());
         }
+        let Some(mut buf) = PageTab::from_token(new_token)
+            .trans_bytes_buffer_mut(user_sp as *const u8, stack.len())
+        else {
+            drop(inner);
+            self.get_mutpart().signals |= SignalFlags::SIGKILL;
+            return Ok(());
+        };
+        buf.copy_from_slice(&stack);
         // align
         user_sp -= user_sp % size_of::<usize>();
 
@@ -176,6 +197,7 @@ impl ProcControlBlock {
 
         trap_cx.reg[11] = argv_base;
         *inner.get_trap_cx() = trap_cx;
+        Ok(())
     }
     pub fn getpid(&self) -> usize {
         self.pid.0
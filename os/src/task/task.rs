@@ -25,16 +25,17 @@ pub struct TaskControlBlock {
     mut_part: UThrCell<TCBMut>
 }
 impl TaskControlBlock {
+    // None if memory ran out
     pub fn new(
         proc: Arc<ProcControlBlock>,
         ustack_base: usize,
         is_alloc_user_res: bool
-    ) -> Self {
-        let res = TaskUserRes::new(proc.clone(), ustack_base, is_alloc_user_res);
+    ) -> Option<Self> {
+        let res = TaskUserRes::new(proc.clone(), ustack_base, is_alloc_user_res)?;
         let trap_cx_ppn = res.trap_cx_ppn();
-        let kern_stack = kstack_alloc();
+        let kern_stack = kstack_alloc()?;
         let kstack_top = kern_stack.get_top();
-        Self {
+        Some(Self {
             proc: Arc::downgrade(&proc),
             kern_stack,
             mut_part: unsafe {
@@ -46,7 +47,7 @@ impl TaskControlBlock {
                     exit_code: None,
                 })
             }
-        }
+        })
     }
     pub fn get_mutpart(&self) -> UThrRefMut<'_, TCBMut> {
         self.mut_part.get_refmut()
@@ -1,6 +1,6 @@
 use alloc::sync::{Arc, Weak};
 
-use crate::{config::{ADDR_TRAPCONTEXT, PAGE_SIZE, USER_STACK_SIZE}, mm::{address::{PhysPageNum, VirtAddr}, memarea::MapPermission}};
+use crate::{config::{ADDR_TRAPCONTEXT, PAGE_SIZE, USER_STACK_SIZE}, mm::{address::{PhysPageNum, VirtAddr}, memarea::MapPermission, memset::MemSet}};
 
 use super::proc::ProcControlBlock;
 
@@ -11,11 +11,12 @@ pub struct TaskUserRes {
 }
 
 impl TaskUserRes {
+    // None if memory ran out
     pub fn new(
         proc: Arc<ProcControlBlock>,
         ustack_base: usize,
         is_alloc_user_res: bool
-    ) -> Self {
+    ) -> Option<Self> {
         let tid = proc.get_mutpart().alloc_tid();
         let ret = Self {
             tid,
@@ -23,28 +24,33 @@ impl TaskUserRes {
             proc: Arc::downgrade(&proc)
         };
         if is_alloc_user_res {
-            ret.alloc_user_res();
+            ret.alloc_user_res()?;
         }
-        ret
+        Some(ret)
     }
 
-    pub fn alloc_user_res(&self) {
+    pub fn alloc_user_res(&self) -> Option<()> {
         let proc = self.proc.upgrade().unwrap();
         let mut inner = proc.get_mutpart();
+        self.map_user_res(&mut inner.memset, self.ustack_base)
+    }
 
-        let ustack_bottom: VirtAddr = (self.ustack_base + self.tid * (PAGE_SIZE + USER_STACK_SIZE)).into();
+    // the user stack and trap context of the thread in `memset`,
+    // which may be one the process has yet to switch to
+    pub fn map_user_res(&self, memset: &mut MemSet, ustack_base: usize) -> Option<()> {
+        let ustack_bottom: VirtAddr = (ustack_base + self.tid * (PAGE_SIZE + USER_STACK_SIZE)).into();
         let ustack_top: VirtAddr = (ustack_bottom.0 + USER_STACK_SIZE).into();
-        inner.memset.insert_framed_area(
+        memset.insert_framed_area(
             ustack_bottom..ustack_top,
             MapPermission::R | MapPermission::W | MapPermission::U
-        );
+        )?;
 
         let trap_cx_bottom: VirtAddr = (ADDR_TRAPCONTEXT - self.tid * PAGE_SIZE).into();
         let trap_cx_top: VirtAddr = (trap_cx_bottom.0 + PAGE_SIZE).into();
-        inner.memset.insert_framed_area(
+        memset.insert_framed_area(
             trap_cx_bottom..trap_cx_top,
             MapPermission::R | MapPermission::W
-        );
+        )
     }
 
     fn dealloc_user_res(&self) {
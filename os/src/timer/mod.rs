@@ -1,6 +1,8 @@
+use core::sync::atomic::{AtomicUsize, Ordering};
+
 use riscv::register::time;
 
-use crate::{sbi::set_timer, config::CLOCK_FREQ};
+use crate::{sbi::set_timer, config::{CLOCK_FREQ, VIRT_RTC}};
 
 pub mod sleep;
 
@@ -14,6 +16,23 @@ pub fn get_time_ms() -> usize {
     time::read() / CLOCK_PER_MILI
 }
 
+// unix time in ms when `get_time_ms` was 0
+static BOOT_UNIX_MS: AtomicUsize = AtomicUsize::new(0);
+
+// read the wall clock off the goldfish RTC, once the MMIO is mapped
+pub fn init() {
+    // nanoseconds since the unix epoch; reading the low half latches the high
+    let low = unsafe { (VIRT_RTC as *const u32).read_volatile() } as usize;
+    let high = unsafe { ((VIRT_RTC + 4) as *const u32).read_volatile() } as usize;
+    let unix_ms = ((high << 32) | low) / 1_000_000;
+    BOOT_UNIX_MS.store(unix_ms.saturating_sub(get_time_ms()), Ordering::Relaxed);
+}
+
+// ms since the unix epoch, as easyfs-fuse stamps files on the host
+pub fn get_unix_time_ms() -> usize {
+    BOOT_UNIX_MS.load(Ordering::Relaxed) + get_time_ms()
+}
+
 pub fn set_trig() {
     set_timer(get_time() + CLOCK_PER_TICK);
 }
@@ -2,7 +2,7 @@ use core::arch::{global_asm, asm};
 use context::TrapContext;
 use riscv::register::{scause::{self, Exception, Interrupt, Trap}, sie, sscratch, sstatus, stval, stvec, utvec::TrapMode};
 
-use crate::{config::ADDR_TRAMPOLINE, syscall::syscall, task::{exit_curr_task, processor::{curr_atp_token, curr_proc, curr_trap_cx, curr_trap_va}, send_signal_curr_proc, signal::SignalFlags, suspend_curr_task}, timer::{set_trig, sleep::check_sleeptimer}};
+use crate::{config::ADDR_TRAMPOLINE, fs::inode::write_back_tick, mm::{address::VirtAddr, memarea::MapPermission}, syscall::syscall, task::{exit_curr_task, handle_user_fault, processor::{curr_atp_token, curr_proc, curr_trap_cx, curr_trap_va}, send_signal_curr_proc, signal::SignalFlags, suspend_curr_task}, timer::{set_trig, sleep::check_sleeptimer}};
 
 pub mod context;
 global_asm!(include_str!("trap.S"));
@@ -74,7 +74,7 @@ pub fn trap_handler() -> ! {
             enable_supervisor_interrupt();
             let ret = syscall(
                 cx.reg[17],
-                [cx.reg[10], cx.reg[11], cx.reg[12]]
+                [cx.reg[10], cx.reg[11], cx.reg[12], cx.reg[13], cx.reg[14], cx.reg[15]]
             ) as usize;
             //  cx may change when calling sys_exec
             cx = curr_trap_cx();
@@ -83,17 +83,31 @@ pub fn trap_handler() -> ! {
         Trap::Interrupt(Interrupt::SupervisorTimer) => {
             set_trig();
             check_sleeptimer();
+            enable_supervisor_interrupt();
+            write_back_tick();
             suspend_curr_task();
         },
         Trap::Interrupt(Interrupt::SupervisorExternal) => {
             crate::drivers::irq_handler();
         }
-        Trap::Exception(Exception::StoreFault) |
         Trap::Exception(Exception::StorePageFault) |
-        Trap::Exception(Exception::LoadFault) |
         Trap::Exception(Exception::LoadPageFault) |
-        Trap::Exception(Exception::InstructionFault) |
         Trap::Exception(Exception::InstructionPageFault) => {
+            let access = match scause_v.cause() {
+                Trap::Exception(Exception::StorePageFault) => MapPermission::W,
+                Trap::Exception(Exception::LoadPageFault) => MapPermission::R,
+                _ => MapPermission::X
+            };
+            let vpn = VirtAddr::from(stval_v).vpn_floor();
+            // a page of a file mapping may have to be read from the disk
+            enable_supervisor_interrupt();
+            if !handle_user_fault(curr_atp_token(), vpn, MapPermission::U | access) {
+                send_signal_curr_proc(SignalFlags::SIGSEGV)
+            }
+        }
+        Trap::Exception(Exception::StoreFault) |
+        Trap::Exception(Exception::LoadFault) |
+        Trap::Exception(Exception::InstructionFault) => {
             /*
             println!("[kernel] {:?} in app, bad addr = {:#x}, kernel execution. ", scause_v.cause(), stval_v);
             exit_curr_proc(-2)
@@ -0,0 +1,33 @@
+#![no_std]
+#![no_main]
+
+#[macro_use]
+extern crate user;
+
+use user::chmod;
+
+// set the permission bits of files, given in octal as `chmod 755 prog`;
+// files made in the kernel start at 644, so a program has to be made
+// executable before it can be run
+#[no_mangle]
+pub fn main(argc: usize, argv: &[&str]) -> i32 {
+    if argc < 3 {
+        println!("usage: chmod MODE FILE...");
+        return -1;
+    }
+    let mode = match u32::from_str_radix(argv[1], 8) {
+        Ok(mode) if mode <= 0o7777 => mode,
+        _ => {
+            println!("chmod: invalid mode {}", argv[1]);
+            return -1;
+        }
+    };
+    let mut ret = 0;
+    for path in &argv[2..argc] {
+        if chmod(path, mode) < 0 {
+            println!("chmod: cannot change {}", path);
+            ret = -1;
+        }
+    }
+    ret
+}
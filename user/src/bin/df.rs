@@ -0,0 +1,36 @@
+#![no_std]
+#![no_main]
+
+#[macro_use]
+extern crate user;
+
+use user::{statfs, StatFs};
+
+// used share of `total`, rounded up as df does
+fn percent(used: u64, total: u64) -> u64 {
+    if total == 0 { 0 } else { (used * 100 + total - 1) / total }
+}
+
+#[no_mangle]
+pub fn main(argc: usize, argv: &[&str]) -> i32 {
+    let path = if argc > 1 { argv[1] } else { "/" };
+    let mut st = StatFs::default();
+    if statfs(path, &mut st) < 0 {
+        println!("df: cannot read {}", path);
+        return -1;
+    }
+    let kib = |blocks: u64| blocks * st.block_size / 1024;
+    let used = st.blocks - st.free_blocks;
+    let iused = st.inodes - st.free_inodes;
+    println!("{:<10}{:>10}{:>10}{:>10}{:>6}", "", "1K-blocks", "Used", "Available", "Use%");
+    println!(
+        "{:<10}{:>10}{:>10}{:>10}{:>5}%",
+        "blocks", kib(st.blocks), kib(used), kib(st.free_blocks), percent(used, st.blocks)
+    );
+    println!("{:<10}{:>10}{:>10}{:>10}{:>6}", "", "Inodes", "IUsed", "IFree", "IUse%");
+    println!(
+        "{:<10}{:>10}{:>10}{:>10}{:>5}%",
+        "inodes", st.inodes, iused, st.free_inodes, percent(iused, st.inodes)
+    );
+    0
+}
@@ -0,0 +1,39 @@
+#![no_std]
+#![no_main]
+
+#[macro_use]
+extern crate user;
+
+use user::{close, mmap, munmap, pipe, read, write, MAP_ANONYMOUS, MAP_PRIVATE, PROT_READ};
+
+const PAGE_SIZE: usize = 4096;
+const EFAULT: isize = 14;
+
+#[no_mangle]
+pub fn main() -> i32 {
+    let mut fds = [0usize; 2];
+    assert_eq!(pipe(&mut fds), 0);
+    assert_eq!(write(fds[1], b"efault"), 6);
+
+    // nothing is mapped at the bottom of the address space
+    let unmapped = unsafe { core::slice::from_raw_parts(PAGE_SIZE as *const u8, 16) };
+    assert_eq!(read(fds[0], unmapped), -EFAULT);
+    assert_eq!(write(fds[1], unmapped), -EFAULT);
+
+    // the kernel can't write where the process couldn't
+    let addr = mmap(0, PAGE_SIZE, PROT_READ, MAP_PRIVATE | MAP_ANONYMOUS, 0, 0);
+    assert!(addr > 0);
+    let page = unsafe { core::slice::from_raw_parts(addr as *const u8, PAGE_SIZE) };
+    assert_eq!(read(fds[0], page), -EFAULT);
+    assert!(page.iter().all(|&b| b == 0));
+    assert_eq!(munmap(addr as usize, PAGE_SIZE), 0);
+
+    // the data is still in the pipe
+    let mut buf = [0u8; 6];
+    assert_eq!(read(fds[0], &mut buf), 6);
+    assert_eq!(&buf, b"efault");
+    close(fds[0]);
+    close(fds[1]);
+    println!("efault_test passed!");
+    0
+}
@@ -0,0 +1,31 @@
+#![no_std]
+#![no_main]
+
+#[macro_use]
+extern crate user;
+
+use user::{fork, wait};
+
+static mut DATA: [u8; 8192] = [1; 8192];
+
+#[no_mangle]
+pub fn main() -> i32 {
+    let data = unsafe { &mut *core::ptr::addr_of_mut!(DATA) };
+    let pid = fork();
+    if pid == 0 {
+        // the child writes its own copy
+        assert!(data.iter().all(|&b| b == 1));
+        data.iter_mut().for_each(|b| *b = 2);
+        assert!(data.iter().all(|&b| b == 2));
+        return 0;
+    }
+    let mut exit_code: i32 = 0;
+    assert_eq!(pid, wait(&mut exit_code));
+    assert_eq!(exit_code, 0);
+    // and the parent still sees its own
+    assert!(data.iter().all(|&b| b == 1));
+    data[0] = 3;
+    assert_eq!(data[0], 3);
+    println!("forktest_cow passed!");
+    0
+}
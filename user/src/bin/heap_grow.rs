@@ -0,0 +1,37 @@
+#![no_std]
+#![no_main]
+
+#[macro_use]
+extern crate user;
+extern crate alloc;
+
+use alloc::{vec, vec::Vec};
+use user::{brk, sbrk};
+
+// far more than the heap the program starts with
+const BIG_SIZE: usize = 2 << 20;
+const PAGE_SIZE: usize = 4096;
+
+#[no_mangle]
+pub fn main() -> i32 {
+    let start = sbrk(0);
+    let mut big = vec![0u8; BIG_SIZE];
+    big.iter_mut().enumerate().for_each(|(i, b)| *b = (i % 251) as u8);
+    let mut small: Vec<Vec<usize>> = Vec::new();
+    for i in 0..1000 {
+        small.push(vec![i; 64]);
+    }
+    assert!(big.iter().enumerate().all(|(i, &b)| b == (i % 251) as u8));
+    assert!(small.iter().enumerate().all(|(i, v)| v.iter().all(|&x| x == i)));
+    assert!(sbrk(0) >= start + BIG_SIZE as isize);
+
+    // a page given back starts zeroed when the heap grows over it again
+    let top = (sbrk(0) as usize + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+    assert_eq!(brk(top + PAGE_SIZE), (top + PAGE_SIZE) as isize);
+    unsafe { (top as *mut u8).write_volatile(1) };
+    assert_eq!(brk(top), top as isize);
+    assert_eq!(brk(top + PAGE_SIZE), (top + PAGE_SIZE) as isize);
+    assert_eq!(unsafe { (top as *const u8).read_volatile() }, 0);
+    println!("heap_grow passed!");
+    0
+}
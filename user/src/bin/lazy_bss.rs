@@ -0,0 +1,23 @@
+#![no_std]
+#![no_main]
+
+#[macro_use]
+extern crate user;
+
+// more than the free memory, only a few pages of it are ever touched
+const BSS_SIZE: usize = 32 << 20;
+static mut BIG: [u8; BSS_SIZE] = [0; BSS_SIZE];
+
+#[no_mangle]
+pub fn main() -> i32 {
+    let big = unsafe { &mut *core::ptr::addr_of_mut!(BIG) };
+    for i in (0..BSS_SIZE).step_by(BSS_SIZE / 8) {
+        assert_eq!(big[i], 0);
+        big[i] = (i >> 20) as u8;
+    }
+    for i in (0..BSS_SIZE).step_by(BSS_SIZE / 8) {
+        assert_eq!(big[i], (i >> 20) as u8);
+    }
+    println!("lazy_bss passed!");
+    0
+}
@@ -0,0 +1,48 @@
+#![no_std]
+#![no_main]
+
+#[macro_use]
+extern crate user;
+extern crate alloc;
+
+use alloc::format;
+use user::{close, fstat, getdents, open, DirentIter, OpenFlags, Stat, DT_DIR};
+
+#[no_mangle]
+pub fn main(argc: usize, argv: &[&str]) -> i32 {
+    let dir = if argc > 1 { argv[1] } else { "/" };
+    let fd = open(dir, OpenFlags::RDONLY);
+    if fd < 0 {
+        println!("ls: cannot open {}", dir);
+        return -1;
+    }
+    let fd = fd as usize;
+    let mut buf = [0u8; 512];
+    loop {
+        let len = getdents(fd, &mut buf);
+        if len < 0 {
+            println!("ls: {} is not a directory", dir);
+            close(fd);
+            return -1;
+        }
+        if len == 0 {
+            break;
+        }
+        for ent in DirentIter::new(&buf[..len as usize]) {
+            if ent.d_type == DT_DIR {
+                println!("{}/", ent.name);
+                continue;
+            }
+            let path = format!("{}/{}", dir.trim_end_matches('/'), ent.name);
+            let mut stat = Stat::default();
+            let file_fd = open(&path, OpenFlags::RDONLY);
+            if file_fd >= 0 {
+                fstat(file_fd as usize, &mut stat);
+                close(file_fd as usize);
+            }
+            println!("{:<28}{}", ent.name, stat.size);
+        }
+    }
+    close(fd);
+    0
+}
@@ -0,0 +1,75 @@
+#![no_std]
+#![no_main]
+
+#[macro_use]
+extern crate user;
+
+use user::{
+    close, fork, mmap, mprotect, munmap, open, waitpid, write, OpenFlags,
+    MAP_ANONYMOUS, MAP_PRIVATE, PROT_READ, PROT_WRITE
+};
+
+const PAGE_SIZE: usize = 4096;
+
+#[no_mangle]
+pub fn main() -> i32 {
+    // anonymous pages start zeroed
+    let len = 4 * PAGE_SIZE;
+    let addr = mmap(0, len, PROT_READ | PROT_WRITE, MAP_PRIVATE | MAP_ANONYMOUS, 0, 0);
+    assert!(addr > 0);
+    let addr = addr as usize;
+    let anon = unsafe { core::slice::from_raw_parts_mut(addr as *mut u8, len) };
+    assert!(anon.iter().all(|&b| b == 0));
+    anon.iter_mut().enumerate().for_each(|(i, b)| *b = i as u8);
+
+    // unmapping the middle keeps both ends
+    assert_eq!(munmap(addr + PAGE_SIZE, 2 * PAGE_SIZE), 0);
+    assert_eq!(anon[PAGE_SIZE - 1], (PAGE_SIZE - 1) as u8);
+    assert_eq!(anon[3 * PAGE_SIZE], (3 * PAGE_SIZE) as u8);
+
+    // a write to a read-only page kills the writer
+    assert_eq!(mprotect(addr, PAGE_SIZE, PROT_READ), 0);
+    assert_eq!(anon[1], 1);
+    let pid = fork();
+    if pid == 0 {
+        unsafe { (addr as *mut u8).write_volatile(0) };
+        return 0;
+    }
+    let mut exit_code: i32 = 0;
+    assert_eq!(waitpid(pid as usize, &mut exit_code), pid);
+    assert_eq!(exit_code, -11);
+    assert_eq!(munmap(addr, len), 0);
+
+    // a private file mapping reads the file, writes stay in memory
+    let text = b"mmap_test file contents";
+    let fd = open("mmap_file\0", OpenFlags::CREATE | OpenFlags::WRONLY);
+    assert!(fd > 0);
+    write(fd as usize, text);
+    close(fd as usize);
+    let fd = open("mmap_file\0", OpenFlags::RDONLY);
+    assert!(fd > 0);
+    let addr = mmap(0, PAGE_SIZE, PROT_READ | PROT_WRITE, MAP_PRIVATE, fd as usize, 0);
+    close(fd as usize);
+    assert!(addr > 0);
+    let file = unsafe { core::slice::from_raw_parts_mut(addr as *mut u8, PAGE_SIZE) };
+    assert_eq!(&file[..text.len()], text);
+    assert!(file[text.len()..].iter().all(|&b| b == 0));
+    file[0] = b'M';
+    assert_eq!(munmap(addr as usize, PAGE_SIZE), 0);
+
+    // the stack is the kernel's to manage
+    let stack = &exit_code as *const i32 as usize & !(PAGE_SIZE - 1);
+    assert_eq!(munmap(stack, PAGE_SIZE), -22);
+    assert_eq!(mprotect(stack, PAGE_SIZE, PROT_READ), -22);
+
+    // a huge mapping costs only the pages touched, also to unmap
+    let huge = 1usize << 36;
+    let addr = mmap(0, huge, PROT_READ | PROT_WRITE, MAP_PRIVATE | MAP_ANONYMOUS, 0, 0);
+    assert!(addr > 0);
+    unsafe { ((addr as usize + huge - 1) as *mut u8).write_volatile(1) };
+    assert_eq!(mprotect(addr as usize, huge, PROT_READ), 0);
+    assert_eq!(munmap(addr as usize, huge), 0);
+
+    println!("mmap_test passed!");
+    0
+}
@@ -0,0 +1,44 @@
+#![no_std]
+#![no_main]
+
+#[macro_use]
+extern crate user;
+
+use user::{mmap, munmap, MAP_ANONYMOUS, MAP_PRIVATE, PROT_READ, PROT_WRITE};
+
+const PAGE_SIZE: usize = 4096;
+// more than the whole memory of the machine
+const LEN: usize = 24 << 20;
+
+fn pattern(page: usize, round: usize) -> usize {
+    page.wrapping_mul(0x9e37_79b9) ^ round
+}
+
+#[no_mangle]
+pub fn main() -> i32 {
+    let addr = mmap(0, LEN, PROT_READ | PROT_WRITE, MAP_PRIVATE | MAP_ANONYMOUS, 0, 0);
+    assert!(addr > 0);
+    let words = |page: usize| unsafe {
+        core::slice::from_raw_parts_mut((addr as usize + page * PAGE_SIZE) as *mut usize, PAGE_SIZE / 8)
+    };
+    let pages = LEN / PAGE_SIZE;
+    // the second round reads pages back from swap and dirties them again
+    for round in 0..2 {
+        for page in 0..pages {
+            let words = words(page);
+            if round > 0 {
+                assert_eq!(words[0], pattern(page, round - 1));
+                assert_eq!(words[words.len() - 1], pattern(page, round - 1));
+            }
+            words[0] = pattern(page, round);
+            words[words.len() - 1] = pattern(page, round);
+        }
+        println!("swaptest: round {} done", round);
+    }
+    for page in (0..pages).rev() {
+        assert_eq!(words(page)[0], pattern(page, 1));
+    }
+    assert_eq!(munmap(addr as usize, LEN), 0);
+    println!("swaptest passed!");
+    0
+}
@@ -52,7 +52,7 @@ fn main() -> i32 {
                     0 => {
                         if let Some(path) = input {
                             let input_fd = open(path.to_str().unwrap(), OpenFlags::RDONLY);
-                            if input_fd == -1 {
+                            if input_fd < 0 {
                                 println!("Error when opening file {}! ", path.to_str().unwrap());
                                 return -4;
                             }
@@ -63,7 +63,7 @@ fn main() -> i32 {
                         }
                         if let Some(path) = output {
                             let output_fd = open(path.to_str().unwrap(), OpenFlags::CREATE | OpenFlags::WRONLY);
-                            if output_fd == -1 {
+                            if output_fd < 0 {
                                 println!("Error when opening file {}! ", path.to_str().unwrap());
                                 return -4;
                             }
@@ -73,7 +73,7 @@ fn main() -> i32 {
                             close(output_fd);
                         }
                         
-                        if exec(args[0].to_str().unwrap(), &args_addr) == -1 {
+                        if exec(args[0].to_str().unwrap(), &args_addr) < 0 {
                             println!("Error when executing");
                             return -4;
                         }
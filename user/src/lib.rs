@@ -25,7 +25,7 @@ pub use graphics::Display;
 #[link_section = ".text.entry"]
 pub extern "C" fn _start(argc: usize, argv: usize) -> ! {
     unsafe {
-        HEAP.lock().init(HEAP_SPACE.as_ptr() as usize, USER_HEAP_SIZE);
+        HEAP.0.lock().init(HEAP_SPACE.as_ptr() as usize, USER_HEAP_SIZE);
     }
     let v: Vec<&str> = (0..argc).map(|i| unsafe {
         CStr::from_ptr(*(argv as *const usize).add(i) as *const i8)
@@ -49,16 +49,45 @@ use bitflags::bitflags;
 /// ------------------------------------------------------------
 /// ------------------------------------------------------------
 
+use core::alloc::{GlobalAlloc, Layout};
+use core::ptr::{self, NonNull};
 use buddy_system_allocator::LockedHeap;
 const USER_HEAP_SIZE: usize = 16324;
 static mut HEAP_SPACE: [u8; USER_HEAP_SIZE] = [0; USER_HEAP_SIZE];
+// the least the heap grows by
+const HEAP_GROW_SIZE: usize = 0x10000;
 extern crate alloc;
 
+// starts on HEAP_SPACE and takes more memory with brk when that runs out
+struct UserHeap(LockedHeap);
+
+unsafe impl GlobalAlloc for UserHeap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let mut heap = self.0.lock();
+        if let Ok(ptr) = heap.alloc(layout) {
+            return ptr.as_ptr();
+        }
+        // twice the block size holds an aligned block of it, and growing
+        // by at least the heap size keeps the number of grows small
+        let block = layout.size().max(layout.align()).next_power_of_two();
+        let grow = (2 * block).max(heap.stats_total_bytes()).max(HEAP_GROW_SIZE);
+        let start = sbrk(grow as isize);
+        if start < 0 {
+            return ptr::null_mut();
+        }
+        heap.add_to_heap(start as usize, start as usize + grow);
+        heap.alloc(layout).map_or(ptr::null_mut(), |ptr| ptr.as_ptr())
+    }
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.0.lock().dealloc(NonNull::new_unchecked(ptr), layout)
+    }
+}
+
 #[global_allocator]
-static HEAP: LockedHeap = LockedHeap::empty();
+static HEAP: UserHeap = UserHeap(LockedHeap::empty());
 
 #[alloc_error_handler]
-pub fn alloc_err_handler(layout: core::alloc::Layout) -> ! {
+pub fn alloc_err_handler(layout: Layout) -> ! {
     panic!("Heap alloc error! layout = {:?}", layout);
 }
 
@@ -144,6 +173,86 @@ bitflags! {
         const RDWR = 1 << 1;
         const CREATE = 1 << 9;
         const TRUNC = 1 << 10;
+        const NOFOLLOW = 1 << 17;
+    }
+}
+
+pub const S_IFMT: u32 = 0o170000;
+pub const S_IFIFO: u32 = 0o010000;
+pub const S_IFCHR: u32 = 0o020000;
+pub const S_IFDIR: u32 = 0o040000;
+pub const S_IFREG: u32 = 0o100000;
+pub const S_IFLNK: u32 = 0o120000;
+pub const S_IRUSR: u32 = 0o400;
+pub const S_IWUSR: u32 = 0o200;
+pub const S_IXUSR: u32 = 0o100;
+
+#[repr(C)]
+#[derive(Clone, Copy, Default, Debug)]
+pub struct Stat {
+    pub dev: u64,
+    pub ino: u64,
+    pub mode: u32,
+    pub nlink: u32,
+    pub size: u64,
+    pub blocks: u64,
+    // milliseconds since boot
+    pub atime: u64,
+    pub mtime: u64,
+    pub ctime: u64
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default, Debug)]
+pub struct StatFs {
+    pub block_size: u64,
+    pub blocks: u64,
+    pub free_blocks: u64,
+    pub inodes: u64,
+    pub free_inodes: u64,
+    pub name_max: u64
+}
+
+pub const DT_DIR: u8 = 4;
+pub const DT_REG: u8 = 8;
+pub const DT_LNK: u8 = 10;
+
+pub struct Dirent<'a> {
+    pub ino: u64,
+    pub d_type: u8,
+    pub name: &'a str
+}
+
+// walks the linux_dirent64 records filled in by getdents
+pub struct DirentIter<'a> {
+    buf: &'a [u8],
+    pos: usize
+}
+
+impl<'a> DirentIter<'a> {
+    pub fn new(buf: &'a [u8]) -> Self {
+        Self { buf, pos: 0 }
+    }
+}
+
+impl<'a> Iterator for DirentIter<'a> {
+    type Item = Dirent<'a>;
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.pos + 19 > self.buf.len() {
+            return None;
+        }
+        let rec = &self.buf[self.pos..];
+        let ino = u64::from_ne_bytes(rec[0..8].try_into().unwrap());
+        let reclen = u16::from_ne_bytes(rec[16..18].try_into().unwrap()) as usize;
+        let d_type = rec[18];
+        let name = &rec[19..reclen];
+        let name_len = name.iter().position(|&b| b == 0).unwrap_or(name.len());
+        self.pos += reclen;
+        Some(Dirent {
+            ino,
+            d_type,
+            name: core::str::from_utf8(&name[..name_len]).unwrap_or("?")
+        })
     }
 }
 
@@ -155,6 +264,144 @@ pub fn open(path: &str, flags: OpenFlags) -> isize {
     sys_open(&path_, flags.bits())
 }
 
+pub fn mkdir(path: &str) -> isize {
+    let path_ = CString::new(path).map_or_else(
+        |_| CString::from_vec_with_nul(Vec::from(path)),
+        |x| Ok(x)
+    ).unwrap();
+    sys_mkdirat(&path_, 0o755)
+}
+
+pub fn rmdir(path: &str) -> isize {
+    let path_ = CString::new(path).map_or_else(
+        |_| CString::from_vec_with_nul(Vec::from(path)),
+        |x| Ok(x)
+    ).unwrap();
+    sys_unlinkat(&path_, AT_REMOVEDIR)
+}
+
+pub fn unlink(path: &str) -> isize {
+    let path_ = CString::new(path).map_or_else(
+        |_| CString::from_vec_with_nul(Vec::from(path)),
+        |x| Ok(x)
+    ).unwrap();
+    sys_unlinkat(&path_, 0)
+}
+
+pub fn link(old_path: &str, new_path: &str) -> isize {
+    let old_path_ = CString::new(old_path).map_or_else(
+        |_| CString::from_vec_with_nul(Vec::from(old_path)),
+        |x| Ok(x)
+    ).unwrap();
+    let new_path_ = CString::new(new_path).map_or_else(
+        |_| CString::from_vec_with_nul(Vec::from(new_path)),
+        |x| Ok(x)
+    ).unwrap();
+    sys_linkat(&old_path_, &new_path_, 0)
+}
+
+// `link_path` will resolve to `target`, which need not exist yet
+pub fn symlink(target: &str, link_path: &str) -> isize {
+    let target_ = CString::new(target).map_or_else(
+        |_| CString::from_vec_with_nul(Vec::from(target)),
+        |x| Ok(x)
+    ).unwrap();
+    let link_path_ = CString::new(link_path).map_or_else(
+        |_| CString::from_vec_with_nul(Vec::from(link_path)),
+        |x| Ok(x)
+    ).unwrap();
+    sys_symlinkat(&target_, &link_path_)
+}
+
+// the length of the target placed in `buf`, which gets no nul
+pub fn readlink(path: &str, buf: &mut [u8]) -> isize {
+    let path_ = CString::new(path).map_or_else(
+        |_| CString::from_vec_with_nul(Vec::from(path)),
+        |x| Ok(x)
+    ).unwrap();
+    sys_readlinkat(&path_, buf)
+}
+
+// fail instead of replacing an existing target
+pub const RENAME_NOREPLACE: u32 = 1;
+
+// `flags` may hold RENAME_NOREPLACE
+pub fn rename(old_path: &str, new_path: &str, flags: u32) -> isize {
+    let old_path_ = CString::new(old_path).map_or_else(
+        |_| CString::from_vec_with_nul(Vec::from(old_path)),
+        |x| Ok(x)
+    ).unwrap();
+    let new_path_ = CString::new(new_path).map_or_else(
+        |_| CString::from_vec_with_nul(Vec::from(new_path)),
+        |x| Ok(x)
+    ).unwrap();
+    sys_renameat2(&old_path_, &new_path_, flags)
+}
+
+pub fn chmod(path: &str, mode: u32) -> isize {
+    let path_ = CString::new(path).map_or_else(
+        |_| CString::from_vec_with_nul(Vec::from(path)),
+        |x| Ok(x)
+    ).unwrap();
+    sys_fchmodat(&path_, mode)
+}
+
+pub fn fstat(fd: usize, stat: &mut Stat) -> isize {
+    sys_fstat(fd, stat)
+}
+
+pub fn statfs(path: &str, buf: &mut StatFs) -> isize {
+    let path_ = CString::new(path).map_or_else(
+        |_| CString::from_vec_with_nul(Vec::from(path)),
+        |x| Ok(x)
+    ).unwrap();
+    sys_statfs(&path_, buf)
+}
+
+pub fn getdents(fd: usize, buf: &mut [u8]) -> isize {
+    sys_getdents64(fd, buf)
+}
+
+pub fn ftruncate(fd: usize, len: usize) -> isize {
+    sys_ftruncate(fd, len)
+}
+
+// set the program break, the break after the call
+pub fn brk(addr: usize) -> isize {
+    sys_brk(addr)
+}
+
+// move the program break by `increment`, the old break or -1
+pub fn sbrk(increment: isize) -> isize {
+    let old = sys_brk(0);
+    let new = old.wrapping_add(increment);
+    if sys_brk(new as usize) != new {
+        return -1;
+    }
+    old
+}
+
+pub const PROT_NONE: usize = 0;
+pub const PROT_READ: usize = 1;
+pub const PROT_WRITE: usize = 2;
+pub const PROT_EXEC: usize = 4;
+pub const MAP_PRIVATE: usize = 0x02;
+pub const MAP_FIXED: usize = 0x10;
+pub const MAP_ANONYMOUS: usize = 0x20;
+
+// the start of the mapping, or a negative errno
+pub fn mmap(addr: usize, len: usize, prot: usize, flags: usize, fd: usize, offset: usize) -> isize {
+    sys_mmap(addr, len, prot, flags, fd, offset)
+}
+
+pub fn munmap(addr: usize, len: usize) -> isize {
+    sys_munmap(addr, len)
+}
+
+pub fn mprotect(addr: usize, len: usize, prot: usize) -> isize {
+    sys_mprotect(addr, len, prot)
+}
+
 pub fn close(fd: usize) -> isize {
     sys_close(fd)
 }
@@ -1,5 +1,7 @@
 use core::{arch::asm, ffi::CStr};
 
+use crate::{Stat, StatFs};
+
 // use crate::SignalAction;
 
 fn syscall(id: usize, args: [usize; 3]) -> isize {
@@ -14,13 +16,38 @@ fn syscall(id: usize, args: [usize; 3]) -> isize {
     ret
 }
 
+fn syscall6(id: usize, args: [usize; 6]) -> isize {
+    let mut ret: isize;
+    unsafe { asm!(
+        "ecall",
+        inlateout("x10") args[0] => ret,
+        in("x11") args[1],
+        in("x12") args[2],
+        in("x13") args[3],
+        in("x14") args[4],
+        in("x15") args[5],
+        in("x17") id
+    )};
+    ret
+}
+
 const SYSCALL_DUP: usize = 24;
+const SYSCALL_MKDIRAT: usize = 34;
+const SYSCALL_UNLINKAT: usize = 35;
+const SYSCALL_SYMLINKAT: usize = 36;
+const SYSCALL_LINKAT: usize = 37;
+const SYSCALL_STATFS: usize = 43;
+const SYSCALL_FTRUNCATE: usize = 46;
+const SYSCALL_FCHMODAT: usize = 53;
 const SYSCALL_OPEN: usize = 56;
 const SYSCALL_CLOSE: usize = 57;
 const SYSCALL_PIPE: usize = 59;
+const SYSCALL_GETDENTS64: usize = 61;
 const SYSCALL_SEEK: usize = 62;
 const SYSCALL_READ: usize = 63;
+const SYSCALL_READLINKAT: usize = 78;
 const SYSCALL_WRITE: usize = 64;
+const SYSCALL_FSTAT: usize = 80;
 const SYSCALL_EXIT: usize = 93;
 const SYSCALL_SLEEP: usize = 101;
 const SYSCALL_YIELD: usize = 124;
@@ -31,8 +58,13 @@ const SYSCALL_KILL: usize = 129;
 const SYSCALL_GET_TIME: usize = 169;
 const SYSCALL_GETPID: usize = 172;
 const SYSCALL_FORK: usize = 220;
+const SYSCALL_BRK: usize = 214;
+const SYSCALL_MUNMAP: usize = 215;
 const SYSCALL_EXEC: usize = 221;
+const SYSCALL_MMAP: usize = 222;
+const SYSCALL_MPROTECT: usize = 226;
 const SYSCALL_WAITPID: usize = 260;
+const SYSCALL_RENAMEAT2: usize = 276;
 
 const SYSCALL_THRDCREATE: usize = 1000;
 const SYSCALL_GETTID: usize = 1001;
@@ -51,6 +83,9 @@ const SYSCALL_GETGPURES: usize = 2000;
 const SYSCALL_GETFBFD: usize = 2001;
 const SYSCALL_INPUTEVENT: usize = 3000;
 
+const AT_FDCWD: isize = -100;
+pub const AT_REMOVEDIR: u32 = 0x200;
+
 pub fn sys_seek(fd: usize, offset: isize, whence: usize) -> isize {
     syscall(SYSCALL_SEEK, [fd, offset as usize, whence])
 }
@@ -63,6 +98,10 @@ pub fn sys_write(fd: usize, buffer: &[u8]) -> isize {
     syscall(SYSCALL_WRITE, [fd, buffer.as_ptr() as usize, buffer.len()])
 }
 
+pub fn sys_fstat(fd: usize, stat: &mut Stat) -> isize {
+    syscall(SYSCALL_FSTAT, [fd, stat as *mut _ as usize, 0])
+}
+
 pub fn sys_exit(xstate: i32) -> isize {
     syscall(SYSCALL_EXIT, [xstate as usize, 0, 0])
 }
@@ -95,6 +134,48 @@ pub fn sys_open(path: &CStr, flags: u32) -> isize {
     syscall(SYSCALL_OPEN, [path.as_ptr() as usize, flags as usize, 0])
 }
 
+pub fn sys_mkdirat(path: &CStr, mode: u32) -> isize {
+    syscall(SYSCALL_MKDIRAT, [AT_FDCWD as usize, path.as_ptr() as usize, mode as usize])
+}
+
+pub fn sys_unlinkat(path: &CStr, flags: u32) -> isize {
+    syscall(SYSCALL_UNLINKAT, [AT_FDCWD as usize, path.as_ptr() as usize, flags as usize])
+}
+
+pub fn sys_linkat(oldpath: &CStr, newpath: &CStr, flags: u32) -> isize {
+    syscall6(SYSCALL_LINKAT, [
+        AT_FDCWD as usize, oldpath.as_ptr() as usize,
+        AT_FDCWD as usize, newpath.as_ptr() as usize,
+        flags as usize, 0
+    ])
+}
+
+pub fn sys_symlinkat(target: &CStr, linkpath: &CStr) -> isize {
+    syscall(SYSCALL_SYMLINKAT, [target.as_ptr() as usize, AT_FDCWD as usize, linkpath.as_ptr() as usize])
+}
+
+pub fn sys_readlinkat(path: &CStr, buf: &mut [u8]) -> isize {
+    syscall6(SYSCALL_READLINKAT, [
+        AT_FDCWD as usize, path.as_ptr() as usize,
+        buf.as_mut_ptr() as usize, buf.len(), 0, 0
+    ])
+}
+
+pub fn sys_renameat2(oldpath: &CStr, newpath: &CStr, flags: u32) -> isize {
+    syscall6(SYSCALL_RENAMEAT2, [
+        AT_FDCWD as usize, oldpath.as_ptr() as usize,
+        AT_FDCWD as usize, newpath.as_ptr() as usize,
+        flags as usize, 0
+    ])
+}
+
+pub fn sys_fchmodat(path: &CStr, mode: u32) -> isize {
+    syscall6(SYSCALL_FCHMODAT, [
+        AT_FDCWD as usize, path.as_ptr() as usize,
+        mode as usize, 0, 0, 0
+    ])
+}
+
 pub fn sys_close(fd: usize) -> isize {
     syscall(SYSCALL_CLOSE, [fd, 0, 0])
 }
@@ -103,6 +184,34 @@ pub fn sys_pipe(pipe: &mut [usize]) -> isize {
     syscall(SYSCALL_PIPE, [pipe.as_mut_ptr() as usize, 0, 0])
 }
 
+pub fn sys_getdents64(fd: usize, buf: &mut [u8]) -> isize {
+    syscall(SYSCALL_GETDENTS64, [fd, buf.as_mut_ptr() as usize, buf.len()])
+}
+
+pub fn sys_statfs(path: &CStr, buf: &mut StatFs) -> isize {
+    syscall(SYSCALL_STATFS, [path.as_ptr() as usize, buf as *mut _ as usize, 0])
+}
+
+pub fn sys_ftruncate(fd: usize, len: usize) -> isize {
+    syscall(SYSCALL_FTRUNCATE, [fd, len, 0])
+}
+
+pub fn sys_brk(addr: usize) -> isize {
+    syscall(SYSCALL_BRK, [addr, 0, 0])
+}
+
+pub fn sys_mmap(addr: usize, len: usize, prot: usize, flags: usize, fd: usize, offset: usize) -> isize {
+    syscall6(SYSCALL_MMAP, [addr, len, prot, flags, fd, offset])
+}
+
+pub fn sys_munmap(addr: usize, len: usize) -> isize {
+    syscall(SYSCALL_MUNMAP, [addr, len, 0])
+}
+
+pub fn sys_mprotect(addr: usize, len: usize, prot: usize) -> isize {
+    syscall(SYSCALL_MPROTECT, [addr, len, prot])
+}
+
 pub fn sys_dup(fd: usize) -> isize {
     syscall(SYSCALL_DUP, [fd, 0, 0])
 }